@@ -1,7 +1,40 @@
+/**
+ * Price of energy in currency per kWh, value is a decimal number serialized as string
+ * to keep its precision, e.g. {"value": "0.45123", "unit": "PLN/kWh"}
+ */
+export interface Money {
+    value: string;
+    unit: string;
+}
+
 export interface PricelistItem {
     startsAt: number;
     duration: number;
+    /** compatibility field, price in 1/100000 of currency per kWh, use unitPrice for exact value */
     price: number;
+    unitPrice?: Money;
     weight: number | null;
     category: "min" | "medium" | "max";
-}
\ No newline at end of file
+    /** suspicious but usable price reported by the price list validation */
+    warnings?: string[];
+}
+
+/**
+ * Where the price list of a single day comes from, version changes whenever any of its prices changes
+ */
+export interface PricelistMetadata {
+    forDay: number;
+    provider: string;
+    fetchedAt: number;
+    publishedAt: number | null;
+    status: "final" | "estimated";
+    version: string;
+    warnings?: string[];
+}
+
+/**
+ * Price list of a single day with its metadata
+ */
+export interface Pricelist extends PricelistMetadata {
+    items: PricelistItem[];
+}
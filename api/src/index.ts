@@ -1,7 +1,7 @@
 import { DateTimeUtils } from "./DateTimeUtils";
-import { PricelistItem} from "./PricelistItem"
+import { Money, Pricelist, PricelistItem, PricelistMetadata } from "./PricelistItem"
 
-export { DateTimeUtils, PricelistItem };
+export { DateTimeUtils, Money, Pricelist, PricelistItem, PricelistMetadata };
 
 
 export interface SwitchAction {
@@ -10,6 +10,19 @@ export interface SwitchAction {
     switchOn: boolean;
     state: "scheduled" | "executed" | "canceled";
     result: string | undefined;
+    /** energy meter read before switching */
+    meterReading?: MeterReading;
+}
+
+export interface MeterReading {
+    energyTotalKwh: number | null;
+    powerW: number | null;
+    measuredAt: number;
+}
+
+export interface ActualConsumption {
+    energyKwh: string;
+    cost: string;
 }
 
 export interface ConsumptionPlanItem {
@@ -25,6 +38,38 @@ export interface ConsumptionPlan {
     finishAt: number;
     consumptionPlanItems: ConsumptionPlanItem[];
     state: "processing" | "executed" | "canceled";
+    /** price lists the plan was computed from */
+    priceLists?: PricelistMetadata[];
+    /** cost in PLN, it is estimated when rated power of the power consumer is configured */
+    estimatedCost?: string;
+    /** measured by the energy meter after the plan has been executed */
+    actualConsumption?: ActualConsumption;
+}
+
+export interface SwitchCapabilities {
+    readState: boolean;
+    readPower?: boolean;
+}
+
+export interface PowerReading {
+    powerW: number;
+    energyTotalKwh?: number;
+    measuredAt: number;
+}
+
+/** state pushed by the device integration, manual is set when the device is switched outside of the application */
+export interface LiveDeviceState {
+    state: string;
+    switchedOn?: boolean;
+    changedAt: number;
+    manual: boolean;
+}
+
+/** Home Assistant entity proposed by power consumers import */
+export interface PowerConsumerProposal {
+    deviceId: string;
+    name: string;
+    registered: boolean;
 }
 
 export interface PowerConsumerModel {
@@ -34,6 +79,8 @@ export interface PowerConsumerModel {
     defaultFinishAt: number | undefined;
     chargingStatusUrl: string | undefined; 
     consumptionPlan: ConsumptionPlan | null;
+    switchCapabilities?: SwitchCapabilities;
+    liveState?: LiveDeviceState;
 }
 
 
@@ -8,18 +8,30 @@ use tokio::sync::RwLock;
 
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Json, Response},
 };
 use chrono::TimeDelta;
 use chrono::{DateTime, Utc};
-use model::PriceListItem;
-use power_consumers::PowerConsumersService;
-use price_list_providers::{parse_date, SingleDayPriceList, TariffSelector};
+use model::{AppError, PriceList, PriceListItem, PriceListPrefetchStatus, PriceListSourceStatus, PriceListStatus};
+use power_consumers::{CheapestWindowFinder, PowerConsumersService};
+use price_list_providers::{
+    next_day_start, parse_date, price_list_to_csv, price_list_to_icalendar, PriceListArchive, PriceListFormat,
+    PriceListOverrides, PriceListPrefetcher, PriceListSourcesMonitor, SharedSingleDayPriceList,
+    TimePeriodPriceListService,
+};
 use serde::Deserialize;
 
 pub struct AppState {
-    pub single_day_price_list: Arc<TariffSelector>,
+    pub single_day_price_list: SharedSingleDayPriceList,
+    /// price list of the configured tariff without overrides, composition and categories
+    pub tariff_price_list: SharedSingleDayPriceList,
+    pub price_list_sources_monitor: Arc<PriceListSourcesMonitor>,
+    pub price_list_prefetcher: Arc<PriceListPrefetcher>,
+    pub price_list_overrides: Arc<PriceListOverrides>,
+    pub price_list_archive: Option<Arc<PriceListArchive>>,
+    pub time_period_price_list_service: Arc<TimePeriodPriceListService>,
+    pub cheapest_window_finder: Arc<CheapestWindowFinder>,
     pub power_consumers_service: PowerConsumersService,
 }
 
@@ -32,51 +44,322 @@ pub struct ScheduleConsumptionPlanParams {
     pub consumption_duration: TimeDelta,
     #[serde(with = "chrono::serde::ts_milliseconds")]
     pub finish_at: DateTime<Utc>,
+    /// consume as much as possible while the price is negative, beyond requested duration
+    #[serde(default)]
+    pub consume_while_negative: bool,
 }
 
-pub async fn get_price_list(Path(date): Path<String>, State(state): State<SharedState>) -> Response {
-    let app_state = state.read().await;
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheapestWindowParams {
+    #[serde(deserialize_with = "model::deserialize_time_delta")]
+    pub duration: TimeDelta,
+    /// it is now when it is missing
+    #[serde(default, with = "chrono::serde::ts_milliseconds_option")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub to: DateTime<Utc>,
+    /// consumption can not be interrupted by default
+    #[serde(default = "default_contiguous")]
+    pub contiguous: bool,
+}
+
+fn default_contiguous() -> bool {
+    true
+}
+
+/// time range is given as ISO-8601 timestamps, e.g. `2024-08-26T07:00:00Z`
+/// or `2024-08-26T09:00:00%2B02:00` as `+` has to be encoded in the query
+#[derive(Deserialize)]
+pub struct PriceListRangeParams {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub format: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PriceListStatsParams {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    #[serde(default = "default_percentile")]
+    pub percentile: u32,
+}
+
+fn default_percentile() -> u32 {
+    90
+}
+
+#[derive(Deserialize)]
+pub struct PriceListBackfillParams {
+    /// number of days walked back from yesterday, `backfill_days` from the settings is used when it is missing
+    pub days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct PriceListUploadParams {
+    /// uploaded prices are final unless they are marked as estimated
+    #[serde(default)]
+    pub estimated: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PriceListExportParams {
+    /// json, csv or ics, when it is missing format is negotiated by the `Accept` header
+    pub format: Option<String>,
+}
+
+fn price_list_response(price_list: Vec<PriceListItem>, format: PriceListFormat) -> Response {
+    let body = match format {
+        PriceListFormat::Json => return (StatusCode::OK, Json(price_list)).into_response(),
+        PriceListFormat::Csv => price_list_to_csv(&price_list),
+        PriceListFormat::ICalendar => price_list_to_icalendar(&price_list, &Utc::now()),
+    };
+    (StatusCode::OK, [(header::CONTENT_TYPE, format.content_type())], body).into_response()
+}
+
+/// Price list of the given day, JSON response has its metadata next to the items,
+/// CSV and iCalendar exports have items only
+pub async fn get_price_list(
+    Path(date): Path<String>,
+    Query(PriceListExportParams { format }): Query<PriceListExportParams>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let accept = headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok());
+    let format = match PriceListFormat::negotiate(format.as_deref(), accept) {
+        Ok(format) => format,
+        Err(error) => return (error.code(), Json(error)).into_response(),
+    };
+    // price list is fetched without holding the state lock, remote call may take a while
+    let single_day_price_list = state.read().await.single_day_price_list.clone();
+    let price_list = match parse_date(date) {
+        Ok(date) => single_day_price_list.get_price_list(&date).await,
+        Err(error) => Err(error),
+    };
+    price_list
+        .map(|price_list| match format {
+            PriceListFormat::Json => (StatusCode::OK, Json(PriceList::clone(&price_list))).into_response(),
+            _ => price_list_response(price_list.items().to_vec(), format),
+        })
+        .unwrap_or_else(|error| (error.code(), Json(error)).into_response())
+}
+
+/// Price list items which overlap given time range, they could span many days,
+/// JSON response has metadata of the price list of each day next to the items
+pub async fn get_price_list_range(
+    Query(PriceListRangeParams { from, to, format }): Query<PriceListRangeParams>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let accept = headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok());
+    let format = match PriceListFormat::negotiate(format.as_deref(), accept)
+        .and_then(|format| TimePeriodPriceListService::validate_time_range(&from, &to).map(|_| format))
+    {
+        Ok(format) => format,
+        Err(error) => return (error.code(), Json(error)).into_response(),
+    };
+    let time_period_price_list_service = state.read().await.time_period_price_list_service.clone();
+    time_period_price_list_service
+        .get_time_period_price_list(&from, &to)
+        .await
+        .map(|time_period_price_list| match format {
+            PriceListFormat::Json => (StatusCode::OK, Json(time_period_price_list)).into_response(),
+            _ => price_list_response(time_period_price_list.items, format),
+        })
+        .unwrap_or_else(|error| (error.code(), Json(error)).into_response())
+}
+
+/// Price statistics over given time range, they help to choose times of recurring schedules
+pub async fn get_price_list_stats(
+    Query(PriceListStatsParams { from, to, percentile }): Query<PriceListStatsParams>,
+    State(state): State<SharedState>,
+) -> Response {
+    if let Err(error) = TimePeriodPriceListService::validate_time_range(&from, &to) {
+        return (error.code(), Json(error)).into_response();
+    }
+    let time_period_price_list_service = state.read().await.time_period_price_list_service.clone();
+    time_period_price_list_service
+        .get_price_list_stats(&from, &to, percentile)
+        .await
+        .map(|stats| (StatusCode::OK, Json(stats)))
+        .map_err(|error| (error.code(), Json(error)))
+        .into_response()
+}
+
+/// Calendar feed of cheap hours of today and tomorrow, it is meant to be subscribed from phone calendars,
+/// tomorrow is skipped until its price list is published
+pub async fn get_cheap_hours_calendar(State(state): State<SharedState>) -> Response {
+    let single_day_price_list = state.read().await.single_day_price_list.clone();
+    let today = Utc::now();
+    let mut price_list: Vec<PriceListItem> = match single_day_price_list.get_price_list(&today).await {
+        Ok(price_list) => price_list.items().to_vec(),
+        Err(error) => return (error.code(), Json(error)).into_response(),
+    };
+    if let Ok(tomorrow_price_list) = single_day_price_list.get_price_list(&next_day_start(&today)).await {
+        price_list.extend(tomorrow_price_list.items().iter().cloned());
+    }
+    price_list_response(price_list, PriceListFormat::ICalendar)
+}
+
+/// Uploaded price list overrides configured provider for the given day until restart,
+/// body is CSV when content type is `text/csv` otherwise it is JSON
+pub async fn put_price_list(
+    Path(date): Path<String>,
+    Query(PriceListUploadParams { estimated }): Query<PriceListUploadParams>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let is_csv = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/csv"));
+    let price_list_overrides = state.read().await.price_list_overrides.clone();
+
+    parse_date(date)
+        .and_then(|date| {
+            if is_csv {
+                PriceListOverrides::parse_csv(&date, &body)
+            } else {
+                PriceListOverrides::parse_json(&body)
+            }
+            .and_then(|price_list| {
+                let status = if estimated { PriceListStatus::Estimated } else { PriceListStatus::Final };
+                price_list_overrides.set_override(&date, price_list, status)
+            })
+        })
+        .map(|price_list| (StatusCode::OK, Json(PriceList::clone(&price_list))))
+        .map_err(|error| (error.code(), Json(error)))
+        .into_response()
+}
+
+/// Removes uploaded price list, configured provider is used again for the given day
+pub async fn delete_price_list_override(Path(date): Path<String>, State(state): State<SharedState>) -> Response {
+    let price_list_overrides = state.read().await.price_list_overrides.clone();
     parse_date(date)
-        .and_then(|date| app_state.single_day_price_list.get_price_list(&date))
-        .map(|price_list| price_list.iter().cloned().collect::<Vec<PriceListItem>>())
-        .map(|price_list| (StatusCode::OK, Json(price_list)))
+        .and_then(|date| {
+            price_list_overrides
+                .remove_override(&date)
+                .ok_or_else(|| AppError::not_found("There is no uploaded price list for this date"))
+        })
+        .map(|price_list| (StatusCode::OK, Json(PriceList::clone(&price_list))))
+        .map_err(|error| (error.code(), Json(error)))
+        .into_response()
+}
+
+pub async fn get_cheapest_window(
+    Query(CheapestWindowParams { duration, from, to, contiguous }): Query<CheapestWindowParams>,
+    State(state): State<SharedState>,
+) -> Response {
+    let cheapest_window_finder = state.read().await.cheapest_window_finder.clone();
+    cheapest_window_finder
+        .find_cheapest_window(&duration, &from.unwrap_or_else(Utc::now), &to, contiguous)
+        .await
+        .map(|cheapest_window| (StatusCode::OK, Json(cheapest_window)))
         .map_err(|error| (error.code(), Json(error)))
         .into_response()
 }
 
+/// Walks back through the price list history and archives days which are not archived yet
+pub async fn backfill_price_list_archive(
+    Query(PriceListBackfillParams { days }): Query<PriceListBackfillParams>,
+    State(state): State<SharedState>,
+) -> Response {
+    let (price_list_archive, tariff_price_list) = {
+        let app_state = state.read().await;
+        (app_state.price_list_archive.clone(), app_state.tariff_price_list.clone())
+    };
+    match price_list_archive {
+        Some(price_list_archive) => {
+            let days = days.unwrap_or(price_list_archive.backfill_days());
+            let report = price_list_archive.backfill(tariff_price_list.as_ref(), &Utc::now(), days).await;
+            (StatusCode::OK, Json(report)).into_response()
+        }
+        None => {
+            let error = AppError::user_error("Price list archive is disabled in the settings");
+            (error.code(), Json(error)).into_response()
+        }
+    }
+}
+
+pub async fn get_price_list_sources_status(State(state): State<SharedState>) -> Response {
+    let app_state = state.read().await;
+    let statuses: Vec<PriceListSourceStatus> = app_state.price_list_sources_monitor.get_statuses();
+    (StatusCode::OK, Json(statuses)).into_response()
+}
+
+pub async fn get_price_list_prefetch_status(State(state): State<SharedState>) -> Response {
+    let app_state = state.read().await;
+    let status: PriceListPrefetchStatus = app_state.price_list_prefetcher.get_status();
+    (StatusCode::OK, Json(status)).into_response()
+}
+
 pub async fn get_power_consumers(State(state): State<SharedState>) -> Response {
     let app_state = state.read().await;
     let power_consumers_model_list = &app_state.power_consumers_service.get_power_consumers_model_list();
     (StatusCode::OK, Json(power_consumers_model_list)).into_response()
 }
 
-pub async fn schedule_consumption_plan(
-    Path(power_consumer_id): Path<String>,
-    Query(ScheduleConsumptionPlanParams { consumption_duration, finish_at }): Query<ScheduleConsumptionPlanParams>,
-    State(state): State<SharedState>,
-) -> Response {
-    state
-        .write()
+pub async fn get_power_consumer_proposals(State(state): State<SharedState>) -> Response {
+    PowerConsumersService::propose_power_consumers(&state)
+        .await
+        .map(|proposals| (StatusCode::OK, Json(proposals)))
+        .map_err(|e| (e.code(), Json(e)))
+        .into_response()
+}
+
+pub async fn import_power_consumers(State(state): State<SharedState>) -> Response {
+    PowerConsumersService::import_power_consumers(&state)
         .await
-        .power_consumers_service
-        .schedule_consumption_plan(power_consumer_id, consumption_duration, &finish_at)
+        .map(|registered| (StatusCode::OK, Json(registered)))
+        .map_err(|e| (e.code(), Json(e)))
+        .into_response()
+}
+
+pub async fn get_power_reading(Path(power_consumer_id): Path<String>, State(state): State<SharedState>) -> Response {
+    PowerConsumersService::read_power(&state, &power_consumer_id)
         .await
-        .map(|pcm| (StatusCode::OK, Json(pcm)))
+        .map(|power_reading| (StatusCode::OK, Json(power_reading)))
         .map_err(|e| (e.code(), Json(e)))
         .into_response()
 }
 
+pub async fn schedule_consumption_plan(
+    Path(power_consumer_id): Path<String>,
+    Query(ScheduleConsumptionPlanParams { consumption_duration, finish_at, consume_while_negative }): Query<
+        ScheduleConsumptionPlanParams,
+    >,
+    State(state): State<SharedState>,
+) -> Response {
+    let result = PowerConsumersService::schedule_consumption_plan(
+        &state,
+        &power_consumer_id,
+        consumption_duration,
+        &finish_at,
+        consume_while_negative,
+    )
+    .await;
+    power_consumer_response(&state, &power_consumer_id, result).await
+}
+
 pub async fn cancel_consumption_plan(
     Path(power_consumer_id): Path<String>,
     State(state): State<SharedState>,
 ) -> Response {
-    state
-        .write()
-        .await
-        .power_consumers_service
-        .cancel_consumption_plan(power_consumer_id)
-        .await
-        .map(|pcm| (StatusCode::OK, Json(pcm)))
+    let result = PowerConsumersService::cancel_consumption_plan(&state, &power_consumer_id).await;
+    power_consumer_response(&state, &power_consumer_id, result).await
+}
+
+/// power consumer with its plan after the change, it is read when the change is done
+async fn power_consumer_response(
+    state: &SharedState,
+    power_consumer_id: &str,
+    result: Result<(), AppError>,
+) -> Response {
+    let app_state = state.read().await;
+    result
+        .and_then(|()| app_state.power_consumers_service.get_power_consumer(power_consumer_id))
+        .map(|power_consumer| (StatusCode::OK, Json(power_consumer.to_power_consumer_model())))
         .map_err(|e| (e.code(), Json(e)))
         .into_response()
 }
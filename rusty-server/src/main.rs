@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{error::Error, sync::Arc};
 
 use axum::{
     routing::{delete, get, post},
@@ -8,51 +8,102 @@ use axum::{
 use tower_http::trace::TraceLayer;
 
 use rusty_server::{
-    cancel_consumption_plan, get_power_consumers, get_price_list,
-    power_consumers::{HomeAssistantService, PowerConsumersService, SwitchActionsScheduler},
-    price_list_providers::TariffSelector,
-    schedule_consumption_plan,
+    backfill_price_list_archive, cancel_consumption_plan, delete_price_list_override, get_cheap_hours_calendar,
+    get_cheapest_window, get_power_consumer_proposals, get_power_consumers, get_power_reading, get_price_list,
+    get_price_list_prefetch_status, get_price_list_range, get_price_list_sources_status, get_price_list_stats,
+    import_power_consumers,
+    model::AppError,
+    power_consumers::{
+        CheapestWindowFinder, HomeAssistantImporter, HomeAssistantWebSocketClient, MqttDiscoveryPublisher,
+        PowerConsumersService, SwitchActionsScheduler, SwitchBackends,
+    },
+    price_list_providers::{
+        create_single_day_price_list, PriceListArchive, PriceListOverrides, PriceListPrefetcher,
+        PriceListSourcesMonitor, SharedSingleDayPriceList, TariffSelector, TimePeriodPriceListService,
+    },
+    put_price_list, schedule_consumption_plan,
     settings::Settings,
     AppState, SharedState,
 };
 use tokio::{net::TcpListener, sync::RwLock};
 
+/// configuration errors are returned from main, so the application exits with their message instead of a panic
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt().with_max_level(tracing::Level::DEBUG).init();
 
-    let settings = Settings::new().unwrap();
+    let settings = Settings::new()?;
+    let state = create_shared_state(&settings).await?;
 
-    axum::serve(
-        TcpListener::bind(format!("0.0.0.0:{}", &settings.application_port)).await.unwrap(),
-        create_routes(create_shared_state(&settings).await),
-    )
-    .await
-    .unwrap();
+    axum::serve(TcpListener::bind(format!("0.0.0.0:{}", &settings.application_port)).await?, create_routes(state))
+        .await?;
+    Ok(())
 }
 
 fn create_routes(state: SharedState) -> Router {
     Router::new()
-        .route("/pricelist/{date}", get(get_price_list))
+        .route("/pricelist", get(get_price_list_range))
+        .route("/pricelist/stats", get(get_price_list_stats))
+        .route("/pricelist/archive/backfill", post(backfill_price_list_archive))
+        .route("/pricelist/sources", get(get_price_list_sources_status))
+        .route("/pricelist/prefetch", get(get_price_list_prefetch_status))
+        .route("/pricelist/cheap-hours.ics", get(get_cheap_hours_calendar))
+        .route("/pricelist/cheapest-window", get(get_cheapest_window))
+        .route("/pricelist/{date}", get(get_price_list).put(put_price_list).delete(delete_price_list_override))
         .route("/power-consumer/", get(get_power_consumers))
+        .route("/power-consumer/import", get(get_power_consumer_proposals).post(import_power_consumers))
+        .route("/power-consumer/{power_consumer_id}/power", get(get_power_reading))
         .route("/power-consumer/{power_consumer_id}/consumption-plan", post(schedule_consumption_plan))
         .route("/power-consumer/{power_consumer_id}/consumption-plan", delete(cancel_consumption_plan))
         .with_state(state)
         .layer(TraceLayer::new_for_http())
 }
 
-async fn create_shared_state(settings: &Settings) -> SharedState {
-    let home_assistant_service = Arc::new(HomeAssistantService::new(&settings.home_assistant_config));
-    let mut switch_actions_scheduler = SwitchActionsScheduler::new(home_assistant_service.clone());
-    let tariff_selector_price_list = Arc::new(TariffSelector::new(settings.tariff_type.clone()));
+async fn create_shared_state(settings: &Settings) -> Result<SharedState, AppError> {
+    let switch_backends = SwitchBackends::new(settings)?;
+    if settings.home_assistant_websocket.enabled {
+        let home_assistant_websocket_client = HomeAssistantWebSocketClient::new(
+            &settings.home_assistant_config,
+            settings.home_assistant_websocket.clone(),
+            switch_backends.home_assistant().entity_states(),
+        );
+        tokio::spawn(home_assistant_websocket_client.run());
+    }
+    let mut switch_actions_scheduler = SwitchActionsScheduler::new();
+    let price_list_sources_monitor = Arc::new(PriceListSourcesMonitor::new());
+    let mut price_list_overrides = PriceListOverrides::new();
+    if settings.price_list_validation.enabled {
+        price_list_overrides = price_list_overrides.with_validation(settings.price_list_validation.clone());
+    }
+    let price_list_overrides = Arc::new(price_list_overrides);
+    let price_list_archive =
+        settings.price_list_archive.enabled.then(|| Arc::new(PriceListArchive::new(&settings.price_list_archive)));
+    let tariff_price_list: SharedSingleDayPriceList =
+        Arc::new(TariffSelector::new(settings, price_list_sources_monitor.clone(), price_list_archive.clone())?);
+    let single_day_price_list =
+        create_single_day_price_list(settings, tariff_price_list.clone(), price_list_overrides.clone());
+    let price_list_prefetcher =
+        Arc::new(PriceListPrefetcher::new(single_day_price_list.clone(), settings.price_list_prefetch.clone()));
+    if settings.price_list_prefetch.enabled {
+        tokio::spawn(price_list_prefetcher.clone().run());
+    }
+
+    let time_period_price_list_service = Arc::new(TimePeriodPriceListService::new(single_day_price_list.clone()));
 
     let state = Arc::new(RwLock::new(AppState {
-        single_day_price_list: tariff_selector_price_list.clone(),
+        single_day_price_list: single_day_price_list.clone(),
+        tariff_price_list,
+        price_list_sources_monitor,
+        price_list_prefetcher,
+        price_list_overrides,
+        price_list_archive,
+        cheapest_window_finder: Arc::new(CheapestWindowFinder::new(time_period_price_list_service.clone())),
+        time_period_price_list_service,
         power_consumers_service: PowerConsumersService::new(
             &settings.power_consumers,
-            tariff_selector_price_list.clone(),
-            home_assistant_service.clone(),
-        ),
+            single_day_price_list.clone(),
+            &switch_backends,
+        )?,
     }));
 
     switch_actions_scheduler.set_state(Some(state.clone()));
@@ -62,5 +113,30 @@ async fn create_shared_state(settings: &Settings) -> SharedState {
         .power_consumers_service
         .set_switch_actions_scheduler(Some(Arc::new(switch_actions_scheduler)));
 
-    state
+    let home_assistant_importer =
+        HomeAssistantImporter::new(switch_backends.home_assistant(), &settings.home_assistant_import)?;
+    {
+        let power_consumers_service = &mut state.write().await.power_consumers_service;
+        power_consumers_service.set_home_assistant_importer(Some(Arc::new(home_assistant_importer)));
+        power_consumers_service.load_imported_power_consumers().await?;
+    }
+    if settings.home_assistant_import.import_on_startup {
+        if let Err(e) = PowerConsumersService::import_power_consumers(&state).await {
+            tracing::warn!("Power consumers have not been imported from Home Assistant: {}", e);
+        }
+    }
+
+    tokio::spawn(PowerConsumersService::replan_on_fetched_price_lists(
+        state.clone(),
+        state.read().await.price_list_prefetcher.subscribe(),
+    ));
+
+    // settings validation requires mqtt_config when MQTT discovery is enabled
+    if let (true, Some(mqtt_config)) = (settings.mqtt_discovery.enabled, &settings.mqtt_config) {
+        let mqtt_discovery_publisher =
+            MqttDiscoveryPublisher::new(mqtt_config, settings.mqtt_discovery.clone(), state.clone());
+        tokio::spawn(mqtt_discovery_publisher.run());
+    }
+
+    Ok(state)
 }
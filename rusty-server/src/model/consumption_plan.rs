@@ -1,9 +1,10 @@
 use chrono::{DateTime, TimeDelta, Utc};
+use rust_decimal::Decimal;
 
 use serde::Serialize;
 use uuid::Uuid;
 
-use super::PriceListItem;
+use super::{Money, PriceListItem, PriceListMetadata, PriceListStatus};
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -27,11 +28,22 @@ pub struct SwitchAction {
     #[serde(with = "chrono::serde::ts_milliseconds_option")]
     executed_at: Option<DateTime<Utc>>,
     result: Option<String>,
+    /// energy meter of the power consumer read right before the device has been switched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meter_reading: Option<MeterReading>,
 }
 
 impl SwitchAction {
     pub fn new(at: DateTime<Utc>, switch_on: bool) -> Self {
-        Self { id: Uuid::new_v4(), at, switch_on, state: SwitchActionState::Scheduled, executed_at: None, result: None }
+        Self {
+            id: Uuid::new_v4(),
+            at,
+            switch_on,
+            state: SwitchActionState::Scheduled,
+            executed_at: None,
+            result: None,
+            meter_reading: None,
+        }
     }
 
     pub fn id(&self) -> &Uuid {
@@ -65,6 +77,14 @@ impl SwitchAction {
     pub fn set_at(&mut self, at: DateTime<Utc>) {
         self.at = at;
     }
+
+    pub fn meter_reading(&self) -> Option<&MeterReading> {
+        self.meter_reading.as_ref()
+    }
+
+    pub fn set_meter_reading(&mut self, meter_reading: Option<MeterReading>) {
+        self.meter_reading = meter_reading;
+    }
 }
 
 ///ConsumptionPlanItem has one to one relation with price list item,
@@ -104,6 +124,35 @@ impl ConsumptionPlanItem {
     }
 }
 
+/// Single period of continuous consumption within the cheapest window
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsumptionPeriod {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub starts_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub ends_at: DateTime<Utc>,
+}
+
+/// The cheapest window to consume energy for the requested duration, it is an answer for consumers
+/// which are not controlled by the application, e.g. manually started dishwasher.
+/// When the window is not contiguous consumption is split into periods like in the consumption plan.
+/// Relative cost is a sum of prices multiplied by hours of consumption, it is a cost of every kW of consumer power.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CheapestWindow {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub starts_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub ends_at: DateTime<Utc>,
+    #[serde(serialize_with = "crate::model::serialize_time_delta")]
+    pub duration: TimeDelta,
+    pub contiguous: bool,
+    pub average_price: Money,
+    pub relative_cost: Decimal,
+    pub periods: Vec<ConsumptionPeriod>,
+}
+
 #[derive(Serialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum ConsumptionPlanState {
@@ -126,8 +175,18 @@ pub struct ConsumptionPlan {
     pub consumption_duration: TimeDelta,
     #[serde(with = "chrono::serde::ts_milliseconds")]
     pub finish_at: DateTime<Utc>,
+    /// consumption is extended to all periods with negative price between plan creation and finish time
+    pub consume_while_negative: bool,
     pub consumption_plan_items: Vec<ConsumptionPlanItem>,
     pub state: ConsumptionPlanState,
+    /// price lists the plan was computed from, their versions identify prices used by the plan
+    pub price_lists: Vec<PriceListMetadata>,
+    /// estimated cost in currency, it is missing when rated power of the power consumer is not configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_cost: Option<Decimal>,
+    /// consumption measured by the energy meter, it is known when the plan has been executed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual_consumption: Option<ActualConsumption>,
 }
 
 impl ConsumptionPlan {
@@ -142,10 +201,99 @@ impl ConsumptionPlan {
         self.consumption_plan_items.iter_mut().flat_map(|cpi| cpi.switch_actions_mut()).collect()
     }
 
+    /// time of the next scheduled switch on action, None when the device is not going to be switched on again
+    pub fn next_switch_on_at(&self) -> Option<DateTime<Utc>> {
+        self.flat_switch_actions()
+            .into_iter()
+            .find(|sa| sa.switch_on() && *sa.state() == SwitchActionState::Scheduled)
+            .map(|sa| *sa.at())
+    }
+
+    /// time of the last switch off action, consumption ends then
+    pub fn planned_end(&self) -> Option<DateTime<Utc>> {
+        self.flat_switch_actions().into_iter().rev().find(|sa| !sa.switch_on()).map(|sa| *sa.at())
+    }
+
     pub fn get_switch_action_by_id_mut(&mut self, switch_action_id: &str) -> Option<&mut SwitchAction> {
         let id = Uuid::parse_str(switch_action_id).unwrap();
         self.flat_switch_actions_mut().into_iter().find(|sa| *sa.id() == id)
     }
+
+    /// plan in progress which has not executed any switch action yet
+    pub fn waits_for_start(&self) -> bool {
+        self.state == ConsumptionPlanState::Processing
+            && self.flat_switch_actions().iter().all(|sa| *sa.state() == SwitchActionState::Scheduled)
+    }
+
+    /// plan which has not been started yet and lasts into the given day without its final prices,
+    /// e.g. it was computed from estimated prices, it should be computed again when the prices are fetched
+    pub fn waits_for_price_list(&self, day: &DateTime<Utc>) -> bool {
+        self.waits_for_start()
+            && self.finish_at > *day
+            && !self
+                .price_lists
+                .iter()
+                .any(|price_list| price_list.for_day == *day && price_list.status == PriceListStatus::Final)
+    }
+}
+
+/// Energy meter reading, total energy counter in kWh or active power in W depending on the meter
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MeterReading {
+    pub energy_total_kwh: Option<f64>,
+    pub power_w: Option<f64>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub measured_at: DateTime<Utc>,
+}
+
+/// Energy consumed by the executed plan and its cost in currency calculated from prices of the plan
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ActualConsumption {
+    pub energy_kwh: Decimal,
+    pub cost: Decimal,
+}
+
+/// What the switch backend of the power consumer is able to do besides switching the device
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SwitchCapabilities {
+    pub read_state: bool,
+    pub read_power: bool,
+}
+
+/// Power metering reported by the device, active power in W and total energy counter in kWh
+/// when the device keeps it
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerReading {
+    pub power_w: f64,
+    pub energy_total_kwh: Option<f64>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub measured_at: DateTime<Utc>,
+}
+
+/// State of the device pushed by its integration as soon as it changes, `switched_on` is missing
+/// when the state is not on or off, e.g. hvac mode of climate. Manual is set when the device
+/// has been switched by someone else than the application
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveDeviceState {
+    pub state: String,
+    pub switched_on: Option<bool>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub changed_at: DateTime<Utc>,
+    pub manual: bool,
+}
+
+/// Home Assistant entity proposed as power consumer, registered is set when it is a power consumer already
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerConsumerProposal {
+    pub device_id: String,
+    pub name: String,
+    pub registered: bool,
 }
 
 #[derive(Serialize, Debug, PartialEq)]
@@ -159,6 +307,9 @@ pub struct PowerConsumerModel<'a> {
     default_consumption_duration: TimeDelta,
     charging_status_url: Option<String>,
     consumption_plan: Option<&'a ConsumptionPlan>,
+    switch_capabilities: SwitchCapabilities,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    live_state: Option<LiveDeviceState>,
 }
 impl<'a> PowerConsumerModel<'a> {
     pub fn new(
@@ -167,6 +318,8 @@ impl<'a> PowerConsumerModel<'a> {
         default_finish_at: DateTime<Utc>,
         default_consumption_duration: TimeDelta,
         consumption_plan: Option<&'a ConsumptionPlan>,
+        switch_capabilities: SwitchCapabilities,
+        live_state: Option<LiveDeviceState>,
     ) -> Self {
         Self {
             id,
@@ -175,6 +328,8 @@ impl<'a> PowerConsumerModel<'a> {
             default_finish_at: Some(default_finish_at),
             charging_status_url: None,
             consumption_plan,
+            switch_capabilities,
+            live_state,
         }
     }
 }
@@ -194,8 +349,20 @@ mod tests {
             created_at: DateTime::from_timestamp_millis(1737068749821).unwrap(),
             consumption_duration: TimeDelta::milliseconds(12),
             finish_at: DateTime::from_timestamp_millis(1737068749821).unwrap(),
+            consume_while_negative: false,
             consumption_plan_items: Vec::new(),
             state: ConsumptionPlanState::Processing,
+            price_lists: vec![PriceListMetadata {
+                for_day: DateTime::from_timestamp_millis(1737068749821).unwrap(),
+                provider: "DayAheadMarket".to_owned(),
+                fetched_at: DateTime::from_timestamp_millis(1737068749821).unwrap(),
+                published_at: None,
+                status: PriceListStatus::Final,
+                version: "e3b0c44298fc1c14".to_owned(),
+                warnings: Vec::new(),
+            }],
+            estimated_cost: None,
+            actual_consumption: None,
         };
 
         let serialized = serde_json::to_string(&consumption_plan).unwrap();
@@ -204,7 +371,7 @@ mod tests {
         assert_ser_tokens(
             &consumption_plan,
             &[
-                Token::Struct { name: "ConsumptionPlan", len: 6 },
+                Token::Struct { name: "ConsumptionPlan", len: 8 },
                 Token::Str("id"),
                 Token::Str(ID),
                 Token::Str("createdAt"),
@@ -213,13 +380,73 @@ mod tests {
                 Token::I64(12),
                 Token::Str("finishAt"),
                 Token::I64(1737068749821),
+                Token::Str("consumeWhileNegative"),
+                Token::Bool(false),
                 Token::Str("consumptionPlanItems"),
                 Token::Seq { len: Some(0) },
                 Token::SeqEnd,
                 Token::Str("state"),
                 Token::UnitVariant { name: "ConsumptionPlanState", variant: "processing" },
+                Token::Str("priceLists"),
+                Token::Seq { len: Some(1) },
+                Token::Struct { name: "PriceListMetadata", len: 6 },
+                Token::Str("forDay"),
+                Token::I64(1737068749821),
+                Token::Str("provider"),
+                Token::Str("DayAheadMarket"),
+                Token::Str("fetchedAt"),
+                Token::I64(1737068749821),
+                Token::Str("publishedAt"),
+                Token::None,
+                Token::Str("status"),
+                Token::UnitVariant { name: "PriceListStatus", variant: "final" },
+                Token::Str("version"),
+                Token::Str("e3b0c44298fc1c14"),
+                Token::StructEnd,
+                Token::SeqEnd,
                 Token::StructEnd,
             ],
         );
     }
+
+    #[test]
+    fn plan_computed_from_estimated_prices_should_wait_for_final_ones() {
+        let today = DateTime::from_timestamp_millis(1724623200000).unwrap();
+        let tomorrow = today + TimeDelta::days(1);
+        let price_list = |for_day, status| PriceListMetadata {
+            for_day,
+            provider: "DayAheadMarket".to_owned(),
+            fetched_at: today,
+            published_at: None,
+            status,
+            version: "e3b0c44298fc1c14".to_owned(),
+            warnings: Vec::new(),
+        };
+        let mut consumption_plan = ConsumptionPlan {
+            id: uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            created_at: today,
+            consumption_duration: TimeDelta::hours(2),
+            finish_at: tomorrow + TimeDelta::hours(7),
+            consume_while_negative: false,
+            consumption_plan_items: Vec::new(),
+            state: ConsumptionPlanState::Processing,
+            price_lists: vec![
+                price_list(today, PriceListStatus::Final),
+                price_list(tomorrow, PriceListStatus::Estimated),
+            ],
+            estimated_cost: None,
+            actual_consumption: None,
+        };
+        assert!(consumption_plan.waits_for_price_list(&tomorrow));
+        assert!(!consumption_plan.waits_for_price_list(&(tomorrow + TimeDelta::days(1))));
+
+        consumption_plan.price_lists[1].status = PriceListStatus::Final;
+        assert!(!consumption_plan.waits_for_price_list(&tomorrow));
+
+        consumption_plan.price_lists.pop();
+        assert!(consumption_plan.waits_for_price_list(&tomorrow));
+
+        consumption_plan.state = ConsumptionPlanState::Canceled;
+        assert!(!consumption_plan.waits_for_price_list(&tomorrow));
+    }
 }
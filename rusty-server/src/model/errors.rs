@@ -2,7 +2,7 @@ use std::error::Error;
 
 use axum::http::StatusCode;
 
-#[derive(serde::Serialize, Debug)]
+#[derive(serde::Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum AppError {
     UserError {
@@ -1,9 +1,11 @@
 mod consumption_plan;
 mod errors;
+mod money;
 mod price_list;
 mod serde;
 
 pub use self::consumption_plan::*;
 pub use self::errors::*;
+pub use self::money::*;
 pub use self::price_list::*;
 pub use self::serde::*;
@@ -0,0 +1,314 @@
+use std::{
+    fmt::Display,
+    iter::Sum,
+    ops::{Add, AddAssign, Neg, Sub},
+    str::FromStr,
+};
+
+use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
+use serde::{
+    de::{self, Deserializer},
+    ser::{SerializeStruct, Serializer},
+    Deserialize, Serialize,
+};
+
+/// unit of all prices handled by the application
+pub const MONEY_UNIT: &str = "PLN/kWh";
+
+/// currency of costs, e.g. estimated cost of the consumption plan
+pub const CURRENCY: &str = "PLN";
+
+/// number of decimal places of the legacy integer price, it is 1/100000 of currency per kWh
+pub const LEGACY_PRICE_SCALE: u32 = 5;
+
+/// Money is a price of energy in currency per kWh kept as decimal number,
+/// so prices are compared and summed exactly without rounding through floats.
+///
+/// JSON representation carries decimal value as string and its unit:
+/// `{"value": "0.45123", "unit": "PLN/kWh"}`.
+/// In the settings money could be given as decimal number or string in currency per kWh, e.g. `0.45123` or `"0.45123"`,
+/// or as integer in 1/100000 of currency per kWh, e.g. `45123`, JSON representation is accepted as well.
+/// Integer strings, e.g. `"45123"` from environment overrides, are in 1/100000 of currency per kWh like integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Money(Decimal);
+
+impl Money {
+    pub const ZERO: Money = Money(Decimal::ZERO);
+
+    pub fn new(amount: Decimal) -> Self {
+        Self(amount.normalize())
+    }
+
+    /// money from the legacy integer price in 1/100000 of currency per kWh
+    pub fn from_legacy_price(legacy_price: i64) -> Self {
+        Self::new(Decimal::new(legacy_price, LEGACY_PRICE_SCALE))
+    }
+
+    /// market prices are published per MWh
+    pub fn from_price_per_mwh(price_per_mwh: Decimal) -> Self {
+        Self::new(price_per_mwh / Decimal::ONE_THOUSAND)
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.0
+    }
+
+    /// legacy integer price in 1/100000 of currency per kWh used by the web-app,
+    /// fractions of the legacy unit are rounded half away from zero
+    pub fn legacy_price(&self) -> i64 {
+        (self.0 * Decimal::new(10i64.pow(LEGACY_PRICE_SCALE), 0))
+            .round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero)
+            .to_i64()
+            .unwrap_or(if self.0.is_sign_negative() { i64::MIN } else { i64::MAX })
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    /// given percent of the money, e.g. VAT
+    pub fn percent(&self, percent: u32) -> Self {
+        Self::new(self.0 * Decimal::from(percent) / Decimal::ONE_HUNDRED)
+    }
+
+    /// average of prices, it is None for empty list
+    pub fn mean(prices: &[Money]) -> Option<Money> {
+        (!prices.is_empty()).then(|| Self::new(prices.iter().sum::<Money>().0 / Decimal::from(prices.len())))
+    }
+}
+
+impl Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.0, MONEY_UNIT)
+    }
+}
+
+impl FromStr for Money {
+    type Err = rust_decimal::Error;
+
+    fn from_str(amount: &str) -> Result<Self, Self::Err> {
+        Decimal::from_str_exact(amount).map(Self::new)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, rhs: Money) -> Money {
+        Money::new(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, rhs: Money) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, rhs: Money) -> Money {
+        Money::new(self.0 - rhs.0)
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money::new(-self.0)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Money> for Money {
+    fn sum<I: Iterator<Item = &'a Money>>(iter: I) -> Money {
+        iter.copied().sum()
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut money = serializer.serialize_struct("Money", 2)?;
+        money.serialize_field("value", &self.0.to_string())?;
+        money.serialize_field("unit", MONEY_UNIT)?;
+        money.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MoneyRepresentation {
+    LegacyPrice(i64),
+    Float(f64),
+    Amount(String),
+    Money { value: String, unit: String },
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Money::from_representation(MoneyRepresentation::deserialize(deserializer)?)
+    }
+}
+
+impl Money {
+    fn from_representation<E: de::Error>(representation: MoneyRepresentation) -> Result<Self, E> {
+        match representation {
+            MoneyRepresentation::LegacyPrice(legacy_price) => Ok(Money::from_legacy_price(legacy_price)),
+            //shortest float representation keeps 0.09 as 0.09 instead of its binary approximation
+            MoneyRepresentation::Float(amount) => amount.to_string().parse().map_err(de::Error::custom),
+            MoneyRepresentation::Amount(amount) => match amount.trim().parse::<i64>() {
+                Ok(legacy_price) => Ok(Money::from_legacy_price(legacy_price)),
+                Err(_) => amount.trim().parse().map_err(de::Error::custom),
+            },
+            MoneyRepresentation::Money { value, unit } if unit == MONEY_UNIT => {
+                value.trim().parse().map_err(de::Error::custom)
+            }
+            MoneyRepresentation::Money { unit, .. } => {
+                Err(de::Error::custom(format!("Money unit {} is not supported, expected {}", unit, MONEY_UNIT)))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AmountRepresentation {
+    Float(f64),
+    Amount(String),
+    Money { value: String, unit: String },
+}
+
+/// Money uploaded by the api user is always decimal currency per kWh, e.g. `"0.45123"` or `0.45123`,
+/// legacy integer prices are accepted in the settings only, so `45123` is 45123 of currency per kWh here
+pub fn deserialize_money_amount<'de, D>(deserializer: D) -> Result<Money, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let representation = match AmountRepresentation::deserialize(deserializer)? {
+        AmountRepresentation::Float(amount) => MoneyRepresentation::Float(amount),
+        AmountRepresentation::Amount(amount) => {
+            MoneyRepresentation::Money { value: amount, unit: MONEY_UNIT.to_owned() }
+        }
+        AmountRepresentation::Money { value, unit } => MoneyRepresentation::Money { value, unit },
+    };
+    Money::from_representation(representation)
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+    use rust_decimal::Decimal;
+    use serde::Deserialize;
+    use serde_test::{assert_ser_tokens, Token};
+
+    use super::{deserialize_money_amount, Money};
+
+    #[test]
+    fn legacy_price_should_keep_scale_of_the_web_app() {
+        let money = Money::from_legacy_price(45123);
+        assert_eq!(money.amount(), Decimal::new(45123, 5));
+        assert_eq!(money.legacy_price(), 45123);
+        assert_eq!(Money::from_price_per_mwh(Decimal::new(-7, 2)).legacy_price(), -7);
+        assert_eq!("0.000125".parse::<Money>().unwrap().legacy_price(), 13);
+        assert_eq!("-0.000125".parse::<Money>().unwrap().legacy_price(), -13);
+    }
+
+    #[test]
+    fn money_arithmetic_should_be_exact() {
+        let price: Money = "0.1".parse().unwrap();
+        assert_eq!(price + "0.2".parse().unwrap(), "0.3".parse().unwrap());
+        assert_eq!(Money::from_legacy_price(47000).percent(23), Money::from_legacy_price(10810));
+        assert_eq!(Money::mean(&[price, "0.3".parse().unwrap()]), Some("0.2".parse().unwrap()));
+        assert_eq!(Money::mean(&[]), None);
+        assert!((-price).is_negative());
+        assert!(!Money::ZERO.is_negative());
+    }
+
+    #[test]
+    fn money_ser_test() {
+        assert_ser_tokens(
+            &Money::from_legacy_price(-1500),
+            &[
+                Token::Struct { name: "Money", len: 2 },
+                Token::Str("value"),
+                Token::Str("-0.015"),
+                Token::Str("unit"),
+                Token::Str("PLN/kWh"),
+                Token::StructEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn money_should_be_deserialized_from_legacy_price_or_decimal_amount() {
+        assert_eq!(serde_json::from_str::<Money>("80000").unwrap(), Money::from_legacy_price(80000));
+        assert_eq!(serde_json::from_str::<Money>("\"0.8\"").unwrap(), Money::from_legacy_price(80000));
+        assert!(serde_json::from_str::<Money>("\"cheap\"").is_err());
+    }
+
+    #[test]
+    fn money_should_be_deserialized_from_float_amount() {
+        assert_eq!(serde_json::from_str::<Money>("0.09").unwrap(), "0.09".parse().unwrap());
+        assert_eq!(serde_json::from_str::<Money>("-0.5").unwrap(), Money::from_legacy_price(-50000));
+    }
+
+    #[test]
+    fn money_should_be_deserialized_from_settings_with_env_override() {
+        #[derive(Deserialize)]
+        struct Prices {
+            env_price: Money,
+            yaml_float_price: Money,
+            yaml_legacy_price: Money,
+        }
+
+        let prices: Prices = Config::builder()
+            .add_source(File::from_str("yaml_float_price: 0.09\nyaml_legacy_price: 9000", FileFormat::Yaml))
+            //environment variables are always strings
+            .set_override("env_price", "80000")
+            .unwrap()
+            .build()
+            .and_then(Config::try_deserialize)
+            .unwrap();
+
+        assert_eq!(prices.env_price, Money::from_legacy_price(80000));
+        assert_eq!(prices.yaml_float_price, Money::from_legacy_price(9000));
+        assert_eq!(prices.yaml_legacy_price, Money::from_legacy_price(9000));
+    }
+
+    #[test]
+    fn uploaded_money_should_be_decimal_amount_only() {
+        #[derive(Deserialize)]
+        struct Upload {
+            #[serde(deserialize_with = "deserialize_money_amount")]
+            price: Money,
+        }
+        let price = |json: &str| serde_json::from_str::<Upload>(json).map(|upload| upload.price);
+
+        assert_eq!(price(r#"{"price": "0.45123"}"#).unwrap(), Money::from_legacy_price(45123));
+        assert_eq!(price(r#"{"price": 0.45123}"#).unwrap(), Money::from_legacy_price(45123));
+        assert_eq!(price(r#"{"price": "45123"}"#).unwrap(), "45123".parse().unwrap());
+        assert_eq!(price(r#"{"price": 1}"#).unwrap(), "1".parse().unwrap());
+        assert_eq!(price(r#"{"price": {"value": "-0.01", "unit": "PLN/kWh"}}"#).unwrap(), "-0.01".parse().unwrap());
+        assert!(price(r#"{"price": "cheap"}"#).is_err());
+    }
+
+    #[test]
+    fn serialized_money_should_be_deserialized_back() {
+        let money = Money::from_legacy_price(-1500);
+        assert_eq!(serde_json::from_str::<Money>(&serde_json::to_string(&money).unwrap()).unwrap(), money);
+        assert!(serde_json::from_str::<Money>(r#"{"value": "0.5", "unit": "EUR/kWh"}"#).is_err());
+    }
+}
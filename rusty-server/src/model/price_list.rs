@@ -1,19 +1,33 @@
 use chrono::{DateTime, TimeDelta, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-pub type Currency = i32;
+use super::Money;
 
-#[derive(Serialize, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum PriceCategory {
     Min,
+    #[default]
     Medium,
     Max,
 }
 
+/// Components of the end user price, price list item price is sum of all of them
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceBreakdown {
+    pub energy: Money,
+    pub distribution: Money,
+    pub fees: Money,
+    pub excise: Money,
+    pub vat: Money,
+}
+
 /// 24 PriceListItem makes daily price list, it has starting time and duration
 /// duration is usually 1 hours, starting time + duration must be equal to the next
-/// price list item start time
+/// price list item start time.
+/// Price is serialized as `unitPrice` money with decimal value and unit,
+/// `price` is kept for the web-app as integer in 1/100000 of currency per kWh.
 #[derive(Serialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PriceListItem {
@@ -21,14 +35,34 @@ pub struct PriceListItem {
     starts_at: DateTime<Utc>,
     #[serde(serialize_with = "crate::model::serialize_time_delta")]
     duration: TimeDelta,
-    price: Currency,
+    #[serde(rename = "price")]
+    legacy_price: i64,
+    #[serde(rename = "unitPrice")]
+    price: Money,
     weight: i64,
     category: PriceCategory,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    breakdown: Option<PriceBreakdown>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// suspicious but usable price list items are reported by price list validation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
 }
 
 impl PriceListItem {
-    pub fn new(starts_at: DateTime<Utc>, duration: TimeDelta, price: Currency, category: PriceCategory) -> Self {
-        Self { starts_at, duration, price, weight: 0, category }
+    pub fn new(starts_at: DateTime<Utc>, duration: TimeDelta, price: Money, category: PriceCategory) -> Self {
+        Self {
+            starts_at,
+            duration,
+            legacy_price: price.legacy_price(),
+            price,
+            weight: 0,
+            category,
+            breakdown: None,
+            source: None,
+            warnings: Vec::new(),
+        }
     }
 
     pub fn starts_at(&self) -> &DateTime<Utc> {
@@ -39,13 +73,45 @@ impl PriceListItem {
         &self.duration
     }
 
-    pub fn price(&self) -> Currency {
+    pub fn price(&self) -> Money {
         self.price
     }
 
-    pub fn price_as_float(&self) -> f32 {
-        let shift = 100000f32;
-        ((self.price as f32 / shift * 100f32) as i32) as f32 / 100.0
+    pub fn category(&self) -> &PriceCategory {
+        &self.category
+    }
+
+    pub fn set_category(&mut self, category: PriceCategory) {
+        self.category = category;
+    }
+
+    pub fn breakdown(&self) -> Option<&PriceBreakdown> {
+        self.breakdown.as_ref()
+    }
+
+    pub fn set_breakdown(&mut self, breakdown: Option<PriceBreakdown>) {
+        self.breakdown = breakdown;
+    }
+
+    /// name of the price list source which answered for the day of this item
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn set_source(&mut self, source: Option<String>) {
+        self.source = source;
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn set_warnings(&mut self, warnings: Vec<String>) {
+        self.warnings = warnings;
+    }
+
+    pub fn add_warning(&mut self, warning: String) {
+        self.warnings.push(warning);
     }
 
     pub fn weight(&self) -> i64 {
@@ -57,19 +123,236 @@ impl PriceListItem {
     }
 }
 
+/// Day ahead prices are final once they are published, price list which stands in for them, e.g. uploaded by hand
+/// before publication, is an estimate
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceListStatus {
+    #[default]
+    Final,
+    Estimated,
+}
+
+/// Where the price list of a single day comes from and how fresh it is.
+/// Version identifies prices of the day, it changes whenever any of the prices changes,
+/// so the plan computed from the price list could be matched with the prices it used.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceListMetadata {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub for_day: DateTime<Utc>,
+    pub provider: String,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub fetched_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option", default)]
+    pub published_at: Option<DateTime<Utc>>,
+    pub status: PriceListStatus,
+    #[serde(default)]
+    pub version: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub warnings: Vec<String>,
+}
+
+/// PriceList is an envelope of price list items of a single day returned by price list providers,
+/// its metadata says which provider answered, when prices were fetched and published and if they are final
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceList {
+    #[serde(flatten)]
+    metadata: PriceListMetadata,
+    items: Vec<PriceListItem>,
+}
+
+impl PriceList {
+    pub fn new(for_day: DateTime<Utc>, provider: &str, fetched_at: DateTime<Utc>, items: Vec<PriceListItem>) -> Self {
+        Self::from_metadata(
+            PriceListMetadata {
+                for_day,
+                provider: provider.to_owned(),
+                fetched_at,
+                published_at: None,
+                status: PriceListStatus::Final,
+                version: String::new(),
+                warnings: Vec::new(),
+            },
+            items,
+        )
+    }
+
+    /// version of given metadata is ignored, it is always calculated from the items
+    pub fn from_metadata(metadata: PriceListMetadata, items: Vec<PriceListItem>) -> Self {
+        let mut price_list = Self { metadata, items };
+        price_list.metadata.version = price_list.calculate_version();
+        price_list
+    }
+
+    /// price list with the same metadata and changed items, e.g. composed prices, gets its own version
+    pub fn with_items(&self, items: Vec<PriceListItem>) -> Self {
+        Self::from_metadata(self.metadata.clone(), items)
+    }
+
+    /// FNV-1a hash of provider and prices, it is stable between restarts, so archived days keep their versions
+    fn calculate_version(&self) -> String {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+        write(self.metadata.provider.as_bytes());
+        for item in &self.items {
+            write(&item.starts_at().timestamp_millis().to_le_bytes());
+            write(&item.duration().num_milliseconds().to_le_bytes());
+            write(item.price().amount().normalize().to_string().as_bytes());
+        }
+        format!("{:016x}", hash)
+    }
+
+    pub fn metadata(&self) -> &PriceListMetadata {
+        &self.metadata
+    }
+
+    pub fn items(&self) -> &[PriceListItem] {
+        &self.items
+    }
+
+    pub fn for_day(&self) -> &DateTime<Utc> {
+        &self.metadata.for_day
+    }
+
+    pub fn provider(&self) -> &str {
+        &self.metadata.provider
+    }
+
+    pub fn version(&self) -> &str {
+        &self.metadata.version
+    }
+
+    pub fn status(&self) -> PriceListStatus {
+        self.metadata.status
+    }
+
+    pub fn set_status(&mut self, status: PriceListStatus) {
+        self.metadata.status = status;
+    }
+
+    pub fn set_published_at(&mut self, published_at: Option<DateTime<Utc>>) {
+        self.metadata.published_at = published_at;
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.metadata.warnings
+    }
+
+    pub fn add_warning(&mut self, warning: String) {
+        self.metadata.warnings.push(warning);
+    }
+}
+
+/// Price list items which overlap requested time range with metadata of each day they come from
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TimePeriodPriceList {
+    pub price_lists: Vec<PriceListMetadata>,
+    pub items: Vec<PriceListItem>,
+}
+
+/// Health of single price list source used by fallback chain
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceListSourceStatus {
+    pub name: String,
+    pub consecutive_failures: u32,
+    pub total_failures: u32,
+    pub repeatedly_failing: bool,
+    pub last_error: Option<String>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub last_failure_at: Option<DateTime<Utc>>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub last_success_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum PriceListPrefetchState {
+    Waiting,
+    Failing,
+    Fetched,
+}
+
+/// Status of the background prefetch of tomorrow's price list
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceListPrefetchStatus {
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub for_day: Option<DateTime<Utc>>,
+    pub state: PriceListPrefetchState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub fetched_at: Option<DateTime<Utc>>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+/// Result of walking back through the price list history, walk stops at the first day which could not be fetched
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceListBackfillReport {
+    pub requested_days: u32,
+    pub fetched_days: u32,
+    pub already_archived_days: u32,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub stopped_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+/// Average price of price list items which start at the given local hour of the day
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HourlyAveragePrice {
+    pub hour: u32,
+    pub average_price: Money,
+}
+
+/// Statistics of prices over a time range, every price list item is weighted by its duration,
+/// so hourly and quarter hour items of the range are comparable
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceListStats {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub from: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub to: DateTime<Utc>,
+    pub items_count: usize,
+    pub min: Money,
+    pub max: Money,
+    pub mean: Money,
+    pub median: Money,
+    pub percentile: u32,
+    pub percentile_price: Money,
+    pub hourly_averages: Vec<HourlyAveragePrice>,
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::{DateTime, TimeDelta};
     use serde_test::{assert_ser_tokens, Token};
 
-    use crate::model::price_list::{PriceCategory, PriceListItem};
+    use crate::model::{
+        price_list::{PriceCategory, PriceList, PriceListItem, PriceListStatus},
+        Money,
+    };
 
     #[test]
     fn price_list_item_ser_test() {
         let price_list_item = PriceListItem::new(
             DateTime::from_timestamp_millis(1737068749821).unwrap(),
             TimeDelta::milliseconds(12),
-            1,
+            Money::from_legacy_price(45123),
             PriceCategory::Medium,
         );
         let serialized = serde_json::to_string(&price_list_item).unwrap();
@@ -78,13 +361,20 @@ mod tests {
         assert_ser_tokens(
             &price_list_item,
             &[
-                Token::Struct { name: "PriceListItem", len: 5 },
+                Token::Struct { name: "PriceListItem", len: 6 },
                 Token::Str("startsAt"),
                 Token::I64(1737068749821),
                 Token::Str("duration"),
                 Token::I64(12),
                 Token::Str("price"),
-                Token::I32(1),
+                Token::I64(45123),
+                Token::Str("unitPrice"),
+                Token::Struct { name: "Money", len: 2 },
+                Token::Str("value"),
+                Token::Str("0.45123"),
+                Token::Str("unit"),
+                Token::Str("PLN/kWh"),
+                Token::StructEnd,
                 Token::Str("weight"),
                 Token::I64(0),
                 Token::Str("category"),
@@ -93,4 +383,25 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn price_list_version_should_change_only_with_prices() {
+        let day = DateTime::from_timestamp_millis(1724623200000).unwrap();
+        let item = |price| {
+            PriceListItem::new(day, TimeDelta::hours(24), Money::from_legacy_price(price), PriceCategory::Medium)
+        };
+        let mut price_list = PriceList::new(day, "DayAheadMarket", day, vec![item(45123)]);
+        let fetched_again = PriceList::new(day, "DayAheadMarket", day + TimeDelta::hours(1), vec![item(45123)]);
+        price_list.set_status(PriceListStatus::Estimated);
+        price_list.add_warning("Price is suspicious".to_owned());
+
+        assert_eq!(price_list.version().len(), 16);
+        assert_eq!(price_list.version(), fetched_again.version());
+
+        let changed = price_list.with_items(vec![item(45124)]);
+        assert_ne!(changed.version(), price_list.version());
+        assert_eq!(changed.status(), PriceListStatus::Estimated);
+        assert_eq!(changed.warnings(), ["Price is suspicious"]);
+        assert_ne!(PriceList::new(day, "Entsoe", day, vec![item(45123)]).version(), price_list.version());
+    }
 }
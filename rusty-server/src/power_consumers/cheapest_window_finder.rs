@@ -0,0 +1,252 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rust_decimal::Decimal;
+
+use crate::{
+    model::{AppError, CheapestWindow, ConsumptionPeriod, Money, PriceListItem},
+    price_list_providers::TimePeriodPriceListService,
+};
+
+use super::PowerConsumer;
+
+/// CheapestWindowFinder answers when it is the cheapest to consume energy for the given duration
+/// between two points in time without creating consumption plan.
+/// Contiguous window is for consumers which can not be interrupted, e.g. dishwasher,
+/// otherwise price list items are selected the same way as for the consumption plan of power consumer.
+pub struct CheapestWindowFinder {
+    time_period_price_list_service: Arc<TimePeriodPriceListService>,
+}
+
+impl CheapestWindowFinder {
+    pub fn new(time_period_price_list_service: Arc<TimePeriodPriceListService>) -> Self {
+        Self { time_period_price_list_service }
+    }
+
+    /// cost of consuming 1 kW for the given duration
+    pub(crate) fn relative_cost(price: Money, duration: &TimeDelta) -> Decimal {
+        price.amount() * Decimal::from(duration.num_milliseconds())
+            / Decimal::from(TimeDelta::hours(1).num_milliseconds())
+    }
+
+    fn create_cheapest_window(
+        periods: Vec<ConsumptionPeriod>,
+        duration: &TimeDelta,
+        contiguous: bool,
+        relative_cost: Decimal,
+    ) -> CheapestWindow {
+        let hours = Decimal::from(duration.num_milliseconds()) / Decimal::from(TimeDelta::hours(1).num_milliseconds());
+        CheapestWindow {
+            starts_at: periods[0].starts_at,
+            ends_at: periods[periods.len() - 1].ends_at,
+            duration: *duration,
+            contiguous,
+            average_price: Money::new(relative_cost / hours),
+            relative_cost: relative_cost.normalize(),
+            periods,
+        }
+    }
+
+    /// relative cost of the window starting at given time, it is None when price list does not cover entire window
+    fn window_relative_cost(
+        price_list: &[PriceListItem],
+        starts_at: &DateTime<Utc>,
+        duration: &TimeDelta,
+    ) -> Option<Decimal> {
+        let ends_at = *starts_at + *duration;
+        let mut covered = TimeDelta::zero();
+        let mut relative_cost = Decimal::ZERO;
+        for item in price_list {
+            let overlap = (*item.starts_at() + *item.duration()).min(ends_at) - *item.starts_at().max(starts_at);
+            if overlap > TimeDelta::zero() {
+                covered += overlap;
+                relative_cost += Self::relative_cost(item.price(), &overlap);
+            }
+        }
+        (covered == *duration).then_some(relative_cost)
+    }
+
+    /// cost of the window changes linearly between price changes, so the cheapest window starts
+    /// at price list item start or ends at price list item end, earlier window wins when costs are equal
+    fn find_contiguous_window(
+        price_list: &[PriceListItem],
+        duration: &TimeDelta,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> Option<CheapestWindow> {
+        let latest_start = *to - *duration;
+        let mut candidates: Vec<DateTime<Utc>> = price_list
+            .iter()
+            .flat_map(|item| [*item.starts_at(), *item.starts_at() + *item.duration() - *duration])
+            .chain([*from])
+            .filter(|starts_at| starts_at >= from && *starts_at <= latest_start)
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+
+        let mut cheapest: Option<(DateTime<Utc>, Decimal)> = None;
+        for starts_at in candidates {
+            if let Some(relative_cost) = Self::window_relative_cost(price_list, &starts_at, duration) {
+                if cheapest.is_none_or(|(_, cheapest_cost)| relative_cost < cheapest_cost) {
+                    cheapest = Some((starts_at, relative_cost));
+                }
+            }
+        }
+
+        cheapest.map(|(starts_at, relative_cost)| {
+            let periods = vec![ConsumptionPeriod { starts_at, ends_at: starts_at + *duration }];
+            Self::create_cheapest_window(periods, duration, true, relative_cost)
+        })
+    }
+
+    /// price list items are selected as for consumption plan, periods are made from planned switch actions
+    fn find_split_window(
+        price_list: Vec<PriceListItem>,
+        duration: &TimeDelta,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> Option<CheapestWindow> {
+        let mut consumption_plan_items =
+            PowerConsumer::select_price_list_items_for_consumption_plan(price_list, duration, from, to, false);
+        let planned_duration = consumption_plan_items.iter().map(|item| *item.duration()).sum::<TimeDelta>();
+        if consumption_plan_items.is_empty() || planned_duration != *duration {
+            return None;
+        }
+        PowerConsumer::create_switch_actions(&mut consumption_plan_items, to);
+
+        let relative_cost = consumption_plan_items
+            .iter()
+            .map(|item| Self::relative_cost(item.price_list_item().price(), item.duration()))
+            .sum::<Decimal>();
+        let mut periods: Vec<ConsumptionPeriod> = Vec::new();
+        for switch_action in consumption_plan_items.iter().flat_map(|item| item.switch_actions()) {
+            if switch_action.switch_on() {
+                periods.push(ConsumptionPeriod { starts_at: *switch_action.at(), ends_at: *switch_action.at() });
+            } else if let Some(period) = periods.last_mut() {
+                period.ends_at = *switch_action.at();
+            }
+        }
+        Some(Self::create_cheapest_window(periods, duration, false, relative_cost))
+    }
+
+    pub async fn find_cheapest_window(
+        &self,
+        duration: &TimeDelta,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        contiguous: bool,
+    ) -> Result<CheapestWindow, AppError> {
+        if *duration <= TimeDelta::zero() {
+            return Err(AppError::user_error("Duration must be positive!"));
+        }
+        TimePeriodPriceListService::validate_time_range(from, to)?;
+        if *from + *duration > *to {
+            return Err(AppError::user_error("Duration is longer than the time between from and to!"));
+        }
+
+        let price_list = self.time_period_price_list_service.get_price_list(from, to).await?;
+        if contiguous {
+            Self::find_contiguous_window(&price_list, duration, from, to)
+        } else {
+            Self::find_split_window(price_list, duration, from, to)
+        }
+        .ok_or_else(|| AppError::not_found("Price list does not cover requested time range"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+    use rust_decimal::Decimal;
+
+    use crate::{
+        model::{ConsumptionPeriod, Money},
+        price_list_providers::{TimeOfUsePriceListProvider, TimePeriodPriceListService},
+    };
+
+    use super::CheapestWindowFinder;
+
+    fn create_cheapest_window_finder() -> CheapestWindowFinder {
+        CheapestWindowFinder::new(Arc::new(TimePeriodPriceListService::new(
+            Arc::new(TimeOfUsePriceListProvider::w12()),
+        )))
+    }
+
+    fn date_time(year: i32, month: u32, day: u32, hour: u32, min: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, hour, min, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn contiguous_window_should_start_with_the_night_tariff() {
+        let finder = create_cheapest_window_finder();
+
+        let from = date_time(2024, 8, 26, 19, 30);
+        let to = date_time(2024, 8, 27, 7, 0);
+        let window = finder.find_cheapest_window(&TimeDelta::minutes(135), &from, &to, true).await.unwrap();
+
+        assert_eq!(window.starts_at, date_time(2024, 8, 26, 22, 0));
+        assert_eq!(window.ends_at, date_time(2024, 8, 27, 0, 15));
+        assert_eq!(window.periods.len(), 1);
+        assert_eq!(window.average_price, Money::from_legacy_price(80000));
+        assert_eq!(window.relative_cost, Decimal::new(18, 1));
+    }
+
+    #[tokio::test]
+    async fn contiguous_window_should_end_with_the_day_tariff() {
+        let finder = create_cheapest_window_finder();
+
+        let from = date_time(2024, 8, 26, 8, 0);
+        let to = date_time(2024, 8, 26, 17, 0);
+        let window = finder.find_cheapest_window(&TimeDelta::minutes(180), &from, &to, true).await.unwrap();
+
+        assert_eq!(window.starts_at, date_time(2024, 8, 26, 12, 0));
+        assert_eq!(window.ends_at, date_time(2024, 8, 26, 15, 0));
+        assert_eq!(window.relative_cost, Decimal::new(32, 1));
+    }
+
+    #[tokio::test]
+    async fn split_window_should_use_consumption_plan_selection() {
+        let finder = create_cheapest_window_finder();
+
+        let from = date_time(2024, 8, 26, 14, 0);
+        let to = date_time(2024, 8, 26, 23, 0);
+        let window = finder.find_cheapest_window(&TimeDelta::minutes(120), &from, &to, false).await.unwrap();
+
+        assert!(!window.contiguous);
+        assert_eq!(
+            window.periods,
+            vec![
+                ConsumptionPeriod { starts_at: from, ends_at: date_time(2024, 8, 26, 15, 0) },
+                ConsumptionPeriod { starts_at: date_time(2024, 8, 26, 22, 0), ends_at: to },
+            ]
+        );
+        assert_eq!(window.average_price, Money::from_legacy_price(80000));
+    }
+
+    #[tokio::test]
+    async fn window_longer_than_time_range_should_be_rejected() {
+        let finder = create_cheapest_window_finder();
+
+        let from = date_time(2024, 8, 26, 12, 0);
+        let to = date_time(2024, 8, 26, 13, 0);
+        assert!(finder.find_cheapest_window(&TimeDelta::minutes(90), &from, &to, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn time_range_longer_than_limit_should_be_rejected() {
+        let finder = create_cheapest_window_finder();
+
+        let from = date_time(2024, 8, 26, 12, 0);
+        let to = from + TimeDelta::days(367);
+        match finder.find_cheapest_window(&TimeDelta::hours(1), &from, &to, false).await {
+            Err(error) => {
+                assert_eq!(error.code(), StatusCode::BAD_REQUEST);
+                assert_eq!(error.to_string(), "[UserError] Time range can not be longer than 366 days!");
+            }
+            Ok(_) => panic!("Time range longer than the limit should be rejected"),
+        }
+    }
+}
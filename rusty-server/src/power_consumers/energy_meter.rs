@@ -0,0 +1,316 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rust_decimal::Decimal;
+
+use crate::{
+    model::{ActualConsumption, AppError, ConsumptionPlan, MeterReading, SwitchActionState},
+    settings::EnergyMeterConfig,
+};
+
+use super::{HomeAssistantSensorValue, HomeAssistantService, SharedSwitchBackend};
+
+/// returns sensor value multiplied to the base unit, sensor without unit is expected to report the base unit
+fn sensor_value_in(
+    entity_id: &str,
+    sensor: HomeAssistantSensorValue,
+    base_unit: &str,
+    units: &[(&str, f64)],
+) -> Result<f64, AppError> {
+    match sensor.unit_of_measurement.as_deref() {
+        None => Ok(sensor.value),
+        Some(unit) if unit == base_unit => Ok(sensor.value),
+        Some(unit) => units
+            .iter()
+            .find(|(known_unit, _)| *known_unit == unit)
+            .map(|(_, multiplier)| sensor.value * multiplier)
+            .ok_or_else(|| {
+                AppError::user_error(&format!(
+                    "Unit {} of sensor {} is not supported, expected {}",
+                    unit, entity_id, base_unit
+                ))
+            }),
+    }
+}
+
+/// EnergyMeter reads meter of the power consumer configured in its `meter` settings
+pub struct EnergyMeter {
+    config: EnergyMeterConfig,
+    home_assistant: Arc<HomeAssistantService>,
+    switch_backend: SharedSwitchBackend,
+}
+
+impl EnergyMeter {
+    pub fn new(
+        config: EnergyMeterConfig,
+        home_assistant: Arc<HomeAssistantService>,
+        switch_backend: SharedSwitchBackend,
+    ) -> Self {
+        Self { config, home_assistant, switch_backend }
+    }
+
+    pub async fn read(&self, device_id: &str) -> Result<MeterReading, AppError> {
+        let measured_at = Utc::now();
+        match &self.config {
+            EnergyMeterConfig::HomeAssistantEnergy { entity_id } => {
+                let sensor = self.home_assistant.read_sensor(entity_id).await?;
+                let energy_total_kwh = sensor_value_in(entity_id, sensor, "kWh", &[("Wh", 0.001), ("MWh", 1000.0)])?;
+                Ok(MeterReading { energy_total_kwh: Some(energy_total_kwh), power_w: None, measured_at })
+            }
+            EnergyMeterConfig::HomeAssistantPower { entity_id } => {
+                let sensor = self.home_assistant.read_sensor(entity_id).await?;
+                let power_w = sensor_value_in(entity_id, sensor, "W", &[("kW", 1000.0)])?;
+                Ok(MeterReading { energy_total_kwh: None, power_w: Some(power_w), measured_at })
+            }
+            EnergyMeterConfig::Backend => {
+                let power_reading = self
+                    .switch_backend
+                    .read_power(device_id)
+                    .await?
+                    .ok_or_else(|| AppError::user_error(&format!("Device {} does not measure power", device_id)))?;
+                Ok(MeterReading {
+                    energy_total_kwh: power_reading.energy_total_kwh,
+                    power_w: Some(power_reading.power_w),
+                    measured_at: power_reading.measured_at,
+                })
+            }
+        }
+    }
+}
+
+fn to_decimal(value: f64) -> Option<Decimal> {
+    Decimal::try_from(value).ok()
+}
+
+fn hours(duration: TimeDelta) -> Decimal {
+    Decimal::from(duration.num_milliseconds()) / Decimal::from(TimeDelta::hours(1).num_milliseconds())
+}
+
+/// energy consumed between switching on and off, it is a difference of energy counters when both readings have them,
+/// otherwise power read before switching off is taken as power of the whole period
+fn period_energy_kwh(switched_on: &MeterReading, switched_off: &MeterReading) -> Option<Decimal> {
+    match (switched_on.energy_total_kwh, switched_off.energy_total_kwh, switched_off.power_w) {
+        (Some(start), Some(end), _) if end >= start => to_decimal(end - start),
+        (Some(_), Some(_), _) => None,
+        (_, _, Some(power_w)) => Some(
+            to_decimal(power_w)? / Decimal::ONE_THOUSAND * hours(switched_off.measured_at - switched_on.measured_at),
+        ),
+        _ => None,
+    }
+}
+
+/// cost of the energy consumed evenly between from and to, it is split over price list items of the plan
+/// by their overlap with the period
+fn period_cost(
+    consumption_plan: &ConsumptionPlan,
+    energy_kwh: Decimal,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> Option<Decimal> {
+    let overlaps: Vec<(Decimal, TimeDelta)> = consumption_plan
+        .consumption_plan_items
+        .iter()
+        .map(|item| item.price_list_item())
+        .map(|item| {
+            (item.price().amount(), (*item.starts_at() + *item.duration()).min(*to) - *item.starts_at().max(from))
+        })
+        .filter(|(_, overlap)| *overlap > TimeDelta::zero())
+        .collect();
+    let covered = overlaps.iter().map(|(_, overlap)| *overlap).sum::<TimeDelta>();
+    if covered <= TimeDelta::zero() {
+        return None;
+    }
+    Some(overlaps.iter().map(|(price, overlap)| energy_kwh * hours(*overlap) / hours(covered) * price).sum())
+}
+
+/// actual consumption of the executed plan measured by meter readings of its switch actions,
+/// it is None when any period of consumption has not been measured
+pub fn measure_actual_consumption(consumption_plan: &ConsumptionPlan) -> Option<ActualConsumption> {
+    let mut energy_kwh = Decimal::ZERO;
+    let mut cost = Decimal::ZERO;
+    let mut switched_on: Option<&MeterReading> = None;
+    let mut measured = false;
+    for switch_action in consumption_plan.flat_switch_actions() {
+        if *switch_action.state() != SwitchActionState::Executed {
+            continue;
+        }
+        match (switch_action.switch_on(), switched_on) {
+            (true, None) => switched_on = Some(switch_action.meter_reading()?),
+            (false, Some(start)) => {
+                let end = switch_action.meter_reading()?;
+                let period_energy_kwh = period_energy_kwh(start, end)?;
+                energy_kwh += period_energy_kwh;
+                cost += period_cost(consumption_plan, period_energy_kwh, &start.measured_at, &end.measured_at)?;
+                switched_on = None;
+                measured = true;
+            }
+            _ => {}
+        }
+    }
+    measured.then(|| ActualConsumption { energy_kwh: energy_kwh.round_dp(3), cost: cost.round_dp(2) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+    use rust_decimal::Decimal;
+    use uuid::Uuid;
+    use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+    use crate::{
+        model::{
+            ActualConsumption, ConsumptionPlan, ConsumptionPlanItem, ConsumptionPlanState, MeterReading, Money,
+            PriceCategory, PriceListItem, SwitchAction, SwitchActionState,
+        },
+        power_consumers::{HomeAssistantService, InMemorySwitchBackend},
+        settings::{EnergyMeterConfig, HttpCallConfig},
+    };
+
+    use super::{measure_actual_consumption, EnergyMeter};
+
+    fn at(hour: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 8, 26, hour, min, 0).unwrap()
+    }
+
+    fn create_meter(server: &MockServer, config: EnergyMeterConfig) -> EnergyMeter {
+        let home_assistant =
+            HomeAssistantService::new(&HttpCallConfig { base_url: server.uri(), token: "token".to_owned() });
+        EnergyMeter::new(config, Arc::new(home_assistant), Arc::new(InMemorySwitchBackend::new()))
+    }
+
+    async fn mount_sensor(server: &MockServer, entity_id: &str, state: &str, unit: &str) {
+        Mock::given(path(format!("/api/states/{}", entity_id)))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                r#"{{"entity_id": "{}", "state": "{}", "attributes": {{"unit_of_measurement": "{}"}}}}"#,
+                entity_id, state, unit
+            )))
+            .mount(server)
+            .await;
+    }
+
+    fn executed_switch_action(switch_on: bool, meter_reading: Option<MeterReading>) -> SwitchAction {
+        let mut switch_action =
+            SwitchAction::new(meter_reading.as_ref().map_or(at(20, 0), |r| r.measured_at), switch_on);
+        switch_action.set_state(SwitchActionState::Executed);
+        switch_action.set_meter_reading(meter_reading);
+        switch_action
+    }
+
+    fn energy_reading(energy_total_kwh: f64, measured_at: DateTime<Utc>) -> Option<MeterReading> {
+        Some(MeterReading { energy_total_kwh: Some(energy_total_kwh), power_w: None, measured_at })
+    }
+
+    fn power_reading(power_w: f64, measured_at: DateTime<Utc>) -> Option<MeterReading> {
+        Some(MeterReading { energy_total_kwh: None, power_w: Some(power_w), measured_at })
+    }
+
+    /// plan from 20:00 to 22:00, the first hour costs 0.8 and the second one 1.6 per kWh
+    fn create_consumption_plan(switched_on: SwitchAction, switched_off: SwitchAction) -> ConsumptionPlan {
+        let item = |hour, price| {
+            ConsumptionPlanItem::new(
+                PriceListItem::new(
+                    at(hour, 0),
+                    TimeDelta::hours(1),
+                    Money::from_legacy_price(price),
+                    PriceCategory::Min,
+                ),
+                TimeDelta::hours(1),
+            )
+        };
+        let mut first_item = item(20, 80000);
+        first_item.switch_actions_mut().push(switched_on);
+        let mut second_item = item(21, 160000);
+        second_item.switch_actions_mut().push(switched_off);
+        ConsumptionPlan {
+            id: Uuid::new_v4(),
+            created_at: at(12, 0),
+            consumption_duration: TimeDelta::hours(2),
+            finish_at: at(22, 0),
+            consume_while_negative: false,
+            consumption_plan_items: vec![first_item, second_item],
+            state: ConsumptionPlanState::Executed,
+            price_lists: Vec::new(),
+            estimated_cost: None,
+            actual_consumption: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn energy_sensor_should_be_read_in_kwh() {
+        let server = MockServer::start().await;
+        mount_sensor(&server, "sensor.boiler_energy", "152374.5", "Wh").await;
+
+        let meter_reading = create_meter(
+            &server,
+            EnergyMeterConfig::HomeAssistantEnergy { entity_id: "sensor.boiler_energy".to_owned() },
+        )
+        .read("switch.boiler")
+        .await
+        .unwrap();
+
+        assert_eq!(meter_reading.energy_total_kwh, Some(152.3745));
+        assert_eq!(meter_reading.power_w, None);
+    }
+
+    #[tokio::test]
+    async fn power_sensor_with_unknown_unit_or_value_should_be_rejected() {
+        let server = MockServer::start().await;
+        mount_sensor(&server, "sensor.boiler_power", "1200", "BTU/h").await;
+        mount_sensor(&server, "sensor.heater_power", "unavailable", "W").await;
+        let read = |entity_id: &str| {
+            create_meter(&server, EnergyMeterConfig::HomeAssistantPower { entity_id: entity_id.to_owned() })
+        };
+
+        assert!(read("sensor.boiler_power").read("switch.boiler").await.is_err());
+        assert!(read("sensor.heater_power").read("switch.heater").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn backend_meter_should_fail_when_backend_does_not_measure_power() {
+        let server = MockServer::start().await;
+        assert!(create_meter(&server, EnergyMeterConfig::Backend).read("switch.boiler").await.is_err());
+    }
+
+    #[test]
+    fn energy_counters_should_measure_energy_and_cost_by_prices_of_the_plan() {
+        let consumption_plan = create_consumption_plan(
+            executed_switch_action(true, energy_reading(100.0, at(20, 0))),
+            executed_switch_action(false, energy_reading(103.0, at(22, 0))),
+        );
+
+        assert_eq!(
+            measure_actual_consumption(&consumption_plan),
+            Some(ActualConsumption { energy_kwh: Decimal::new(3, 0), cost: Decimal::new(360, 2) })
+        );
+    }
+
+    #[test]
+    fn power_read_before_switching_off_should_be_used_for_the_whole_period() {
+        let consumption_plan = create_consumption_plan(
+            executed_switch_action(true, power_reading(0.0, at(20, 30))),
+            executed_switch_action(false, power_reading(2000.0, at(22, 0))),
+        );
+
+        assert_eq!(
+            measure_actual_consumption(&consumption_plan),
+            Some(ActualConsumption { energy_kwh: Decimal::new(3, 0), cost: Decimal::new(4, 0) })
+        );
+    }
+
+    #[test]
+    fn consumption_should_not_be_measured_without_meter_readings() {
+        let consumption_plan = create_consumption_plan(
+            executed_switch_action(true, energy_reading(100.0, at(20, 0))),
+            executed_switch_action(false, None),
+        );
+        assert_eq!(measure_actual_consumption(&consumption_plan), None);
+
+        let consumption_plan = create_consumption_plan(
+            executed_switch_action(true, energy_reading(100.0, at(20, 0))),
+            executed_switch_action(false, energy_reading(12.0, at(22, 0))),
+        );
+        assert_eq!(measure_actual_consumption(&consumption_plan), None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{model::AppError, settings::HomeAssistantImportConfig};
+
+use super::{HomeAssistantDomain, HomeAssistantService, SharedSwitchBackend};
+
+/// Power consumer imported from Home Assistant, it is switched by Home Assistant backend
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImportedPowerConsumer {
+    pub device_id: String,
+    pub name: String,
+}
+
+/// glob pattern matches whole entity id, `*` matches any text and `?` single character
+fn glob_to_regex(pattern: &str) -> Result<Regex, AppError> {
+    let expression = regex::escape(pattern).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("^{}$", expression))
+        .map_err(|e| AppError::user_error(&format!("Entity pattern {} is not valid: {}", pattern, e)))
+}
+
+/// HomeAssistantImporter finds Home Assistant entities which could be power consumers and keeps
+/// the imported ones in the JSON file, so they are registered again after restart
+pub struct HomeAssistantImporter {
+    home_assistant: Arc<HomeAssistantService>,
+    domains: Vec<HomeAssistantDomain>,
+    entity_patterns: Vec<Regex>,
+    file: PathBuf,
+}
+
+impl HomeAssistantImporter {
+    pub fn new(
+        home_assistant: Arc<HomeAssistantService>,
+        config: &HomeAssistantImportConfig,
+    ) -> Result<Self, AppError> {
+        Ok(Self {
+            home_assistant,
+            domains: config.domains.clone(),
+            entity_patterns: config
+                .entity_patterns
+                .iter()
+                .map(|pattern| glob_to_regex(pattern))
+                .collect::<Result<_, _>>()?,
+            file: PathBuf::from(&config.file),
+        })
+    }
+
+    pub fn switch_backend(&self) -> SharedSwitchBackend {
+        self.home_assistant.clone()
+    }
+
+    /// entity live state is received when the entity is imported
+    pub fn watch(&self, entity_id: &str) {
+        self.home_assistant.entity_states().watch(entity_id);
+    }
+
+    /// only entities of the domains which can be switched without configuration are matched,
+    /// climate needs its setpoints to be configured
+    fn matches(&self, entity_id: &str) -> bool {
+        HomeAssistantDomain::from_entity_id(entity_id).is_ok_and(|domain| {
+            domain != HomeAssistantDomain::Climate
+                && (self.domains.contains(&domain)
+                    || self.entity_patterns.iter().any(|pattern| pattern.is_match(entity_id)))
+        })
+    }
+
+    /// returns entities matching configured domains or patterns sorted by entity id,
+    /// entity without friendly name is named by its id
+    pub async fn find_entities(&self) -> Result<Vec<ImportedPowerConsumer>, AppError> {
+        let mut entities: Vec<ImportedPowerConsumer> = self
+            .home_assistant
+            .list_entities()
+            .await?
+            .into_iter()
+            .filter(|entity| self.matches(&entity.entity_id))
+            .map(|entity| ImportedPowerConsumer {
+                name: entity.friendly_name.unwrap_or_else(|| entity.entity_id.clone()),
+                device_id: entity.entity_id,
+            })
+            .collect();
+        entities.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        Ok(entities)
+    }
+
+    /// returns power consumers imported before, nothing has been imported when the file does not exist
+    pub async fn load(&self) -> Result<Vec<ImportedPowerConsumer>, AppError> {
+        match tokio::fs::read_to_string(&self.file).await {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| {
+                AppError::system_error(&format!(
+                    "Imported power consumers {} are not parsable: {}",
+                    self.file.display(),
+                    e
+                ))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(AppError::system_error(&format!(
+                "Imported power consumers {} can not be read: {}",
+                self.file.display(),
+                e
+            ))),
+        }
+    }
+
+    /// file is written next to its final location and renamed, so a half written file is never loaded
+    pub async fn store(&self, imported: &[ImportedPowerConsumer]) -> Result<(), AppError> {
+        let store_failed = |e: std::io::Error| {
+            AppError::system_error(&format!(
+                "Imported power consumers {} can not be stored: {}",
+                self.file.display(),
+                e
+            ))
+        };
+        let text = serde_json::to_string_pretty(imported)
+            .map_err(|e| AppError::system_error(&format!("Imported power consumers can not be stored: {}", e)))?;
+
+        if let Some(directory) = self.file.parent().filter(|directory| *directory != Path::new("")) {
+            tokio::fs::create_dir_all(directory).await.map_err(store_failed)?;
+        }
+        let temporary_path = self.file.with_extension("json.tmp");
+        tokio::fs::write(&temporary_path, text).await.map_err(store_failed)?;
+        tokio::fs::rename(&temporary_path, &self.file).await.map_err(store_failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+    use crate::{
+        power_consumers::{HomeAssistantDomain, HomeAssistantService},
+        settings::{HomeAssistantImportConfig, HttpCallConfig},
+    };
+
+    use super::{HomeAssistantImporter, ImportedPowerConsumer};
+
+    fn create_importer(base_url: &str) -> HomeAssistantImporter {
+        let home_assistant =
+            HomeAssistantService::new(&HttpCallConfig { base_url: base_url.to_owned(), token: "token".to_owned() });
+        let file = std::env::temp_dir().join(format!("imported-power-consumers-{}", uuid::Uuid::new_v4()));
+        HomeAssistantImporter::new(
+            Arc::new(home_assistant),
+            &HomeAssistantImportConfig {
+                domains: vec![HomeAssistantDomain::InputBoolean],
+                entity_patterns: vec!["switch.*_plug".to_owned(), "light.lamp_?".to_owned(), "climate.*".to_owned()],
+                file: file.join("power-consumers.json").to_string_lossy().into_owned(),
+                import_on_startup: false,
+            },
+        )
+        .unwrap()
+    }
+
+    fn imported(device_id: &str, name: &str) -> ImportedPowerConsumer {
+        ImportedPowerConsumer { device_id: device_id.to_owned(), name: name.to_owned() }
+    }
+
+    #[test]
+    fn entities_of_domains_or_matching_patterns_should_be_matched() {
+        let importer = create_importer("http://localhost");
+        assert!(importer.matches("input_boolean.boiler"));
+        assert!(importer.matches("switch.kitchen_plug"));
+        assert!(importer.matches("light.lamp_1"));
+        assert!(!importer.matches("light.lamp_12"));
+        assert!(!importer.matches("climate.heat_pump"));
+        assert!(!importer.matches("switch.kitchen_plug_power"));
+        assert!(!importer.matches("switch.boiler"));
+        assert!(!importer.matches("sensor.kitchen_plug"));
+    }
+
+    #[tokio::test]
+    async fn entities_should_be_found_with_friendly_names() {
+        let server = MockServer::start().await;
+        Mock::given(path("/api/states"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(include_str!("../../tests/fixtures/home_assistant/states.json")),
+            )
+            .mount(&server)
+            .await;
+
+        let entities = create_importer(&server.uri()).find_entities().await.unwrap();
+
+        assert_eq!(
+            entities,
+            vec![
+                imported("input_boolean.boiler", "Boiler"),
+                imported("light.lamp_1", "Living room lamp"),
+                imported("switch.kitchen_plug", "switch.kitchen_plug"),
+                imported("switch.washing_machine_plug", "Washing machine"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn imported_power_consumers_should_be_stored_and_loaded() {
+        let importer = create_importer("http://localhost");
+        assert!(importer.load().await.unwrap().is_empty());
+
+        let power_consumers = vec![imported("input_boolean.boiler", "Boiler")];
+        importer.store(&power_consumers).await.unwrap();
+
+        assert_eq!(importer.load().await.unwrap(), power_consumers);
+    }
+}
@@ -1,34 +1,272 @@
-use crate::{model::AppError, settings::HttpCallConfig};
+use std::{collections::HashMap, sync::Arc};
 
-use serde::Serialize;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    model::{AppError, LiveDeviceState, SwitchCapabilities},
+    settings::{HomeAssistantEntityConfig, HttpCallConfig},
+};
+
+use super::{HomeAssistantEntityStates, SwitchBackend};
+
+/// Home Assistant domains which can switch power consumers
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HomeAssistantDomain {
+    Switch,
+    Light,
+    InputBoolean,
+    Script,
+    Button,
+    Climate,
+}
+
+impl HomeAssistantDomain {
+    /// domain is the part of the entity id before the dot, e.g. `switch` of `switch.boiler`
+    pub fn from_entity_id(entity_id: &str) -> Result<Self, AppError> {
+        match entity_id.split_once('.').map(|(domain, _)| domain) {
+            Some("switch") => Ok(Self::Switch),
+            Some("light") => Ok(Self::Light),
+            Some("input_boolean") => Ok(Self::InputBoolean),
+            Some("script") => Ok(Self::Script),
+            Some("button") => Ok(Self::Button),
+            Some("climate") => Ok(Self::Climate),
+            _ => Err(AppError::user_error(&format!("Domain of entity {} is not supported", entity_id))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Switch => "switch",
+            Self::Light => "light",
+            Self::InputBoolean => "input_boolean",
+            Self::Script => "script",
+            Self::Button => "button",
+            Self::Climate => "climate",
+        }
+    }
+
+    /// state of script tells only whether it is running and button has no state at all
+    fn has_readable_state(&self) -> bool {
+        !matches!(self, Self::Script | Self::Button)
+    }
+
+    /// services switching entity on and off, button is only pressed to switch it on
+    fn default_services(&self) -> (Option<&'static str>, Option<&'static str>) {
+        match self {
+            Self::Switch | Self::Light | Self::InputBoolean | Self::Script => (Some("turn_on"), Some("turn_off")),
+            Self::Button => (Some("press"), None),
+            Self::Climate => (Some("set_temperature"), Some("set_temperature")),
+        }
+    }
+}
+
+/// Service called to switch the entity, temperature is the setpoint of climate entity
+#[derive(Debug, PartialEq)]
+struct ServiceCall {
+    domain: HomeAssistantDomain,
+    service: String,
+    temperature: Option<f64>,
+}
 
 #[derive(Serialize)]
-struct EntityRef {
+struct ServiceData<'a> {
+    entity_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+struct EntityAttributes {
+    temperature: Option<f64>,
+    friendly_name: Option<String>,
+    unit_of_measurement: Option<String>,
+}
+
+/// State of the entity returned by Home Assistant, switch state is `on`, `off` or `unavailable`,
+/// climate state is its hvac mode and its setpoint is in the attributes
+#[derive(Deserialize)]
+struct EntityState {
+    state: String,
+    #[serde(default)]
+    attributes: EntityAttributes,
+}
+
+/// Entity listed by `/api/states`
+#[derive(Deserialize)]
+struct ListedEntity {
     entity_id: String,
+    #[serde(default)]
+    attributes: EntityAttributes,
+}
+
+/// Entity of Home Assistant with its friendly name
+#[derive(Debug, PartialEq, Clone)]
+pub struct HomeAssistantEntity {
+    pub entity_id: String,
+    pub friendly_name: Option<String>,
+}
+
+/// Numeric state of the sensor entity with its unit, e.g. energy in kWh
+#[derive(Debug, PartialEq, Clone)]
+pub struct HomeAssistantSensorValue {
+    pub value: f64,
+    pub unit_of_measurement: Option<String>,
 }
 
 /// Api to HomeAssistant application to change switch state
 ///
-/// It uses reqwest async calls
+/// It uses reqwest async calls. Entities of switch, light, input boolean, script, button and climate domains
+/// are switched by the services of their domain, services and climate setpoints are configured per entity.
+/// State of the entity is read from live states pushed by WebSocket client when it is connected.
 pub struct HomeAssistantService {
     token: String,
     base_url: String,
+    entities: HashMap<String, HomeAssistantEntityConfig>,
+    entity_states: Arc<HomeAssistantEntityStates>,
 }
 
 impl HomeAssistantService {
     pub fn new(home_assistant_config: &HttpCallConfig) -> Self {
-        Self { token: home_assistant_config.token.clone(), base_url: home_assistant_config.base_url.clone() }
+        Self {
+            token: home_assistant_config.token.clone(),
+            base_url: home_assistant_config.base_url.clone(),
+            entities: HashMap::new(),
+            entity_states: Arc::new(HomeAssistantEntityStates::new([])),
+        }
+    }
+
+    /// configurations of the power consumer entities, they are validated before the first call
+    /// and their live states are kept
+    pub fn with_entities(mut self, entities: HashMap<String, HomeAssistantEntityConfig>) -> Result<Self, AppError> {
+        self.entity_states = Arc::new(HomeAssistantEntityStates::new(entities.keys().cloned()));
+        self.entities = entities;
+        for entity_id in self.entities.keys() {
+            self.service_call(entity_id, true)?;
+            self.service_call(entity_id, false)?;
+        }
+        Ok(self)
+    }
+
+    /// live states of the entities, they are fed by HomeAssistantWebSocketClient
+    pub fn entity_states(&self) -> Arc<HomeAssistantEntityStates> {
+        self.entity_states.clone()
+    }
+
+    /// returns all entities known to Home Assistant
+    pub async fn list_entities(&self) -> Result<Vec<HomeAssistantEntity>, AppError> {
+        let text = self.get("/api/states").await?;
+        let entities = serde_json::from_str::<Vec<ListedEntity>>(&text)
+            .map_err(|e| AppError::system_error(&format!("Home assistant states are not parsable: {}", e)))?;
+        Ok(entities
+            .into_iter()
+            .map(|entity| HomeAssistantEntity {
+                entity_id: entity.entity_id,
+                friendly_name: entity.attributes.friendly_name,
+            })
+            .collect())
+    }
+
+    /// returns numeric state of the sensor, sensor which is unavailable or unknown has no value
+    pub async fn read_sensor(&self, entity_id: &str) -> Result<HomeAssistantSensorValue, AppError> {
+        let text = self.get(&format!("/api/states/{}", entity_id)).await?;
+        let entity_state = serde_json::from_str::<EntityState>(&text)
+            .map_err(|e| AppError::system_error(&format!("Home assistant state is not parsable: {}", e)))?;
+        let value = entity_state.state.parse::<f64>().map_err(|_| {
+            AppError::system_error(&format!("Sensor {} has no numeric value: {}", entity_id, entity_state.state))
+        })?;
+        Ok(HomeAssistantSensorValue { value, unit_of_measurement: entity_state.attributes.unit_of_measurement })
+    }
+
+    async fn get(&self, path: &str) -> Result<String, AppError> {
+        self.validate_token()?;
+
+        let request_failed =
+            |e: reqwest::Error| AppError::system_error(&format!("Request to home assistant {} failed: {}", path, e));
+        reqwest::Client::new()
+            .get(format!("{}{}", self.base_url, path))
+            .bearer_auth(&self.token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(request_failed)?
+            .text()
+            .await
+            .map_err(request_failed)
     }
 
-    pub async fn switch_device(&self, device_name: &str, switch_on: bool) -> Result<(), AppError> {
+    fn validate_token(&self) -> Result<(), AppError> {
         if self.token.is_empty() {
             return Err(AppError::system_error("Home assistant authorization token is missing"));
         }
+        Ok(())
+    }
+
+    fn entity_config(&self, entity_id: &str) -> Result<(HomeAssistantDomain, HomeAssistantEntityConfig), AppError> {
+        let config = self.entities.get(entity_id).cloned().unwrap_or_default();
+        let domain = match config.domain {
+            Some(domain) => domain,
+            None => HomeAssistantDomain::from_entity_id(entity_id)?,
+        };
+        Ok((domain, config))
+    }
+
+    /// returns service which switches the entity, None when there is nothing to call, e.g. button is not switched off
+    fn service_call(&self, entity_id: &str, switch_on: bool) -> Result<Option<ServiceCall>, AppError> {
+        let (domain, config) = self.entity_config(entity_id)?;
+        let (default_on_service, default_off_service) = domain.default_services();
+        let (service, temperature) = if switch_on {
+            (config.turn_on_service.as_deref().or(default_on_service), config.on_temperature)
+        } else {
+            (config.turn_off_service.as_deref().or(default_off_service), config.off_temperature)
+        };
+        let Some(service) = service else {
+            return Ok(None);
+        };
+        if service == "set_temperature" && temperature.is_none() {
+            return Err(AppError::user_error(&format!(
+                "Entity {} needs {} temperature to be switched by set_temperature",
+                entity_id,
+                if switch_on { "on" } else { "off" }
+            )));
+        }
+        let temperature = if domain == HomeAssistantDomain::Climate { temperature } else { None };
+        Ok(Some(ServiceCall { domain, service: service.to_owned(), temperature }))
+    }
+
+    /// climate is on when its setpoint is closer to the on temperature than to the off temperature,
+    /// without configured setpoints it is on when its hvac mode is not off
+    fn climate_state(&self, entity_id: &str, entity_state: &EntityState) -> Result<bool, AppError> {
+        let (_, config) = self.entity_config(entity_id)?;
+        match (config.on_temperature, config.off_temperature, entity_state.attributes.temperature) {
+            (Some(on_temperature), Some(off_temperature), Some(temperature)) => {
+                Ok((temperature - on_temperature).abs() < (temperature - off_temperature).abs())
+            }
+            _ => match entity_state.state.as_str() {
+                "off" => Ok(false),
+                "unavailable" | "unknown" => {
+                    Err(AppError::system_error(&format!("Device {} is {}", entity_id, entity_state.state)))
+                }
+                _ => Ok(true),
+            },
+        }
+    }
+}
 
-        let operation = if switch_on { "turn_on" } else { "turn_off" };
-        let url = format!("{}/api/services/switch/{}", self.base_url, operation);
-        let body = serde_json::to_string(&EntityRef { entity_id: device_name.to_owned() })
-            .map_err(|e| AppError::system_error(&format!("Home assistant request serialization error: {}", e)))?;
+#[async_trait]
+impl SwitchBackend for HomeAssistantService {
+    async fn switch_device(&self, device_name: &str, switch_on: bool) -> Result<(), AppError> {
+        self.validate_token()?;
+
+        let Some(service_call) = self.service_call(device_name, switch_on)? else {
+            return Ok(());
+        };
+        self.entity_states.request_switch(device_name, switch_on);
+        let url = format!("{}/api/services/{}/{}", self.base_url, service_call.domain.name(), service_call.service);
+        let body =
+            serde_json::to_string(&ServiceData { entity_id: device_name, temperature: service_call.temperature })
+                .map_err(|e| AppError::system_error(&format!("Home assistant request serialization error: {}", e)))?;
 
         reqwest::Client::new()
             .post(url)
@@ -36,7 +274,204 @@ impl HomeAssistantService {
             .body(body)
             .send()
             .await
+            .and_then(|response| response.error_for_status())
             .map(|_| ())
             .map_err(|e| AppError::system_error(&format!("Request to switch device  failed: {}", e)))
     }
+
+    async fn read_state(&self, device_name: &str) -> Result<bool, AppError> {
+        self.validate_token()?;
+
+        let (domain, _) = self.entity_config(device_name)?;
+        if !domain.has_readable_state() {
+            return Err(AppError::user_error(&format!("State of {} {} can not be read", domain.name(), device_name)));
+        }
+        if domain != HomeAssistantDomain::Climate {
+            if let Some(switched_on) = self.live_state(device_name).and_then(|live_state| live_state.switched_on) {
+                return Ok(switched_on);
+            }
+        }
+
+        let text = self.get(&format!("/api/states/{}", device_name)).await?;
+        let entity_state = serde_json::from_str::<EntityState>(&text)
+            .map_err(|e| AppError::system_error(&format!("Home assistant state is not parsable: {}", e)))?;
+
+        if domain == HomeAssistantDomain::Climate {
+            return self.climate_state(device_name, &entity_state);
+        }
+        match entity_state.state.as_str() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            state => Err(AppError::system_error(&format!("Device {} is {}", device_name, state))),
+        }
+    }
+
+    fn capabilities(&self, device_id: &str) -> SwitchCapabilities {
+        let read_state = self.entity_config(device_id).is_ok_and(|(domain, _)| domain.has_readable_state());
+        SwitchCapabilities { read_state, read_power: false }
+    }
+
+    fn live_state(&self, device_id: &str) -> Option<LiveDeviceState> {
+        self.entity_states.live_state(device_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        power_consumers::SwitchBackend,
+        settings::{HomeAssistantEntityConfig, HttpCallConfig},
+    };
+
+    use super::{HomeAssistantDomain, HomeAssistantService, ServiceCall};
+
+    fn http_call_config(base_url: &str) -> HttpCallConfig {
+        HttpCallConfig { base_url: base_url.to_owned(), token: "token".to_owned() }
+    }
+
+    fn create_service(base_url: &str) -> HomeAssistantService {
+        HomeAssistantService::new(&http_call_config(base_url))
+            .with_entities(HashMap::from([
+                (
+                    "climate.heat_pump".to_owned(),
+                    HomeAssistantEntityConfig {
+                        on_temperature: Some(55.0),
+                        off_temperature: Some(45.0),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "script.dishwasher_start".to_owned(),
+                    HomeAssistantEntityConfig {
+                        turn_off_service: Some("dishwasher_stop".to_owned()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "switch.boiler_relay".to_owned(),
+                    HomeAssistantEntityConfig { domain: Some(HomeAssistantDomain::Light), ..Default::default() },
+                ),
+            ]))
+            .unwrap()
+    }
+
+    fn service_call(domain: HomeAssistantDomain, service: &str, temperature: Option<f64>) -> Option<ServiceCall> {
+        Some(ServiceCall { domain, service: service.to_owned(), temperature })
+    }
+
+    #[test]
+    fn entity_should_be_switched_by_services_of_its_domain() {
+        let service = create_service("http://localhost");
+        assert_eq!(
+            service.service_call("switch.smart_plug", true).unwrap(),
+            service_call(HomeAssistantDomain::Switch, "turn_on", None)
+        );
+        assert_eq!(
+            service.service_call("input_boolean.boiler", false).unwrap(),
+            service_call(HomeAssistantDomain::InputBoolean, "turn_off", None)
+        );
+        assert_eq!(
+            service.service_call("switch.boiler_relay", true).unwrap(),
+            service_call(HomeAssistantDomain::Light, "turn_on", None)
+        );
+        assert_eq!(
+            service.service_call("button.washing_machine_start", true).unwrap(),
+            service_call(HomeAssistantDomain::Button, "press", None)
+        );
+        assert_eq!(service.service_call("button.washing_machine_start", false).unwrap(), None);
+        assert!(service.service_call("sensor.power", true).is_err());
+    }
+
+    #[test]
+    fn configured_services_and_setpoints_should_be_used() {
+        let service = create_service("http://localhost");
+        assert_eq!(
+            service.service_call("script.dishwasher_start", true).unwrap(),
+            service_call(HomeAssistantDomain::Script, "turn_on", None)
+        );
+        assert_eq!(
+            service.service_call("script.dishwasher_start", false).unwrap(),
+            service_call(HomeAssistantDomain::Script, "dishwasher_stop", None)
+        );
+        assert_eq!(
+            service.service_call("climate.heat_pump", true).unwrap(),
+            service_call(HomeAssistantDomain::Climate, "set_temperature", Some(55.0))
+        );
+        assert_eq!(
+            service.service_call("climate.heat_pump", false).unwrap(),
+            service_call(HomeAssistantDomain::Climate, "set_temperature", Some(45.0))
+        );
+    }
+
+    #[test]
+    fn climate_without_setpoints_should_be_rejected() {
+        let entities = HashMap::from([("climate.heat_pump".to_owned(), HomeAssistantEntityConfig::default())]);
+        assert!(HomeAssistantService::new(&http_call_config("http://localhost")).with_entities(entities).is_err());
+    }
+
+    #[tokio::test]
+    async fn climate_setpoint_should_be_set_by_service_call() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/services/climate/set_temperature"))
+            .and(header("authorization", "Bearer token"))
+            .and(body_json(serde_json::json!({"entity_id": "climate.heat_pump", "temperature": 55.0})))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        create_service(&server.uri()).switch_device("climate.heat_pump", true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn climate_state_should_be_read_from_its_setpoint() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/states/climate.heat_pump"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"entity_id": "climate.heat_pump", "state": "heat", "attributes": {"temperature": 45.0}}"#,
+            ))
+            .mount(&server)
+            .await;
+
+        let service = create_service(&server.uri());
+        assert!(!service.read_state("climate.heat_pump").await.unwrap());
+        assert!(service.read_state("button.washing_machine_start").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn script_state_should_not_be_read() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/states/script.dishwasher_start"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"entity_id": "script.dishwasher_start", "state": "off", "attributes": {}}"#),
+            )
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let error = create_service(&server.uri()).read_state("script.dishwasher_start").await.unwrap_err();
+        assert_eq!(error.to_string(), "[UserError] State of script script.dishwasher_start can not be read");
+    }
+
+    #[test]
+    fn state_of_buttons_and_scripts_should_not_be_readable() {
+        let service = create_service("http://localhost");
+        assert!(service.capabilities("switch.boiler").read_state);
+        assert!(service.capabilities("switch.boiler_relay").read_state);
+        assert!(service.capabilities("climate.heat_pump").read_state);
+        assert!(!service.capabilities("button.washing_machine_start").read_state);
+        assert!(!service.capabilities("script.dishwasher_start").read_state);
+        assert!(!service.capabilities("sensor.boiler_power").read_state);
+    }
 }
@@ -0,0 +1,507 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, sync::Notify};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    model::{AppError, LiveDeviceState},
+    settings::{HomeAssistantWebSocketConfig, HttpCallConfig},
+};
+
+/// state change which happens within this time from the switch requested by the application is not manual
+const SWITCH_ATTRIBUTION_WINDOW: TimeDelta = TimeDelta::seconds(60);
+
+/// id of the first subscription, entities watched later are subscribed with the next ids
+const FIRST_SUBSCRIPTION_ID: u64 = 1;
+
+/// HomeAssistantEntityStates keeps live states of the power consumer entities pushed by Home Assistant.
+/// Switches requested by the application are remembered, so toggle of the entity which has not been requested
+/// is detected as manual. States are served only while WebSocket connection is up.
+pub struct HomeAssistantEntityStates {
+    entity_ids: RwLock<HashSet<String>>,
+    entities_watched: Notify,
+    connected: AtomicBool,
+    states: Mutex<HashMap<String, LiveDeviceState>>,
+    requested_switches: Mutex<HashMap<String, (bool, DateTime<Utc>)>>,
+}
+
+impl HomeAssistantEntityStates {
+    pub fn new(entity_ids: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            entity_ids: RwLock::new(entity_ids.into_iter().collect()),
+            entities_watched: Notify::new(),
+            connected: AtomicBool::new(false),
+            states: Mutex::new(HashMap::new()),
+            requested_switches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// entity registered after start is subscribed by the connected client and watched from its current state
+    pub fn watch(&self, entity_id: &str) {
+        if self.entity_ids.write().unwrap().insert(entity_id.to_owned()) {
+            self.entities_watched.notify_one();
+        }
+    }
+
+    fn entity_ids(&self) -> Vec<String> {
+        let mut entity_ids: Vec<String> = self.entity_ids.read().unwrap().iter().cloned().collect();
+        entity_ids.sort();
+        entity_ids
+    }
+
+    pub fn request_switch(&self, entity_id: &str, switch_on: bool) {
+        self.requested_switches.lock().unwrap().insert(entity_id.to_owned(), (switch_on, Utc::now()));
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn live_state(&self, entity_id: &str) -> Option<LiveDeviceState> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.states.lock().unwrap().get(entity_id).cloned()
+    }
+
+    /// stores state of the watched entity, toggle between on and off is manual unless it has been requested recently,
+    /// change of the attributes keeps the state and its manual flag
+    pub fn update(&self, entity_id: &str, state: &str, changed_at: DateTime<Utc>) -> Option<LiveDeviceState> {
+        if !self.entity_ids.read().unwrap().contains(entity_id) {
+            return None;
+        }
+        let switched_on = match state {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        };
+        let mut states = self.states.lock().unwrap();
+        let previous = states.get(entity_id);
+        let toggled = matches!(
+            (previous.and_then(|previous| previous.switched_on), switched_on),
+            (Some(previous), Some(current)) if previous != current
+        );
+        let manual = if toggled {
+            let requested = self.requested_switches.lock().unwrap().remove(entity_id);
+            !matches!(
+                requested,
+                Some((switch_on, requested_at))
+                    if Some(switch_on) == switched_on && (changed_at - requested_at).abs() <= SWITCH_ATTRIBUTION_WINDOW
+            )
+        } else {
+            previous.filter(|previous| previous.state == state).is_some_and(|previous| previous.manual)
+        };
+        if toggled && manual {
+            tracing::warn!("Device {} has been switched {} manually", entity_id, state);
+        }
+
+        let live_state = LiveDeviceState { state: state.to_owned(), switched_on, changed_at, manual };
+        states.insert(entity_id.to_owned(), live_state.clone());
+        Some(live_state)
+    }
+}
+
+/// compressed state of `subscribe_entities`, times are in seconds since epoch,
+/// last change is missing when it is the same as the last update
+#[derive(Deserialize)]
+struct CompressedState {
+    #[serde(rename = "s")]
+    state: Option<String>,
+    #[serde(rename = "lc")]
+    last_changed: Option<f64>,
+    #[serde(rename = "lu")]
+    last_updated: Option<f64>,
+}
+
+impl CompressedState {
+    fn changed_at(&self) -> DateTime<Utc> {
+        self.last_changed
+            .or(self.last_updated)
+            .and_then(|seconds| DateTime::from_timestamp_millis((seconds * 1000.0).round() as i64))
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+/// changed fields of the state, state is missing when only attributes have changed
+#[derive(Deserialize)]
+struct CompressedStateDiff {
+    #[serde(rename = "+")]
+    additions: Option<CompressedState>,
+}
+
+/// current states of the subscribed entities are added first, then their changes are pushed
+#[derive(Deserialize)]
+struct EntitiesEvent {
+    #[serde(rename = "a", default)]
+    added: HashMap<String, CompressedState>,
+    #[serde(rename = "c", default)]
+    changed: HashMap<String, CompressedStateDiff>,
+}
+
+#[derive(Deserialize)]
+struct ResultError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum IncomingMessage {
+    AuthRequired,
+    AuthOk,
+    AuthInvalid {
+        message: String,
+    },
+    Result {
+        id: u64,
+        success: bool,
+        error: Option<ResultError>,
+    },
+    Event {
+        event: EntitiesEvent,
+    },
+    #[serde(other)]
+    Other,
+}
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Client of Home Assistant WebSocket API, it authenticates with the token of Home Assistant configuration,
+/// subscribes only the watched entities by `subscribe_entities` and feeds their states to HomeAssistantEntityStates.
+/// Current states are pushed by Home Assistant after each subscription, https Home Assistant is connected by wss.
+pub struct HomeAssistantWebSocketClient {
+    url: String,
+    token: String,
+    config: HomeAssistantWebSocketConfig,
+    entity_states: Arc<HomeAssistantEntityStates>,
+}
+
+impl HomeAssistantWebSocketClient {
+    pub fn new(
+        home_assistant_config: &HttpCallConfig,
+        config: HomeAssistantWebSocketConfig,
+        entity_states: Arc<HomeAssistantEntityStates>,
+    ) -> Self {
+        Self {
+            url: format!("{}/api/websocket", home_assistant_config.base_url.replacen("http", "ws", 1)),
+            token: home_assistant_config.token.clone(),
+            config,
+            entity_states,
+        }
+    }
+
+    /// keeps connection up while the application is running, delay of the reconnection is doubled after each failure
+    pub async fn run(self) {
+        if self.token.is_empty() {
+            tracing::warn!("Home assistant authorization token is missing, live states are not received");
+            return;
+        }
+        let min_reconnect_delay = Duration::from_millis(self.config.min_reconnect_delay_ms);
+        let max_reconnect_delay = Duration::from_millis(self.config.max_reconnect_delay_ms);
+        let mut reconnect_delay = min_reconnect_delay;
+        loop {
+            if let Err(e) = self.listen(&mut reconnect_delay).await {
+                tracing::warn!("Home assistant WebSocket connection failed: {}", e);
+            }
+            self.entity_states.set_connected(false);
+            tokio::time::sleep(reconnect_delay).await;
+            reconnect_delay = (reconnect_delay * 2).min(max_reconnect_delay);
+        }
+    }
+
+    async fn listen(&self, reconnect_delay: &mut Duration) -> Result<(), AppError> {
+        let (mut socket, _) = connect_async(&self.url)
+            .await
+            .map_err(|e| AppError::system_error(&format!("Connection to {} failed: {}", self.url, e)))?;
+        self.authenticate(&mut socket).await?;
+        let mut subscribed = HashSet::new();
+        let mut next_id = FIRST_SUBSCRIPTION_ID;
+        self.subscribe(&mut socket, &mut subscribed, &mut next_id).await?;
+        *reconnect_delay = Duration::from_millis(self.config.min_reconnect_delay_ms);
+        tracing::info!("Connected to Home Assistant WebSocket API");
+
+        loop {
+            let message = tokio::select! {
+                message = receive(&mut socket) => message?,
+                _ = self.entity_states.entities_watched.notified() => {
+                    self.subscribe(&mut socket, &mut subscribed, &mut next_id).await?;
+                    continue;
+                }
+            };
+            match message {
+                IncomingMessage::Event { event } => {
+                    for (entity_id, state) in event.added {
+                        if let Some(value) = &state.state {
+                            self.entity_states.update(&entity_id, value, state.changed_at());
+                        }
+                    }
+                    for (entity_id, diff) in event.changed {
+                        if let Some(state) = diff.additions {
+                            if let Some(value) = &state.state {
+                                self.entity_states.update(&entity_id, value, state.changed_at());
+                            }
+                        }
+                    }
+                    self.entity_states.set_connected(true);
+                }
+                IncomingMessage::Result { id, success: false, error, .. } => {
+                    return Err(AppError::system_error(&format!(
+                        "Home assistant command {} failed: {}",
+                        id,
+                        error.map(|error| error.message).unwrap_or_default()
+                    )));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// subscribes watched entities which have not been subscribed by this connection yet,
+    /// Home Assistant would push all entities when the list was empty
+    async fn subscribe(
+        &self,
+        socket: &mut WebSocket,
+        subscribed: &mut HashSet<String>,
+        next_id: &mut u64,
+    ) -> Result<(), AppError> {
+        let entity_ids: Vec<String> =
+            self.entity_states.entity_ids().into_iter().filter(|entity_id| !subscribed.contains(entity_id)).collect();
+        if entity_ids.is_empty() {
+            return Ok(());
+        }
+        send(socket, json!({"id": *next_id, "type": "subscribe_entities", "entity_ids": entity_ids})).await?;
+        *next_id += 1;
+        subscribed.extend(entity_ids);
+        Ok(())
+    }
+
+    async fn authenticate(&self, socket: &mut WebSocket) -> Result<(), AppError> {
+        if !matches!(receive(socket).await?, IncomingMessage::AuthRequired) {
+            return Err(AppError::system_error("Home assistant has not asked for authentication"));
+        }
+        send(socket, json!({"type": "auth", "access_token": self.token})).await?;
+        match receive(socket).await? {
+            IncomingMessage::AuthOk => Ok(()),
+            IncomingMessage::AuthInvalid { message } => {
+                Err(AppError::user_error(&format!("Home assistant has rejected the token: {}", message)))
+            }
+            _ => Err(AppError::system_error("Home assistant has not confirmed authentication")),
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: Value) -> Result<(), AppError> {
+    socket
+        .send(Message::Text(message.to_string().into()))
+        .await
+        .map_err(|e| AppError::system_error(&format!("Home assistant WebSocket message can not be sent: {}", e)))
+}
+
+/// returns next text message, pings are answered by the WebSocket stream
+async fn receive(socket: &mut WebSocket) -> Result<IncomingMessage, AppError> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => {
+                return serde_json::from_str(&text).map_err(|e| {
+                    AppError::system_error(&format!("Home assistant message is not parsable: {}: {}", e, text.as_str()))
+                })
+            }
+            Some(Ok(Message::Close(_))) | None => {
+                return Err(AppError::system_error("Home assistant has closed WebSocket connection"))
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(AppError::system_error(&format!("Home assistant WebSocket error: {}", e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    use crate::{
+        model::LiveDeviceState,
+        settings::{HomeAssistantWebSocketConfig, HttpCallConfig},
+    };
+
+    use super::{HomeAssistantEntityStates, HomeAssistantWebSocketClient};
+
+    fn create_entity_states() -> HomeAssistantEntityStates {
+        let entity_states = HomeAssistantEntityStates::new(["switch.boiler".to_owned()]);
+        entity_states.set_connected(true);
+        entity_states
+    }
+
+    #[test]
+    fn requested_switch_should_not_be_manual() {
+        let entity_states = create_entity_states();
+        entity_states.update("switch.boiler", "off", Utc::now());
+        entity_states.request_switch("switch.boiler", true);
+
+        let live_state = entity_states.update("switch.boiler", "on", Utc::now()).unwrap();
+        assert_eq!((live_state.switched_on, live_state.manual), (Some(true), false));
+    }
+
+    #[test]
+    fn toggle_which_has_not_been_requested_should_be_manual() {
+        let entity_states = create_entity_states();
+        entity_states.update("switch.boiler", "off", Utc::now());
+        entity_states.request_switch("switch.boiler", true);
+        entity_states.update("switch.boiler", "on", Utc::now());
+
+        assert!(entity_states.update("switch.boiler", "off", Utc::now()).unwrap().manual);
+        assert!(entity_states.update("switch.boiler", "off", Utc::now()).unwrap().manual);
+        assert!(!entity_states.update("switch.boiler", "unavailable", Utc::now()).unwrap().manual);
+    }
+
+    #[test]
+    fn switch_requested_long_before_change_should_not_explain_it() {
+        let entity_states = create_entity_states();
+        entity_states.update("switch.boiler", "off", Utc::now());
+        entity_states.request_switch("switch.boiler", true);
+
+        assert!(entity_states.update("switch.boiler", "on", Utc::now() + TimeDelta::minutes(5)).unwrap().manual);
+    }
+
+    #[test]
+    fn states_should_be_served_only_for_watched_entities_while_connected() {
+        let entity_states = create_entity_states();
+        assert!(entity_states.update("switch.heater", "on", Utc::now()).is_none());
+        entity_states.update("switch.boiler", "on", Utc::now());
+        assert!(entity_states.live_state("switch.heater").is_none());
+        assert!(entity_states.live_state("switch.boiler").is_some());
+
+        entity_states.watch("switch.heater");
+        entity_states.update("switch.heater", "on", Utc::now());
+        assert!(entity_states.live_state("switch.heater").is_some());
+
+        entity_states.set_connected(false);
+        assert!(entity_states.live_state("switch.boiler").is_none());
+    }
+
+    async fn receive_json<S>(socket: &mut S) -> Value
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let message = socket.next().await.unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn state_changes_should_be_received_from_home_assistant() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            let send = |message: Value| Message::Text(message.to_string().into());
+            socket.send(send(json!({"type": "auth_required", "ha_version": "2024.10.0"}))).await.unwrap();
+            assert_eq!(receive_json(&mut socket).await, json!({"type": "auth", "access_token": "token"}));
+            socket.send(send(json!({"type": "auth_ok", "ha_version": "2024.10.0"}))).await.unwrap();
+            assert_eq!(
+                receive_json(&mut socket).await,
+                json!({"id": 1, "type": "subscribe_entities", "entity_ids": ["switch.boiler"]})
+            );
+            socket.send(send(json!({"id": 1, "type": "result", "success": true, "result": null}))).await.unwrap();
+            socket
+                .send(send(json!({"id": 1, "type": "event", "event": {"a": {
+                    "switch.boiler": {"s": "off", "a": {}, "c": "01JAJ6Q3ZC", "lc": 1729332000.0}
+                }}})))
+                .await
+                .unwrap();
+            socket
+                .send(send(json!({"id": 1, "type": "event", "event": {"c": {
+                    "switch.boiler": {"+": {"s": "on", "c": "01JAJ6Q3ZD", "lc": 1729332300.0}}
+                }}})))
+                .await
+                .unwrap();
+
+            assert_eq!(
+                receive_json(&mut socket).await,
+                json!({"id": 2, "type": "subscribe_entities", "entity_ids": ["switch.heater"]})
+            );
+            socket
+                .send(send(json!({"id": 2, "type": "event", "event": {"a": {
+                    "switch.heater": {"s": "on", "a": {}, "c": "01JAJ6Q3ZE", "lu": 1729332400.0}
+                }}})))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let entity_states = Arc::new(HomeAssistantEntityStates::new(["switch.boiler".to_owned()]));
+        let client = HomeAssistantWebSocketClient::new(
+            &HttpCallConfig { base_url, token: "token".to_owned() },
+            HomeAssistantWebSocketConfig::default(),
+            entity_states.clone(),
+        );
+        tokio::spawn(client.run());
+
+        let live_state = wait_for_state(&entity_states, "switch.boiler", "on").await;
+        assert_eq!(live_state.switched_on, Some(true));
+        assert_eq!(live_state.changed_at, DateTime::from_timestamp(1729332300, 0).unwrap());
+        assert!(live_state.manual);
+
+        entity_states.watch("switch.heater");
+        let live_state = wait_for_state(&entity_states, "switch.heater", "on").await;
+        assert_eq!(live_state.changed_at, DateTime::from_timestamp(1729332400, 0).unwrap());
+        assert!(!live_state.manual);
+    }
+
+    async fn wait_for_state(
+        entity_states: &HomeAssistantEntityStates,
+        entity_id: &str,
+        state: &str,
+    ) -> LiveDeviceState {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match entity_states.live_state(entity_id) {
+                    Some(live_state) if live_state.state == state => return live_state,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn https_home_assistant_should_be_connected_by_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("https://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut record_type = [0u8; 1];
+            stream.read_exact(&mut record_type).await.unwrap();
+            record_type[0]
+        });
+
+        let client = HomeAssistantWebSocketClient::new(
+            &HttpCallConfig { base_url, token: "token".to_owned() },
+            HomeAssistantWebSocketConfig::default(),
+            Arc::new(HomeAssistantEntityStates::new(["switch.boiler".to_owned()])),
+        );
+        assert!(client.url.starts_with("wss://"));
+        let mut reconnect_delay = Duration::ZERO;
+        let _ = tokio::time::timeout(Duration::from_secs(2), client.listen(&mut reconnect_delay)).await;
+
+        // TLS handshake record starts the connection
+        assert_eq!(tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap(), 0x16);
+    }
+}
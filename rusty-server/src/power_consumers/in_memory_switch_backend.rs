@@ -0,0 +1,59 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+
+use crate::model::{AppError, SwitchCapabilities};
+
+use super::SwitchBackend;
+
+/// InMemorySwitchBackend keeps device states in memory instead of switching real devices,
+/// it lets the application schedule consumers which are not controlled by Home Assistant,
+/// e.g. to follow a plan by hand or to try the application without devices.
+/// Device which has never been switched is off.
+#[derive(Default)]
+pub struct InMemorySwitchBackend {
+    states: Mutex<HashMap<String, bool>>,
+}
+
+impl InMemorySwitchBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SwitchBackend for InMemorySwitchBackend {
+    async fn switch_device(&self, device_id: &str, switch_on: bool) -> Result<(), AppError> {
+        tracing::info!("Device {} is switched {} in memory", device_id, if switch_on { "on" } else { "off" });
+        self.states.lock().unwrap().insert(device_id.to_owned(), switch_on);
+        Ok(())
+    }
+
+    async fn read_state(&self, device_id: &str) -> Result<bool, AppError> {
+        Ok(self.states.lock().unwrap().get(device_id).copied().unwrap_or(false))
+    }
+
+    fn capabilities(&self, _device_id: &str) -> SwitchCapabilities {
+        SwitchCapabilities { read_state: true, read_power: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::power_consumers::SwitchBackend;
+
+    use super::InMemorySwitchBackend;
+
+    #[tokio::test]
+    async fn switched_state_should_be_read_back() {
+        let backend = InMemorySwitchBackend::new();
+        assert!(!backend.read_state("switch.boiler").await.unwrap());
+
+        backend.switch_device("switch.boiler", true).await.unwrap();
+        assert!(backend.read_state("switch.boiler").await.unwrap());
+        assert!(!backend.read_state("switch.heater").await.unwrap());
+
+        backend.switch_device("switch.boiler", false).await.unwrap();
+        assert!(!backend.read_state("switch.boiler").await.unwrap());
+    }
+}
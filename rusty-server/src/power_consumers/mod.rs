@@ -1,9 +1,38 @@
 mod power_consumer;
 pub use self::power_consumer::PowerConsumer;
+mod cheapest_window_finder;
+mod energy_meter;
+mod home_assistant_importer;
 mod home_assistant_service;
+mod home_assistant_websocket_client;
+mod in_memory_switch_backend;
+mod mqtt_discovery_publisher;
+mod mqtt_switch_backend;
 mod power_consumers_service;
+mod shelly_switch_backend;
 mod switch_actions_scheduler;
+mod switch_backend;
+mod tasmota_switch_backend;
 
+pub use self::cheapest_window_finder::CheapestWindowFinder;
+pub use self::energy_meter::measure_actual_consumption;
+pub use self::energy_meter::EnergyMeter;
+pub use self::home_assistant_importer::HomeAssistantImporter;
+pub use self::home_assistant_importer::ImportedPowerConsumer;
+pub use self::home_assistant_service::HomeAssistantDomain;
+pub use self::home_assistant_service::HomeAssistantEntity;
+pub use self::home_assistant_service::HomeAssistantSensorValue;
 pub use self::home_assistant_service::HomeAssistantService;
+pub use self::home_assistant_websocket_client::HomeAssistantEntityStates;
+pub use self::home_assistant_websocket_client::HomeAssistantWebSocketClient;
+pub use self::in_memory_switch_backend::InMemorySwitchBackend;
+pub use self::mqtt_discovery_publisher::MqttDiscoveryPublisher;
+pub use self::mqtt_switch_backend::MqttSwitchBackend;
 pub use self::power_consumers_service::PowerConsumersService;
+pub use self::shelly_switch_backend::ShellySwitchBackend;
 pub use self::switch_actions_scheduler::SwitchActionsScheduler;
+pub use self::switch_backend::SharedSwitchBackend;
+pub use self::switch_backend::SwitchBackend;
+pub use self::switch_backend::SwitchBackendTypes;
+pub use self::switch_backend::SwitchBackends;
+pub use self::tasmota_switch_backend::TasmotaSwitchBackend;
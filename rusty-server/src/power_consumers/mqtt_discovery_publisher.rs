@@ -0,0 +1,430 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, Packet, QoS};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::{
+    model::{AppError, ConsumptionPlan, ConsumptionPlanState, Money, CURRENCY, MONEY_UNIT},
+    settings::{MqttConfig, MqttDiscoveryConfig},
+    SharedState,
+};
+
+use super::{
+    mqtt_switch_backend::create_mqtt_options, mqtt_switch_backend::RECONNECT_DELAY, PowerConsumer,
+    PowerConsumersService,
+};
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+const PRESS: &str = "PRESS";
+const CANCEL_PLAN: &str = "cancel_plan";
+const PLAN_DEFAULT: &str = "plan_default";
+
+/// State of the power consumer plan published as JSON, discovered sensors pick their fields by value templates.
+/// Missing times and cost are published as null, so sensors are unknown
+#[derive(Serialize, Debug, PartialEq)]
+struct PowerConsumerState {
+    next_switch_on: Option<DateTime<Utc>>,
+    plan_state: &'static str,
+    planned_end: Option<DateTime<Utc>>,
+    estimated_cost: Option<Decimal>,
+}
+
+impl PowerConsumerState {
+    fn new(consumption_plan: Option<&ConsumptionPlan>) -> Self {
+        Self {
+            next_switch_on: consumption_plan.and_then(ConsumptionPlan::next_switch_on_at),
+            plan_state: match consumption_plan.map(|consumption_plan| &consumption_plan.state) {
+                None => "none",
+                Some(ConsumptionPlanState::Processing) => "processing",
+                Some(ConsumptionPlanState::Executed) => "executed",
+                Some(ConsumptionPlanState::Canceled) => "canceled",
+            },
+            planned_end: consumption_plan.and_then(ConsumptionPlan::planned_end),
+            estimated_cost: consumption_plan.and_then(|consumption_plan| consumption_plan.estimated_cost),
+        }
+    }
+}
+
+/// Home Assistant object ids may contain only letters, digits and underscores, e.g. `switch.boiler` is `switch_boiler`
+fn object_id(device_id: &str) -> String {
+    device_id.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+/// Topics and discovery configs of the entities, they are derived from the base topic and the discovery prefix
+struct DiscoveryTopics(MqttDiscoveryConfig);
+
+impl DiscoveryTopics {
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.0.base_topic)
+    }
+
+    fn state_topic(&self, object_id: &str) -> String {
+        format!("{}/{}/state", self.0.base_topic, object_id)
+    }
+
+    fn command_topic(&self, object_id: &str, command: &str) -> String {
+        format!("{}/{}/{}/set", self.0.base_topic, object_id, command)
+    }
+
+    fn config_topic(&self, component: &str, object_id: &str) -> String {
+        format!("{}/{}/{}/{}/config", self.0.discovery_prefix, component, self.0.base_topic, object_id)
+    }
+
+    /// returns object id and command of the button command topic
+    fn parse_command_topic<'a>(&self, topic: &'a str) -> Option<(&'a str, &'a str)> {
+        let mut parts = topic.strip_prefix(&self.0.base_topic)?.strip_prefix('/')?.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(object_id), Some(command), Some("set"), None) => Some((object_id, command)),
+            _ => None,
+        }
+    }
+
+    fn entity(&self, unique_id: String, name: &str, device: &Value, state_topic: String) -> Value {
+        json!({
+            "unique_id": unique_id,
+            "object_id": unique_id,
+            "name": name,
+            "device": device,
+            "state_topic": state_topic,
+            "availability_topic": self.availability_topic(),
+        })
+    }
+
+    /// discovery topics and configs of the power consumer entities
+    fn power_consumer_discovery(&self, device_id: &str, name: &str) -> Vec<(String, Value)> {
+        let object_id = object_id(device_id);
+        let unique_id = |key: &str| format!("{}_{}_{}", self.0.base_topic.replace('-', "_"), object_id, key);
+        let device = json!({
+            "identifiers": [format!("{}_{}", self.0.base_topic, object_id)],
+            "name": name,
+            "manufacturer": "rusty-server",
+            "model": "Power consumer",
+        });
+        let sensor = |key: &str, entity_name: &str, extra: Value| {
+            let mut entity = self.entity(unique_id(key), entity_name, &device, self.state_topic(&object_id));
+            entity["value_template"] = json!(format!("{{{{ value_json.{} }}}}", key));
+            if let (Value::Object(entity), Value::Object(extra)) = (&mut entity, extra) {
+                entity.extend(extra);
+            }
+            (self.config_topic("sensor", &unique_id(key)), entity)
+        };
+        let button = |command: &str, entity_name: &str| {
+            let mut entity = self.entity(unique_id(command), entity_name, &device, self.state_topic(&object_id));
+            if let Value::Object(entity) = &mut entity {
+                entity.remove("state_topic");
+                entity.insert("command_topic".to_owned(), json!(self.command_topic(&object_id, command)));
+                entity.insert("payload_press".to_owned(), json!(PRESS));
+            }
+            (self.config_topic("button", &unique_id(command)), entity)
+        };
+
+        vec![
+            sensor("next_switch_on", "Next switch on", json!({"device_class": "timestamp"})),
+            sensor("plan_state", "Plan state", json!({})),
+            sensor("planned_end", "Planned end", json!({"device_class": "timestamp"})),
+            sensor(
+                "estimated_cost",
+                "Estimated cost",
+                json!({"device_class": "monetary", "unit_of_measurement": CURRENCY}),
+            ),
+            button(CANCEL_PLAN, "Cancel plan"),
+            button(PLAN_DEFAULT, "Plan default"),
+        ]
+    }
+
+    fn price_topic(&self) -> String {
+        format!("{}/price/state", self.0.base_topic)
+    }
+
+    fn price_discovery(&self) -> (String, Value) {
+        let unique_id = format!("{}_current_price", self.0.base_topic.replace('-', "_"));
+        let device = json!({
+            "identifiers": [self.0.base_topic.clone()],
+            "name": "Rusty server",
+            "manufacturer": "rusty-server",
+            "model": "Price list",
+        });
+        let mut entity = self.entity(unique_id.clone(), "Current price", &device, self.price_topic());
+        entity["unit_of_measurement"] = json!(MONEY_UNIT);
+        entity["state_class"] = json!("measurement");
+        (self.config_topic("sensor", &unique_id), entity)
+    }
+}
+
+/// MqttDiscoveryPublisher announces entities of power consumer plans and the current price sensor
+/// by Home Assistant MQTT discovery, so plans are shown on Home Assistant dashboards.
+/// Each power consumer is a device with sensors of the next switch on time, plan state, planned end
+/// and estimated cost, its buttons cancel the plan or schedule the default plan.
+/// Discovery configs are retained and they are announced again after each connection,
+/// power consumers imported later are announced with their first state.
+pub struct MqttDiscoveryPublisher {
+    client: AsyncClient,
+    topics: DiscoveryTopics,
+    state: SharedState,
+    announced: Mutex<HashSet<String>>,
+}
+
+impl MqttDiscoveryPublisher {
+    /// creates MQTT client and spawns its event loop, so it has to be called inside tokio runtime
+    pub fn new(mqtt_config: &MqttConfig, config: MqttDiscoveryConfig, state: SharedState) -> Arc<Self> {
+        let topics = DiscoveryTopics(config);
+        let mut options = create_mqtt_options(mqtt_config, &format!("{}-discovery", mqtt_config.client_id));
+        options.set_last_will(LastWill::new(topics.availability_topic(), OFFLINE, QoS::AtLeastOnce, true));
+        let (client, event_loop) = AsyncClient::new(options, 100);
+        let publisher = Arc::new(Self { client, topics, state, announced: Mutex::new(HashSet::new()) });
+        tokio::spawn(run_event_loop(publisher.clone(), event_loop));
+        publisher
+    }
+
+    /// publishes states periodically, times of the plans are moved by the scheduler and price changes every hour
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.topics.0.publish_interval_secs));
+        loop {
+            interval.tick().await;
+            self.publish_states().await;
+        }
+    }
+
+    async fn publish(&self, topic: String, payload: String, retain: bool) {
+        if let Err(e) = self.client.publish(&topic, QoS::AtLeastOnce, retain, payload).await {
+            tracing::warn!("Publishing to {} failed: {}", topic, e);
+        }
+    }
+
+    async fn announce(&self, discovery: Vec<(String, Value)>) {
+        for (topic, config) in discovery {
+            self.publish(topic, config.to_string(), true).await;
+        }
+    }
+
+    /// announces discovery configs again after connection, broker could have lost retained ones
+    async fn on_connected(&self) {
+        self.announced.lock().await.clear();
+        self.publish(self.topics.availability_topic(), ONLINE.to_owned(), true).await;
+        self.announce(vec![self.topics.price_discovery()]).await;
+        let commands = format!("{}/+/+/set", self.topics.0.base_topic);
+        if let Err(e) = self.client.subscribe(&commands, QoS::AtLeastOnce).await {
+            tracing::warn!("Subscription of {} failed: {}", commands, e);
+        }
+        self.publish_states().await;
+    }
+
+    async fn current_price(&self) -> Result<Option<Money>, AppError> {
+        let single_day_price_list = self.state.read().await.single_day_price_list.clone();
+        let now = Utc::now();
+        let price_list = single_day_price_list.get_price_list(&now).await?;
+        Ok(price_list
+            .items()
+            .iter()
+            .find(|item| *item.starts_at() <= now && now < *item.starts_at() + *item.duration())
+            .map(|item| item.price()))
+    }
+
+    async fn publish_states(&self) {
+        let states: Vec<(String, String, PowerConsumerState)> = {
+            let app_state = self.state.read().await;
+            app_state
+                .power_consumers_service
+                .power_consumers()
+                .map(|power_consumer| {
+                    (
+                        power_consumer.id().to_owned(),
+                        power_consumer.name().to_owned(),
+                        PowerConsumerState::new(power_consumer.consumption_plan()),
+                    )
+                })
+                .collect()
+        };
+
+        for (device_id, name, state) in states {
+            let object_id = object_id(&device_id);
+            if self.announced.lock().await.insert(object_id.clone()) {
+                self.announce(self.topics.power_consumer_discovery(&device_id, &name)).await;
+            }
+            match serde_json::to_string(&state) {
+                Ok(payload) => self.publish(self.topics.state_topic(&object_id), payload, true).await,
+                Err(e) => tracing::warn!("State of {} is not serializable: {}", device_id, e),
+            }
+        }
+
+        match self.current_price().await {
+            Ok(Some(price)) => self.publish(self.topics.price_topic(), price.amount().to_string(), true).await,
+            Ok(None) => tracing::debug!("There is no current price to publish"),
+            Err(e) => tracing::warn!("Current price has not been published: {}", e),
+        }
+    }
+
+    /// executes button command of the power consumer and publishes its new state
+    async fn handle_command(&self, topic: &str, payload: &[u8]) {
+        let Some((object_id, command)) = self.topics.parse_command_topic(topic) else {
+            return;
+        };
+        if payload != PRESS.as_bytes() {
+            tracing::debug!("Unknown payload '{}' on topic {}", String::from_utf8_lossy(payload), topic);
+            return;
+        }
+        let device_id = self
+            .state
+            .read()
+            .await
+            .power_consumers_service
+            .power_consumers()
+            .map(PowerConsumer::id)
+            .find(|id| self::object_id(id) == object_id)
+            .map(str::to_owned);
+        let result = match (device_id, command) {
+            (Some(device_id), CANCEL_PLAN) => {
+                PowerConsumersService::cancel_consumption_plan(&self.state, &device_id).await
+            }
+            (Some(device_id), PLAN_DEFAULT) => {
+                PowerConsumersService::schedule_default_consumption_plan(&self.state, &device_id).await
+            }
+            (Some(_), command) => Err(AppError::user_error(&format!("Command {} is not supported", command))),
+            (None, _) => Err(AppError::not_found(&format!("Power consumer {} not found", object_id))),
+        };
+        if let Err(e) = result {
+            tracing::warn!("Command {} has failed: {}", topic, e);
+        }
+        self.publish_states().await;
+    }
+}
+
+/// polls MQTT connection, announcing and commands are handled by separate tasks,
+/// publishing waits for the event loop, so it can not be awaited here
+async fn run_event_loop(publisher: Arc<MqttDiscoveryPublisher>, mut event_loop: EventLoop) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker for Home Assistant discovery");
+                let publisher = publisher.clone();
+                tokio::spawn(async move { publisher.on_connected().await });
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let publisher = publisher.clone();
+                tokio::spawn(async move { publisher.handle_command(&publish.topic, &publish.payload).await });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("MQTT discovery connection error: {}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, TimeZone, Utc};
+    use rust_decimal::Decimal;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use crate::{
+        model::{
+            ConsumptionPlan, ConsumptionPlanItem, ConsumptionPlanState, Money, PriceCategory, PriceListItem,
+            SwitchAction,
+        },
+        settings::MqttDiscoveryConfig,
+    };
+
+    use super::{object_id, DiscoveryTopics, PowerConsumerState};
+
+    fn create_topics() -> DiscoveryTopics {
+        DiscoveryTopics(MqttDiscoveryConfig::default())
+    }
+
+    #[test]
+    fn object_id_should_contain_only_letters_digits_and_underscores() {
+        assert_eq!(object_id("switch.Boiler-1"), "switch_boiler_1");
+    }
+
+    #[test]
+    fn button_command_topic_should_be_parsed() {
+        let topics = create_topics();
+        assert_eq!(
+            topics.parse_command_topic("rusty-server/switch_boiler/cancel_plan/set"),
+            Some(("switch_boiler", "cancel_plan"))
+        );
+        assert_eq!(topics.parse_command_topic("rusty-server/switch_boiler/state"), None);
+        assert_eq!(topics.parse_command_topic("rusty-server/switch_boiler/cancel_plan/set/more"), None);
+        assert_eq!(topics.parse_command_topic("other/switch_boiler/cancel_plan/set"), None);
+    }
+
+    #[test]
+    fn power_consumer_entities_should_be_announced() {
+        let discovery = create_topics().power_consumer_discovery("switch.boiler", "Boiler");
+        let topics: Vec<&str> = discovery.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/rusty-server/rusty_server_switch_boiler_next_switch_on/config",
+                "homeassistant/sensor/rusty-server/rusty_server_switch_boiler_plan_state/config",
+                "homeassistant/sensor/rusty-server/rusty_server_switch_boiler_planned_end/config",
+                "homeassistant/sensor/rusty-server/rusty_server_switch_boiler_estimated_cost/config",
+                "homeassistant/button/rusty-server/rusty_server_switch_boiler_cancel_plan/config",
+                "homeassistant/button/rusty-server/rusty_server_switch_boiler_plan_default/config",
+            ]
+        );
+
+        let (_, next_switch_on) = &discovery[0];
+        assert_eq!(next_switch_on["state_topic"], json!("rusty-server/switch_boiler/state"));
+        assert_eq!(next_switch_on["value_template"], json!("{{ value_json.next_switch_on }}"));
+        assert_eq!(next_switch_on["device_class"], json!("timestamp"));
+        assert_eq!(next_switch_on["device"]["name"], json!("Boiler"));
+        assert_eq!(next_switch_on["availability_topic"], json!("rusty-server/status"));
+
+        let (_, estimated_cost) = &discovery[3];
+        assert_eq!(estimated_cost["unit_of_measurement"], json!("PLN"));
+
+        let (_, cancel_plan) = &discovery[4];
+        assert_eq!(cancel_plan["command_topic"], json!("rusty-server/switch_boiler/cancel_plan/set"));
+        assert_eq!(cancel_plan["payload_press"], json!("PRESS"));
+        assert!(cancel_plan.get("state_topic").is_none());
+    }
+
+    #[test]
+    fn state_without_plan_should_be_published_with_unknown_values() {
+        assert_eq!(
+            serde_json::to_value(PowerConsumerState::new(None)).unwrap(),
+            json!({"next_switch_on": null, "plan_state": "none", "planned_end": null, "estimated_cost": null})
+        );
+    }
+
+    #[test]
+    fn state_of_plan_should_have_next_switch_on_and_planned_end() {
+        let at = |hour| Utc.with_ymd_and_hms(2024, 8, 26, hour, 0, 0).unwrap();
+        let mut consumption_plan_item = ConsumptionPlanItem::new(
+            PriceListItem::new(at(20), TimeDelta::hours(2), Money::from_legacy_price(80000), PriceCategory::Min),
+            TimeDelta::hours(2),
+        );
+        consumption_plan_item.switch_actions_mut().push(SwitchAction::new(at(20), true));
+        consumption_plan_item.switch_actions_mut().push(SwitchAction::new(at(22), false));
+        let consumption_plan = ConsumptionPlan {
+            id: Uuid::new_v4(),
+            created_at: at(12),
+            consumption_duration: TimeDelta::hours(2),
+            finish_at: at(23),
+            consume_while_negative: false,
+            consumption_plan_items: vec![consumption_plan_item],
+            state: ConsumptionPlanState::Processing,
+            price_lists: Vec::new(),
+            estimated_cost: Some(Decimal::new(320, 2)),
+            actual_consumption: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(PowerConsumerState::new(Some(&consumption_plan))).unwrap(),
+            json!({
+                "next_switch_on": "2024-08-26T20:00:00Z",
+                "plan_state": "processing",
+                "planned_end": "2024-08-26T22:00:00Z",
+                "estimated_cost": "3.20"
+            })
+        );
+    }
+}
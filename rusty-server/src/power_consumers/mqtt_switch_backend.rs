@@ -0,0 +1,259 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde_json::Value;
+use tokio::sync::watch;
+
+use crate::{
+    model::{AppError, SwitchCapabilities},
+    settings::{MqttConfig, MqttSwitchConfig},
+};
+
+use super::SwitchBackend;
+
+/// delay of the next connection attempt after broker connection error
+pub(super) const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+impl MqttSwitchConfig {
+    /// returns device state read from the state topic payload,
+    /// None when payload does not match any of the state values
+    fn parse_state(&self, payload: &[u8]) -> Option<bool> {
+        let payload = std::str::from_utf8(payload).ok()?.trim();
+        let value = match &self.state_value_key {
+            Some(key) => match serde_json::from_str::<Value>(payload).ok()?.get(key)? {
+                Value::String(value) => value.to_owned(),
+                value => value.to_string(),
+            },
+            None => payload.to_owned(),
+        };
+        if value == self.state_on {
+            Some(true)
+        } else if value == self.state_off {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// broker connection options, every MQTT client of the application needs its own client id
+pub(super) fn create_mqtt_options(config: &MqttConfig, client_id: &str) -> MqttOptions {
+    let mut options = MqttOptions::new(client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    options
+}
+
+fn parse_qos(level: u8) -> Result<QoS, AppError> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        level => Err(AppError::user_error(&format!("MQTT QoS {} is not valid, it should be 0, 1 or 2", level))),
+    }
+}
+
+/// Device switched by MQTT with the last state reported on its state topic, None until device reports its state
+struct MqttDevice {
+    config: MqttSwitchConfig,
+    qos: QoS,
+    state: watch::Sender<Option<bool>>,
+}
+
+impl MqttDevice {
+    fn new(config: MqttSwitchConfig) -> Result<Self, AppError> {
+        Ok(Self { qos: parse_qos(config.qos)?, config, state: watch::Sender::new(None) })
+    }
+
+    fn update_state(&self, payload: &[u8]) {
+        match self.config.parse_state(payload) {
+            Some(state) => {
+                self.state.send_replace(Some(state));
+            }
+            None => tracing::debug!(
+                "Unknown state payload '{}' on topic {}",
+                String::from_utf8_lossy(payload),
+                self.config.state_topic
+            ),
+        }
+    }
+}
+
+/// waits for the state reported after the receiver has been created, earlier state does not confirm switching
+async fn confirm_state(mut receiver: watch::Receiver<Option<bool>>, switch_on: bool) -> bool {
+    while receiver.changed().await.is_ok() {
+        if *receiver.borrow_and_update() == Some(switch_on) {
+            return true;
+        }
+    }
+    false
+}
+
+/// MqttSwitchBackend switches devices directly by MQTT broker, e.g. Tasmota or Zigbee2MQTT relays,
+/// so they are controlled when Home Assistant is down.
+/// Command is published to the command topic of the device and switching succeeds only when
+/// the device reports requested state on its state topic. State topics are subscribed on every connection,
+/// the last reported (or retained) state is returned as device state.
+pub struct MqttSwitchBackend {
+    client: AsyncClient,
+    devices: Arc<HashMap<String, MqttDevice>>,
+    confirmation_timeout: Duration,
+}
+
+impl MqttSwitchBackend {
+    /// creates MQTT client and spawns its event loop, so it has to be called inside tokio runtime
+    pub fn new(config: &MqttConfig, devices: HashMap<String, MqttSwitchConfig>) -> Result<Self, AppError> {
+        let devices = Arc::new(
+            devices
+                .into_iter()
+                .map(|(device_id, config)| MqttDevice::new(config).map(|device| (device_id, device)))
+                .collect::<Result<HashMap<_, _>, _>>()?,
+        );
+
+        let options = create_mqtt_options(config, &config.client_id);
+        let (client, event_loop) = AsyncClient::new(options, devices.len().max(10));
+        tokio::spawn(run_event_loop(client.clone(), event_loop, devices.clone()));
+
+        Ok(Self { client, devices, confirmation_timeout: Duration::from_millis(config.confirmation_timeout_ms) })
+    }
+
+    fn device(&self, device_id: &str) -> Result<&MqttDevice, AppError> {
+        self.devices
+            .get(device_id)
+            .ok_or_else(|| AppError::user_error(&format!("Device {} has no MQTT topics configured", device_id)))
+    }
+}
+
+/// polls MQTT connection, it reconnects after errors and subscribes state topics after each connection
+async fn run_event_loop(client: AsyncClient, mut event_loop: EventLoop, devices: Arc<HashMap<String, MqttDevice>>) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker");
+                for device in devices.values() {
+                    if let Err(e) = client.try_subscribe(&device.config.state_topic, device.qos) {
+                        tracing::warn!("Subscription of {} failed: {}", device.config.state_topic, e);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                for device in devices.values().filter(|device| device.config.state_topic == publish.topic) {
+                    device.update_state(&publish.payload);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("MQTT connection error: {}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SwitchBackend for MqttSwitchBackend {
+    async fn switch_device(&self, device_id: &str, switch_on: bool) -> Result<(), AppError> {
+        let device = self.device(device_id)?;
+        let state = device.state.subscribe();
+        let payload = if switch_on { &device.config.payload_on } else { &device.config.payload_off };
+        self.client
+            .publish(&device.config.command_topic, device.qos, device.config.retain, payload.as_bytes())
+            .await
+            .map_err(|e| AppError::system_error(&format!("MQTT command to switch device {} failed: {}", device_id, e)))?;
+
+        match tokio::time::timeout(self.confirmation_timeout, confirm_state(state, switch_on)).await {
+            Ok(true) => Ok(()),
+            _ => Err(AppError::system_error(&format!(
+                "Device {} has not confirmed switching {} within {} ms",
+                device_id,
+                if switch_on { "on" } else { "off" },
+                self.confirmation_timeout.as_millis()
+            ))),
+        }
+    }
+
+    async fn read_state(&self, device_id: &str) -> Result<bool, AppError> {
+        let device = self.device(device_id)?;
+        let state = *device.state.borrow();
+        state.ok_or_else(|| {
+            AppError::system_error(&format!(
+                "Device {} has not reported its state on {}",
+                device_id, device.config.state_topic
+            ))
+        })
+    }
+
+    fn capabilities(&self, _device_id: &str) -> SwitchCapabilities {
+        SwitchCapabilities { read_state: true, read_power: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::settings::MqttSwitchConfig;
+
+    use super::{confirm_state, MqttDevice};
+
+    fn tasmota_config() -> MqttSwitchConfig {
+        serde_json::from_str(r#"{"command_topic": "cmnd/boiler/POWER", "state_topic": "stat/boiler/POWER"}"#).unwrap()
+    }
+
+    fn zigbee2mqtt_config() -> MqttSwitchConfig {
+        serde_json::from_str(
+            r#"{"command_topic": "zigbee2mqtt/heater/set", "state_topic": "zigbee2mqtt/heater",
+                "payload_on": "{\"state\": \"ON\"}", "payload_off": "{\"state\": \"OFF\"}", "state_value_key": "state",
+                "qos": 0, "retain": true}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn plain_state_payload_should_be_parsed() {
+        let config = tasmota_config();
+        assert_eq!((config.payload_on.as_str(), config.qos, config.retain), ("ON", 1, false));
+        assert_eq!(config.parse_state(b"ON"), Some(true));
+        assert_eq!(config.parse_state(b"OFF\n"), Some(false));
+        assert_eq!(config.parse_state(b"on"), None);
+        assert_eq!(config.parse_state(b"{\"state\": \"ON\"}"), None);
+    }
+
+    #[test]
+    fn json_state_payload_should_be_parsed() {
+        let config = zigbee2mqtt_config();
+        assert_eq!(config.parse_state(br#"{"state": "ON", "linkquality": 120}"#), Some(true));
+        assert_eq!(config.parse_state(br#"{"state": "OFF"}"#), Some(false));
+        assert_eq!(config.parse_state(br#"{"linkquality": 120}"#), None);
+        assert_eq!(config.parse_state(b"ON"), None);
+    }
+
+    #[test]
+    fn invalid_qos_should_be_rejected() {
+        let mut config = tasmota_config();
+        config.qos = 3;
+        assert!(MqttDevice::new(config).is_err());
+    }
+
+    #[tokio::test]
+    async fn switching_should_be_confirmed_by_reported_state() {
+        let device = MqttDevice::new(tasmota_config()).unwrap();
+        let state = device.state.subscribe();
+        device.update_state(b"OFF");
+        device.update_state(b"ON");
+        assert!(tokio::time::timeout(Duration::from_millis(100), confirm_state(state, true)).await.unwrap());
+        assert_eq!(*device.state.borrow(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn state_reported_before_command_should_not_confirm_switching() {
+        let device = MqttDevice::new(tasmota_config()).unwrap();
+        device.update_state(b"ON");
+        let state = device.state.subscribe();
+        device.update_state(b"unknown");
+        assert!(tokio::time::timeout(Duration::from_millis(50), confirm_state(state, true)).await.is_err());
+    }
+}
@@ -1,40 +1,80 @@
 use std::{cmp::Ordering, sync::Arc};
 
 use chrono::{DateTime, Datelike, Local, TimeDelta, TimeZone, Timelike, Utc};
+use rust_decimal::Decimal;
 use uuid::Uuid;
 
 use crate::{
     model::{
-        AppError, ConsumptionPlan, ConsumptionPlanItem, ConsumptionPlanState, PowerConsumerModel, PriceListItem,
-        SwitchAction, SwitchActionState,
+        AppError, ConsumptionPlan, ConsumptionPlanItem, ConsumptionPlanState, MeterReading, Money, PowerConsumerModel,
+        PriceListItem, SwitchAction, SwitchActionState,
     },
     price_list_providers::TimePeriodPriceListService,
 };
 
-use super::{HomeAssistantService, SwitchActionsScheduler};
+use super::{
+    measure_actual_consumption, CheapestWindowFinder, EnergyMeter, SharedSwitchBackend, SwitchActionsScheduler,
+};
 
-/// PowerConsumer is central point of the application, it represents a single Tuya switch
+/// PowerConsumer is central point of the application, it represents a single switchable device,
+/// the device is switched by the switch backend picked in the power consumer configuration.
 /// It has three main tasks
 /// 1. Prepare ConsumptionPlan
 /// 2. Cancel ConsumptionPlan
 /// 3. Execute planed switch actions
 ///
+/// Cost of the consumption plan is estimated when rated power of the device is known,
+/// actual consumption is measured when the device has energy meter.
+/// Prices are fetched, meter is read and the device is switched by the callers without holding the state lock,
+/// power consumer only changes its plan.
+///
 pub struct PowerConsumer {
-    ha_device_name: String,
+    device_id: String,
     name: String,
+    power_kw: Option<Decimal>,
     time_period_price_list_service: Arc<TimePeriodPriceListService>,
-    home_assistant_service: Arc<HomeAssistantService>,
+    switch_backend: SharedSwitchBackend,
+    energy_meter: Option<Arc<EnergyMeter>>,
     consumption_plan: Option<ConsumptionPlan>,
 }
 
 impl PowerConsumer {
     pub fn new(
-        ha_device_name: String,
+        device_id: String,
         name: String,
+        power_kw: Option<Decimal>,
         time_period_price_list_service: Arc<TimePeriodPriceListService>,
-        home_assistant_service: Arc<HomeAssistantService>,
+        switch_backend: SharedSwitchBackend,
     ) -> Self {
-        Self { ha_device_name, name, consumption_plan: None, time_period_price_list_service, home_assistant_service }
+        Self {
+            device_id,
+            name,
+            power_kw,
+            consumption_plan: None,
+            time_period_price_list_service,
+            switch_backend,
+            energy_meter: None,
+        }
+    }
+
+    pub fn switch_backend(&self) -> &SharedSwitchBackend {
+        &self.switch_backend
+    }
+
+    pub fn power_kw(&self) -> Option<Decimal> {
+        self.power_kw
+    }
+
+    pub fn time_period_price_list_service(&self) -> &Arc<TimePeriodPriceListService> {
+        &self.time_period_price_list_service
+    }
+
+    pub fn energy_meter(&self) -> Option<&Arc<EnergyMeter>> {
+        self.energy_meter.as_ref()
+    }
+
+    pub fn set_energy_meter(&mut self, energy_meter: Option<Arc<EnergyMeter>>) {
+        self.energy_meter = energy_meter;
     }
 
     pub fn consumption_plan(&self) -> Option<&ConsumptionPlan> {
@@ -45,7 +85,7 @@ impl PowerConsumer {
         self.consumption_plan.as_mut()
     }
 
-    fn get_default_charging_finish_time() -> DateTime<Utc> {
+    pub fn get_default_charging_finish_time() -> DateTime<Utc> {
         let now = Local::now();
         let default_finis_at = if now.hour() < 16 {
             now + TimeDelta::hours(2)
@@ -56,24 +96,39 @@ impl PowerConsumer {
         default_finis_at.with_timezone(&Utc)
     }
 
+    pub fn default_consumption_duration() -> TimeDelta {
+        TimeDelta::minutes(90)
+    }
+
     pub fn id(&self) -> &str {
-        &self.ha_device_name
+        &self.device_id
     }
 
-    pub fn to_power_consumer_model(&self) -> PowerConsumerModel {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn to_power_consumer_model(&self) -> PowerConsumerModel<'_> {
         PowerConsumerModel::new(
-            self.ha_device_name.clone(),
+            self.device_id.clone(),
             self.name.clone(),
             Self::get_default_charging_finish_time(),
-            TimeDelta::minutes(90),
+            Self::default_consumption_duration(),
             self.consumption_plan.as_ref(),
+            self.switch_backend.capabilities(&self.device_id),
+            self.switch_backend.live_state(&self.device_id),
         )
     }
 
+    pub fn has_plan_in_progress(&self) -> bool {
+        matches!(self.consumption_plan, Some(ConsumptionPlan { state: ConsumptionPlanState::Processing, .. }))
+    }
+
     /// if the execution of consumption plan has not been started we just cancel all switch actions
     /// if it is partially executed we execute first unexecuted action if it is switch off action
-    /// or cancel it if it is switch on the next actions are canceled
-    pub async fn cancel_consumption_plan(&mut self, now: DateTime<Utc>) -> PowerConsumerModel {
+    /// or cancel it if it is switch on the next actions are canceled,
+    /// meter is read by the caller before and it returns true when the device has to be switched off
+    pub fn cancel_consumption_plan(&mut self, now: DateTime<Utc>, meter_reading: Option<MeterReading>) -> bool {
         use SwitchActionState::*;
 
         if let Some(consumption_plan) = &mut self.consumption_plan {
@@ -93,6 +148,7 @@ impl PowerConsumer {
                                 format!("Canceled at {}", now.with_timezone(&Local).format("%H:%M:%S")).to_owned(),
                             ));
                             switch_action.set_executed_at(Some(now));
+                            switch_action.set_meter_reading(meter_reading.clone());
                             switch_action.set_state(Executed);
                         } else {
                             switch_action.set_state(Canceled);
@@ -104,10 +160,11 @@ impl PowerConsumer {
                 } else {
                     ConsumptionPlanState::Canceled
                 };
-                let _ = self.home_assistant_service.switch_device(&self.ha_device_name, false).await;
+                consumption_plan.actual_consumption = measure_actual_consumption(consumption_plan);
+                return true;
             }
         }
-        self.to_power_consumer_model()
+        false
     }
 
     ///price list items are selected for consumption plan from the list which is sorted by price, weight and time
@@ -143,23 +200,28 @@ impl PowerConsumer {
     }
 
     /// list items are sorted by price, weight and time
-    /// next we take as much price list items as it is needed to cover required charging duration
+    /// next we take as much price list items as it is needed to cover required charging duration,
+    /// when consumption while negative price is requested all items with negative price are taken entirely
+    /// even if it exceeds required duration, e.g. to heat the boiler when we are paid for consumption
     /// for each selected price list item there is created consumption plan item
     /// next we sort consumption plan items according its related pice list items
-    fn select_price_list_items_for_consumption_plan(
-        &self,
+    pub(crate) fn select_price_list_items_for_consumption_plan(
+        mut price_list: Vec<PriceListItem>,
         consumption_duration: &TimeDelta,
         start_from: &DateTime<Utc>,
         finish_at: &DateTime<Utc>,
-    ) -> Result<Vec<ConsumptionPlanItem>, AppError> {
-        let mut price_list = self.time_period_price_list_service.get_price_list(start_from, finish_at)?;
+        consume_while_negative: bool,
+    ) -> Vec<ConsumptionPlanItem> {
         Self::calculate_price_items_weights(&mut price_list, start_from, finish_at);
         price_list.sort_by(Self::compare_by_price_weight_and_start_at);
         let mut current_consumption_duration = TimeDelta::milliseconds(0);
         let mut consumption_plan: Vec<ConsumptionPlanItem> = Vec::new();
         for price_list_item in price_list {
             let price_list_item_duration = Self::apply_constraints_to_duration(&price_list_item, start_from, finish_at);
-            if current_consumption_duration + price_list_item_duration <= *consumption_duration {
+            let consumed_for_negative_price = consume_while_negative && price_list_item.price().is_negative();
+            if consumed_for_negative_price
+                || current_consumption_duration + price_list_item_duration <= *consumption_duration
+            {
                 current_consumption_duration += price_list_item_duration;
                 consumption_plan.push(ConsumptionPlanItem::new(price_list_item, price_list_item_duration));
             } else {
@@ -173,18 +235,18 @@ impl PowerConsumer {
 
         consumption_plan.sort_by(Self::compare_by_start_at);
 
-        Ok(consumption_plan)
+        consumption_plan
     }
 
-    /// The price list item weight is proportional to the number of continuous price list items with the sme price to which belongs given price list item
+    /// The price list item weight is proportional to the number of continuous price list items with the sme price to which belongs given price list item,
+    /// prices are compared exactly
     fn calculate_price_items_weights(
         price_list: &mut [PriceListItem],
         start_from: &DateTime<Utc>,
         finish_at: &DateTime<Utc>,
     ) {
         let mut weights: Vec<i64> = vec![0i64; price_list.len()];
-        let mut current_price_min = 0.0;
-        let mut current_price_max = 0.0;
+        let mut current_price: Option<Money> = None;
         let mut weight = 0i64;
         let mut weight_change_index = 0;
 
@@ -193,15 +255,13 @@ impl PowerConsumer {
         };
 
         for (i, price_list_item) in price_list.iter().enumerate() {
-            let item_price = price_list_item.price_as_float();
-            if current_price_min <= item_price && item_price <= current_price_max {
+            if current_price == Some(price_list_item.price()) {
                 weight += Self::apply_constraints_to_duration(price_list_item, start_from, finish_at).num_minutes();
             } else {
                 if i > 0 {
                     apply_weight(weight_change_index, i - 1, weight);
                 }
-                current_price_min = price_list_item.price_as_float();
-                current_price_max = current_price_min;
+                current_price = Some(price_list_item.price());
                 weight_change_index = i;
                 weight = Self::apply_constraints_to_duration(price_list_item, start_from, finish_at).num_minutes();
             }
@@ -211,7 +271,7 @@ impl PowerConsumer {
     }
 
     /// this method creates switch action for sorted collection of consumption plan items
-    fn create_switch_actions(&self, consumption_plan_items: &mut [ConsumptionPlanItem], finish_at: &DateTime<Utc>) {
+    pub(crate) fn create_switch_actions(consumption_plan_items: &mut [ConsumptionPlanItem], finish_at: &DateTime<Utc>) {
         let mut prev_consumption_plan_item: Option<&mut ConsumptionPlanItem> = None;
         let mut prev_item_is_adjacent = false;
         for consumption_item in consumption_plan_items.iter_mut() {
@@ -262,36 +322,59 @@ impl PowerConsumer {
         }
     }
 
-    fn create_consumption_plan(
-        &mut self,
+    /// prices are fetched here, so it is called without holding the state lock
+    pub async fn create_consumption_plan(
+        time_period_price_list_service: &TimePeriodPriceListService,
+        power_kw: Option<Decimal>,
         consumption_duration: &TimeDelta,
         start_from: &DateTime<Utc>,
         finish_at: &DateTime<Utc>,
-    ) -> Result<(), AppError> {
-        let mut consumption_plan_items =
-            self.select_price_list_items_for_consumption_plan(consumption_duration, start_from, finish_at)?;
-        self.create_switch_actions(&mut consumption_plan_items, finish_at);
+        consume_while_negative: bool,
+    ) -> Result<ConsumptionPlan, AppError> {
+        let price_list = time_period_price_list_service.get_time_period_price_list(start_from, finish_at).await?;
+        let mut consumption_plan_items = Self::select_price_list_items_for_consumption_plan(
+            price_list.items,
+            consumption_duration,
+            start_from,
+            finish_at,
+            consume_while_negative,
+        );
+        Self::create_switch_actions(&mut consumption_plan_items, finish_at);
+        let estimated_cost = power_kw.map(|power_kw| {
+            let relative_cost: Decimal = consumption_plan_items
+                .iter()
+                .map(|item| CheapestWindowFinder::relative_cost(item.price_list_item().price(), item.duration()))
+                .sum();
+            (relative_cost * power_kw).round_dp(2)
+        });
 
-        self.consumption_plan = Some(ConsumptionPlan {
+        Ok(ConsumptionPlan {
             id: Uuid::new_v4(),
             created_at: *start_from,
             consumption_duration: *consumption_duration,
             finish_at: *finish_at,
+            consume_while_negative,
             consumption_plan_items,
             state: ConsumptionPlanState::Processing,
-        });
+            price_lists: price_list.price_lists,
+            estimated_cost,
+            actual_consumption: None,
+        })
+    }
 
+    fn validate_no_plan_in_progress(&self) -> Result<(), AppError> {
+        if self.has_plan_in_progress() {
+            return Err(AppError::user_error("Current plan needs to be canceled!"));
+        }
         Ok(())
     }
 
-    fn validate_schedule_consumption_plan_inputs(
+    pub fn validate_schedule_consumption_plan_inputs(
         &self,
         consumption_duration: &TimeDelta,
         finish_at: &DateTime<Utc>,
     ) -> Result<(), AppError> {
-        if let Some(ConsumptionPlan { state: ConsumptionPlanState::Processing, .. }) = self.consumption_plan {
-            return Err(AppError::user_error("Current plan needs to be canceled!"));
-        }
+        self.validate_no_plan_in_progress()?;
 
         if consumption_duration.num_milliseconds() <= 0 {
             return Err(AppError::user_error("Consumption duration should be grater than zero!"));
@@ -320,20 +403,41 @@ impl PowerConsumer {
         Ok(())
     }
 
-    pub async fn schedule_consumption_plan(
+    /// replaces the plan which waits for the prices with the plan computed again from them, it is kept
+    /// when it has been started or replaced meanwhile, returns ids of switch actions which are due now.
+    /// Tasks of the replaced switch actions do not find them in the new plan
+    pub fn replace_waiting_consumption_plan(
         &mut self,
-        switch_actions_scheduler: Arc<SwitchActionsScheduler>,
-        consumption_duration: TimeDelta,
-        start_from: &DateTime<Utc>,
-        finish_at: &DateTime<Utc>,
-    ) -> Result<PowerConsumerModel, AppError> {
-        self.validate_schedule_consumption_plan_inputs(&consumption_duration, finish_at)?;
-        self.create_consumption_plan(&consumption_duration, start_from, finish_at)?;
-        if let Some(consumption_plan) = &mut self.consumption_plan {
-            switch_actions_scheduler.schedule_switch_actions(&self.ha_device_name, consumption_plan, start_from).await;
+        switch_actions_scheduler: &SwitchActionsScheduler,
+        waiting_consumption_plan_id: Uuid,
+        mut consumption_plan: ConsumptionPlan,
+        now: &DateTime<Utc>,
+    ) -> Result<Vec<String>, AppError> {
+        let waiting = self.consumption_plan.as_ref().is_some_and(|waiting_consumption_plan| {
+            waiting_consumption_plan.id == waiting_consumption_plan_id && waiting_consumption_plan.waits_for_start()
+        });
+        if !waiting {
+            return Err(AppError::user_error("Plan has been started or replaced meanwhile"));
         }
+        let due_switch_actions =
+            switch_actions_scheduler.schedule_switch_actions(&self.device_id, &mut consumption_plan, now);
+        self.consumption_plan = Some(consumption_plan);
+        Ok(due_switch_actions)
+    }
 
-        Ok(self.to_power_consumer_model())
+    /// plan is created without holding the state lock, so another plan could have been scheduled meanwhile,
+    /// returns ids of switch actions which are due now, they are executed by the caller
+    pub fn set_consumption_plan(
+        &mut self,
+        switch_actions_scheduler: &SwitchActionsScheduler,
+        mut consumption_plan: ConsumptionPlan,
+        now: &DateTime<Utc>,
+    ) -> Result<Vec<String>, AppError> {
+        self.validate_no_plan_in_progress()?;
+        let due_switch_actions =
+            switch_actions_scheduler.schedule_switch_actions(&self.device_id, &mut consumption_plan, now);
+        self.consumption_plan = Some(consumption_plan);
+        Ok(due_switch_actions)
     }
 }
 
@@ -342,25 +446,71 @@ mod tests {
     use std::sync::Arc;
 
     use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+    use rust_decimal::Decimal;
 
     use crate::{
-        model::{ConsumptionPlanItem, ConsumptionPlanState, SwitchAction, SwitchActionState},
-        power_consumers::HomeAssistantService,
-        price_list_providers::{TariffSelector, TariffTypes, TimePeriodPriceListService},
-        settings::HttpCallConfig,
+        model::{
+            AppError, ConsumptionPlanItem, ConsumptionPlanState, Money, PriceCategory, SwitchAction, SwitchActionState,
+        },
+        power_consumers::InMemorySwitchBackend,
+        price_list_providers::{TimeOfUsePriceListProvider, TimePeriodPriceListService},
+        settings::{TariffBandConfig, TimeOfUseTariffConfig},
     };
 
     use super::PowerConsumer;
 
-    fn create_power_consumer() -> PowerConsumer {
+    fn create_power_consumer_with_price_list(price_list_provider: TimeOfUsePriceListProvider) -> PowerConsumer {
         PowerConsumer::new(
             "test.device".to_owned(),
             "Smart switch".to_owned(),
-            Arc::new(TimePeriodPriceListService::new(Arc::new(TariffSelector::new(TariffTypes::W12)))),
-            Arc::new(HomeAssistantService::new(&HttpCallConfig { base_url: "".to_owned(), token: "".to_owned() })),
+            None,
+            Arc::new(TimePeriodPriceListService::new(Arc::new(price_list_provider))),
+            Arc::new(InMemorySwitchBackend::new()),
         )
     }
 
+    fn create_power_consumer() -> PowerConsumer {
+        create_power_consumer_with_price_list(TimeOfUsePriceListProvider::w12())
+    }
+
+    async fn create_consumption_plan(
+        power_consumer: &mut PowerConsumer,
+        consumption_duration: &TimeDelta,
+        start_from: &DateTime<Utc>,
+        finish_at: &DateTime<Utc>,
+        consume_while_negative: bool,
+    ) -> Result<(), AppError> {
+        let consumption_plan = PowerConsumer::create_consumption_plan(
+            power_consumer.time_period_price_list_service(),
+            power_consumer.power_kw(),
+            consumption_duration,
+            start_from,
+            finish_at,
+            consume_while_negative,
+        )
+        .await?;
+        power_consumer.consumption_plan = Some(consumption_plan);
+        Ok(())
+    }
+
+    /// sunny day where prices are negative between 11 am and 2 pm
+    fn create_power_consumer_for_sunny_day() -> PowerConsumer {
+        let band = |from_hour, to_hour, price| TariffBandConfig {
+            from_hour,
+            to_hour,
+            price: Money::from_legacy_price(price),
+            category: PriceCategory::Medium,
+            weekdays: Vec::new(),
+            months: Vec::new(),
+            valid_from: None,
+            valid_to: None,
+        };
+        create_power_consumer_with_price_list(TimeOfUsePriceListProvider::new(TimeOfUseTariffConfig {
+            name: "sunny day".to_owned(),
+            bands: vec![band(11, 12, -3), band(12, 14, -1500), band(10, 11, 2), band(0, 24, 45000)],
+        }))
+    }
+
     fn date_time(year: i32, month: u32, day: u32, hour: u32, min: u32) -> DateTime<Utc> {
         Local.with_ymd_and_hms(year, month, day, hour, min, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
     }
@@ -373,13 +523,15 @@ mod tests {
         consumption_plan_items.iter().flat_map(|cp| cp.switch_actions().iter()).collect()
     }
 
-    #[test]
-    fn consumption_plan_items_two_hours_in_the_night_in_w12() {
+    #[tokio::test]
+    async fn consumption_plan_items_two_hours_in_the_night_in_w12() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 19, 30);
         let end_time = date(2024, 8, 27);
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(90), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(90), &start_time, &end_time, false)
+            .await
+            .unwrap();
         let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
 
         println!("{}", serde_json::to_string(&consumption_plan_items).unwrap());
@@ -395,13 +547,15 @@ mod tests {
         assert_eq!(switch_actions[1].at(), &date_time(2024, 8, 26, 23, 30));
     }
 
-    #[test]
-    fn consumption_plan_items_one_hour_in_the_night_in_w12() {
+    #[tokio::test]
+    async fn consumption_plan_items_one_hour_in_the_night_in_w12() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 19, 30);
         let end_time = date_time(2024, 8, 26, 23, 0);
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(60), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(60), &start_time, &end_time, false)
+            .await
+            .unwrap();
         let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
         println!("{}", serde_json::to_string(&consumption_plan_items).unwrap());
         assert_eq!(consumption_plan_items.len(), 1);
@@ -417,13 +571,15 @@ mod tests {
         assert_eq!(switch_actions[1].at(), &date_time(2024, 8, 26, 23, 0));
     }
 
-    #[test]
-    fn consumption_plan_items_do_not_start_in_the_past() {
+    #[tokio::test]
+    async fn consumption_plan_items_do_not_start_in_the_past() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 23, 20);
         let end_time = date_time(2024, 8, 26, 23, 30);
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(5), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(5), &start_time, &end_time, false)
+            .await
+            .unwrap();
         let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
         println!("{}", serde_json::to_string(&consumption_plan_items).unwrap());
         assert_eq!(consumption_plan_items.len(), 1);
@@ -438,13 +594,15 @@ mod tests {
         assert_eq!(switch_actions[1].at(), &date_time(2024, 8, 26, 23, 30));
     }
 
-    #[test]
-    fn consumption_plan_items_two_hours_one_in_the_noon_and_one_in_the_night_in_w12() {
+    #[tokio::test]
+    async fn consumption_plan_items_two_hours_one_in_the_noon_and_one_in_the_night_in_w12() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 14, 0);
         let end_time = date_time(2024, 8, 26, 23, 0);
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(120), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(120), &start_time, &end_time, false)
+            .await
+            .unwrap();
         let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
         println!("{}", serde_json::to_string(&consumption_plan_items).unwrap());
         assert_eq!(consumption_plan_items.len(), 2);
@@ -465,13 +623,15 @@ mod tests {
         assert_eq!(switch_actions[3].at(), &date_time(2024, 8, 26, 23, 0));
     }
 
-    #[test]
-    fn consumption_plan_items_more_than_two_hours_one_in_noon_and_one_in_the_night_in_w12() {
+    #[tokio::test]
+    async fn consumption_plan_items_more_than_two_hours_one_in_noon_and_one_in_the_night_in_w12() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 14, 0);
         let end_time = date_time(2024, 8, 26, 23, 0);
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(130), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(130), &start_time, &end_time, false)
+            .await
+            .unwrap();
         let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
         println!("{}", serde_json::to_string(&consumption_plan_items).unwrap());
         assert_eq!(consumption_plan_items.len(), 3);
@@ -492,13 +652,15 @@ mod tests {
         assert_eq!(switch_actions[3].at(), &date_time(2024, 8, 26, 23, 0));
     }
 
-    #[test]
-    fn consumption_plan_items_more_than_two_hours_two_in_the_night_in_w12_ten_in_the_noon() {
+    #[tokio::test]
+    async fn consumption_plan_items_more_than_two_hours_two_in_the_night_in_w12_ten_in_the_noon() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 14, 0);
         let end_time = date_time(2024, 8, 27, 0, 0);
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(130), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(130), &start_time, &end_time, false)
+            .await
+            .unwrap();
         let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
         println!("{}", serde_json::to_string(&consumption_plan_items).unwrap());
         assert_eq!(consumption_plan_items.len(), 3);
@@ -519,14 +681,16 @@ mod tests {
         assert_eq!(switch_actions[3].at(), &date_time(2024, 8, 27, 0, 0));
     }
 
-    #[test]
-    fn consumption_plan_items_more_than_two_hours_both_in_the_night_in_w12() {
+    #[tokio::test]
+    async fn consumption_plan_items_more_than_two_hours_both_in_the_night_in_w12() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 14, 0);
         let end_time = date_time(2024, 8, 27, 0, 0);
 
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(120), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(120), &start_time, &end_time, false)
+            .await
+            .unwrap();
         let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
         println!("{}", serde_json::to_string(&consumption_plan_items).unwrap());
         assert_eq!(consumption_plan_items.len(), 2);
@@ -542,15 +706,54 @@ mod tests {
         assert_eq!(switch_actions[1].at(), &date_time(2024, 8, 27, 0, 0));
     }
 
+    #[tokio::test]
+    async fn consumption_plan_cost_should_be_estimated_from_rated_power() {
+        let mut power_consumer = PowerConsumer::new(
+            "test.device".to_owned(),
+            "Smart switch".to_owned(),
+            Some(Decimal::new(25, 1)),
+            Arc::new(TimePeriodPriceListService::new(Arc::new(TimeOfUsePriceListProvider::w12()))),
+            Arc::new(InMemorySwitchBackend::new()),
+        );
+
+        let start_time = date_time(2024, 8, 26, 14, 0);
+        let end_time = date_time(2024, 8, 27, 0, 0);
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(120), &start_time, &end_time, false)
+            .await
+            .unwrap();
+        let consumption_plan = power_consumer.consumption_plan().unwrap();
+
+        assert_eq!(consumption_plan.estimated_cost, Some(Decimal::new(4, 0)));
+        assert_eq!(consumption_plan.next_switch_on_at(), Some(date_time(2024, 8, 26, 22, 0)));
+        assert_eq!(consumption_plan.planned_end(), Some(date_time(2024, 8, 27, 0, 0)));
+    }
+
+    #[tokio::test]
+    async fn cancel_consumption_plan_should_ask_to_switch_device_off_once() {
+        let mut power_consumer = create_power_consumer();
+
+        let start_time = date_time(2024, 8, 26, 14, 0);
+        let end_time = date_time(2024, 8, 27, 0, 0);
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(120), &start_time, &end_time, false)
+            .await
+            .unwrap();
+
+        assert!(power_consumer.cancel_consumption_plan(date_time(2024, 8, 26, 12, 0), None));
+        assert!(!power_consumer.has_plan_in_progress());
+        assert!(!power_consumer.cancel_consumption_plan(date_time(2024, 8, 26, 12, 0), None));
+    }
+
     #[tokio::test]
     async fn cancel_consumption_plan_which_is_waiting_for_execution() {
         let mut power_consumer = create_power_consumer();
 
         let start_time = date_time(2024, 8, 26, 14, 0);
         let end_time = date_time(2024, 8, 27, 0, 0);
-        power_consumer.create_consumption_plan(&TimeDelta::minutes(120), &start_time, &end_time).unwrap();
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(120), &start_time, &end_time, false)
+            .await
+            .unwrap();
 
-        power_consumer.cancel_consumption_plan(date_time(2024, 8, 26, 12, 0)).await;
+        power_consumer.cancel_consumption_plan(date_time(2024, 8, 26, 12, 0), None);
         let consumption_plan = power_consumer.consumption_plan().unwrap();
         assert_eq!(consumption_plan.state, ConsumptionPlanState::Canceled);
 
@@ -563,4 +766,55 @@ mod tests {
         assert_eq!(*switch_actions[0].state(), SwitchActionState::Canceled);
         assert_eq!(switch_actions[1].at(), &date_time(2024, 8, 27, 0, 0));
     }
+
+    #[tokio::test]
+    async fn consumption_plan_should_select_the_cheapest_negative_price() {
+        let mut power_consumer = create_power_consumer_for_sunny_day();
+
+        let start_time = date_time(2024, 8, 26, 8, 0);
+        let end_time = date_time(2024, 8, 26, 18, 0);
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(60), &start_time, &end_time, false)
+            .await
+            .unwrap();
+        let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
+        assert_eq!(consumption_plan_items.len(), 1);
+        assert_eq!(consumption_plan_items[0].price_list_item().price(), Money::from_legacy_price(-1500));
+        assert_eq!(consumption_plan_items[0].price_list_item().starts_at(), &date_time(2024, 8, 26, 12, 0));
+    }
+
+    #[tokio::test]
+    async fn consumption_plan_should_consume_while_price_is_negative() {
+        let mut power_consumer = create_power_consumer_for_sunny_day();
+
+        let start_time = date_time(2024, 8, 26, 8, 0);
+        let end_time = date_time(2024, 8, 26, 18, 0);
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(60), &start_time, &end_time, true)
+            .await
+            .unwrap();
+        let consumption_plan = power_consumer.consumption_plan().unwrap();
+        assert!(consumption_plan.consume_while_negative);
+        assert_eq!(consumption_plan.consumption_plan_items.len(), 3);
+
+        let switch_actions = collect_switch_actions(&consumption_plan.consumption_plan_items);
+        assert_eq!(switch_actions.len(), 2);
+        assert!(switch_actions[0].switch_on());
+        assert_eq!(switch_actions[0].at(), &date_time(2024, 8, 26, 11, 0));
+        assert!(!switch_actions[1].switch_on());
+        assert_eq!(switch_actions[1].at(), &date_time(2024, 8, 26, 14, 0));
+    }
+
+    #[tokio::test]
+    async fn consumption_while_negative_should_cover_requested_duration() {
+        let mut power_consumer = create_power_consumer_for_sunny_day();
+
+        let start_time = date_time(2024, 8, 26, 8, 0);
+        let end_time = date_time(2024, 8, 26, 18, 0);
+        create_consumption_plan(&mut power_consumer, &TimeDelta::minutes(240), &start_time, &end_time, true)
+            .await
+            .unwrap();
+        let consumption_plan_items = &power_consumer.consumption_plan().unwrap().consumption_plan_items;
+        let prices: Vec<i64> =
+            consumption_plan_items.iter().map(|item| item.price_list_item().price().legacy_price()).collect();
+        assert_eq!(prices, vec![2, -3, -1500, -1500]);
+    }
 }
@@ -1,48 +1,63 @@
 use std::{collections::HashMap, sync::Arc};
 
 use crate::{
-    model::{AppError, PowerConsumerModel},
-    price_list_providers::{TariffSelector, TimePeriodPriceListService},
+    model::{AppError, PowerConsumerModel, PowerConsumerProposal, PowerReading},
+    price_list_providers::{SharedSingleDayPriceList, TimePeriodPriceListService},
     settings::PowerConsumerConfig,
+    SharedState,
 };
 use chrono::{DateTime, TimeDelta, Utc};
+use tokio::sync::watch;
 
-use super::{power_consumer::PowerConsumer, HomeAssistantService, SwitchActionsScheduler};
+use super::{
+    power_consumer::PowerConsumer, EnergyMeter, HomeAssistantImporter, ImportedPowerConsumer, SwitchActionsScheduler,
+    SwitchBackends,
+};
 
 /// PowerConsumersService has a map of PowerConsumers
-/// Each PowerConsumer represents single Tuya switch.
+/// Each PowerConsumer represents single switchable device with the switch backend picked in its configuration.
 /// PowerConsumersService handles request to schedule consumption plan or to cancel it
 /// by selecting required power consumer and delegating request to it. Scheduling is done by PowerConsumer  
+/// Power consumers imported from Home Assistant are registered next to the configured ones.
+/// Requests which fetch prices or call devices take the shared state, they lock it only to read
+/// or to change the power consumers, never across the calls.
 ///
 pub struct PowerConsumersService {
     switch_actions_scheduler: Option<Arc<SwitchActionsScheduler>>,
+    home_assistant_importer: Option<Arc<HomeAssistantImporter>>,
+    time_period_price_list_service: Arc<TimePeriodPriceListService>,
     power_consumers: HashMap<String, PowerConsumer>,
 }
 
 impl PowerConsumersService {
     pub fn new(
         power_consumers_config: &[PowerConsumerConfig],
-        tariff_selector_price_list: Arc<TariffSelector>,
-        home_assistant_service: Arc<HomeAssistantService>,
-    ) -> Self {
-        let time_period_price_list_service = Arc::new(TimePeriodPriceListService::new(tariff_selector_price_list));
-        Self {
+        single_day_price_list: SharedSingleDayPriceList,
+        switch_backends: &SwitchBackends,
+    ) -> Result<Self, AppError> {
+        let time_period_price_list_service = Arc::new(TimePeriodPriceListService::new(single_day_price_list));
+        Ok(Self {
             switch_actions_scheduler: None,
+            home_assistant_importer: None,
             power_consumers: power_consumers_config
                 .iter()
                 .map(|config| {
-                    (
+                    let switch_backend = switch_backends.get(&config.backend)?;
+                    let mut power_consumer = PowerConsumer::new(
                         config.device_id.to_owned(),
-                        PowerConsumer::new(
-                            config.device_id.to_owned(),
-                            config.name.to_owned(),
-                            time_period_price_list_service.clone(),
-                            home_assistant_service.clone(),
-                        ),
-                    )
+                        config.name.to_owned(),
+                        config.power_kw,
+                        time_period_price_list_service.clone(),
+                        switch_backend.clone(),
+                    );
+                    power_consumer.set_energy_meter(config.meter.clone().map(|meter| {
+                        Arc::new(EnergyMeter::new(meter, switch_backends.home_assistant(), switch_backend))
+                    }));
+                    Ok((config.device_id.to_owned(), power_consumer))
                 })
-                .collect(),
-        }
+                .collect::<Result<_, AppError>>()?,
+            time_period_price_list_service,
+        })
     }
 
     pub fn switch_actions_scheduler(&self) -> Option<&Arc<SwitchActionsScheduler>> {
@@ -53,31 +68,284 @@ impl PowerConsumersService {
         self.switch_actions_scheduler = switch_actions_scheduler;
     }
 
+    pub fn set_home_assistant_importer(&mut self, home_assistant_importer: Option<Arc<HomeAssistantImporter>>) {
+        self.home_assistant_importer = home_assistant_importer;
+    }
+
+    fn home_assistant_importer(&self) -> Result<Arc<HomeAssistantImporter>, AppError> {
+        self.home_assistant_importer
+            .clone()
+            .ok_or(AppError::user_error("Import of power consumers from Home Assistant is not configured"))
+    }
+
+    /// returns false when the power consumer with the same id exists, e.g. it has been configured after import
+    fn register_imported_power_consumer(
+        &mut self,
+        importer: &HomeAssistantImporter,
+        imported: &ImportedPowerConsumer,
+    ) -> bool {
+        if self.power_consumers.contains_key(&imported.device_id) {
+            return false;
+        }
+        importer.watch(&imported.device_id);
+        self.power_consumers.insert(
+            imported.device_id.to_owned(),
+            PowerConsumer::new(
+                imported.device_id.to_owned(),
+                imported.name.to_owned(),
+                None,
+                self.time_period_price_list_service.clone(),
+                importer.switch_backend(),
+            ),
+        );
+        true
+    }
+
+    /// registers power consumers imported before restart
+    pub async fn load_imported_power_consumers(&mut self) -> Result<(), AppError> {
+        let importer = self.home_assistant_importer()?;
+        for imported in importer.load().await? {
+            self.register_imported_power_consumer(&importer, &imported);
+        }
+        Ok(())
+    }
+
+    /// returns Home Assistant entities matching import configuration, registered ones are marked,
+    /// Home Assistant is called without holding the state lock
+    pub async fn propose_power_consumers(state: &SharedState) -> Result<Vec<PowerConsumerProposal>, AppError> {
+        let importer = state.read().await.power_consumers_service.home_assistant_importer()?;
+        let entities = importer.find_entities().await?;
+        let power_consumers_service = &state.read().await.power_consumers_service;
+        Ok(entities
+            .into_iter()
+            .map(|entity| PowerConsumerProposal {
+                registered: power_consumers_service.power_consumers.contains_key(&entity.device_id),
+                device_id: entity.device_id,
+                name: entity.name,
+            })
+            .collect())
+    }
+
+    /// registers proposed entities which are not power consumers yet, returns the newly registered ones.
+    /// They are stored with power consumers imported before and registered only when they are stored,
+    /// Home Assistant and the file are called without holding the state lock
+    pub async fn import_power_consumers(state: &SharedState) -> Result<Vec<PowerConsumerProposal>, AppError> {
+        let importer = state.read().await.power_consumers_service.home_assistant_importer()?;
+        let mut imported = importer.load().await?;
+        let entities = importer.find_entities().await?;
+        let candidates: Vec<ImportedPowerConsumer> = {
+            let power_consumers_service = &state.read().await.power_consumers_service;
+            entities
+                .into_iter()
+                .filter(|entity| !power_consumers_service.power_consumers.contains_key(&entity.device_id))
+                .collect()
+        };
+        imported.extend(candidates.iter().cloned());
+        importer.store(&imported).await?;
+
+        let power_consumers_service = &mut state.write().await.power_consumers_service;
+        let mut registered = Vec::new();
+        for candidate in candidates {
+            if power_consumers_service.register_imported_power_consumer(&importer, &candidate) {
+                registered.push(candidate);
+            }
+        }
+        Ok(registered
+            .into_iter()
+            .map(|entity| PowerConsumerProposal { device_id: entity.device_id, name: entity.name, registered: true })
+            .collect())
+    }
+
+    pub fn get_power_consumer(&self, power_consumer_id: &str) -> Result<&PowerConsumer, AppError> {
+        self.power_consumers.get(power_consumer_id).ok_or(AppError::not_found("Power consumer not found"))
+    }
+
     pub fn get_power_consumer_mut(&mut self, power_consumer_id: &str) -> Option<&mut PowerConsumer> {
         self.power_consumers.get_mut(power_consumer_id)
     }
 
-    pub fn get_power_consumers_model_list(&self) -> Vec<PowerConsumerModel> {
+    pub fn power_consumers(&self) -> impl Iterator<Item = &PowerConsumer> {
+        self.power_consumers.values()
+    }
+
+    pub fn get_power_consumers_model_list(&self) -> Vec<PowerConsumerModel<'_>> {
         self.power_consumers.values().map(|v| v.to_power_consumer_model()).collect()
     }
 
+    /// prices are fetched and due switch actions are executed without holding the state lock,
+    /// the plan is rejected when another one has been scheduled meanwhile
     pub async fn schedule_consumption_plan(
-        &mut self,
-        power_consumer_id: String,
+        state: &SharedState,
+        power_consumer_id: &str,
         consumption_duration: TimeDelta,
         finish_at: &DateTime<Utc>,
-    ) -> Result<PowerConsumerModel, AppError> {
-        let switch_actions_scheduler = self.switch_actions_scheduler.as_ref().unwrap().clone();
-        let power_consumer =
-            self.power_consumers.get_mut(&power_consumer_id).ok_or(AppError::not_found("Power consumer not found"))?;
-        power_consumer
-            .schedule_consumption_plan(switch_actions_scheduler, consumption_duration, &Utc::now(), finish_at)
+        consume_while_negative: bool,
+    ) -> Result<(), AppError> {
+        let (time_period_price_list_service, power_kw) = {
+            let app_state = state.read().await;
+            let power_consumer = app_state.power_consumers_service.get_power_consumer(power_consumer_id)?;
+            power_consumer.validate_schedule_consumption_plan_inputs(&consumption_duration, finish_at)?;
+            (power_consumer.time_period_price_list_service().clone(), power_consumer.power_kw())
+        };
+        let now = Utc::now();
+        let consumption_plan = PowerConsumer::create_consumption_plan(
+            &time_period_price_list_service,
+            power_kw,
+            &consumption_duration,
+            &now,
+            finish_at,
+            consume_while_negative,
+        )
+        .await?;
+        let due_switch_actions = {
+            let power_consumers_service = &mut state.write().await.power_consumers_service;
+            let switch_actions_scheduler = power_consumers_service.switch_actions_scheduler.clone().unwrap();
+            power_consumers_service
+                .get_power_consumer_mut(power_consumer_id)
+                .ok_or(AppError::not_found("Power consumer not found"))?
+                .set_consumption_plan(&switch_actions_scheduler, consumption_plan, &now)?
+        };
+        for switch_action_id in due_switch_actions {
+            SwitchActionsScheduler::execute_scheduled_switch_action(state, power_consumer_id, &switch_action_id).await;
+        }
+        Ok(())
+    }
+
+    /// computes again plans which wait for the prices of the fetched day, prices are fetched
+    /// and due switch actions are executed without holding the state lock
+    pub async fn replan_waiting_consumption_plans(state: &SharedState, day: &DateTime<Utc>) {
+        let waiting_consumption_plans: Vec<_> = state
+            .read()
+            .await
+            .power_consumers_service
+            .power_consumers()
+            .filter_map(|power_consumer| {
+                let consumption_plan = power_consumer.consumption_plan()?;
+                consumption_plan.waits_for_price_list(day).then(|| {
+                    (
+                        power_consumer.id().to_owned(),
+                        power_consumer.time_period_price_list_service().clone(),
+                        power_consumer.power_kw(),
+                        consumption_plan.clone(),
+                    )
+                })
+            })
+            .collect();
+
+        for (power_consumer_id, time_period_price_list_service, power_kw, waiting_consumption_plan) in
+            waiting_consumption_plans
+        {
+            let now = Utc::now();
+            let result = match PowerConsumer::create_consumption_plan(
+                &time_period_price_list_service,
+                power_kw,
+                &waiting_consumption_plan.consumption_duration,
+                &now,
+                &waiting_consumption_plan.finish_at,
+                waiting_consumption_plan.consume_while_negative,
+            )
             .await
+            {
+                Ok(consumption_plan) => {
+                    let power_consumers_service = &mut state.write().await.power_consumers_service;
+                    let switch_actions_scheduler = power_consumers_service.switch_actions_scheduler.clone().unwrap();
+                    power_consumers_service
+                        .get_power_consumer_mut(&power_consumer_id)
+                        .ok_or(AppError::not_found("Power consumer not found"))
+                        .and_then(|power_consumer| {
+                            power_consumer.replace_waiting_consumption_plan(
+                                &switch_actions_scheduler,
+                                waiting_consumption_plan.id,
+                                consumption_plan,
+                                &now,
+                            )
+                        })
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(due_switch_actions) => {
+                    tracing::info!("Plan of {} has been computed again from fetched prices", power_consumer_id);
+                    for switch_action_id in due_switch_actions {
+                        SwitchActionsScheduler::execute_scheduled_switch_action(
+                            state,
+                            &power_consumer_id,
+                            &switch_action_id,
+                        )
+                        .await;
+                    }
+                }
+                Err(e) => tracing::warn!("Plan of {} has not been computed again: {}", power_consumer_id, e),
+            }
+        }
+    }
+
+    /// background job which re-plans waiting plans whenever the prefetcher fetches prices of the next day,
+    /// it should be spawned on the tokio runtime
+    pub async fn replan_on_fetched_price_lists(
+        state: SharedState,
+        mut fetched_day: watch::Receiver<Option<DateTime<Utc>>>,
+    ) {
+        while fetched_day.changed().await.is_ok() {
+            let day = *fetched_day.borrow_and_update();
+            if let Some(day) = day {
+                Self::replan_waiting_consumption_plans(&state, &day).await;
+            }
+        }
+    }
+
+    /// schedules the plan with default duration and finish time of the power consumer,
+    /// e.g. when it is requested from Home Assistant without any parameters
+    pub async fn schedule_default_consumption_plan(
+        state: &SharedState,
+        power_consumer_id: &str,
+    ) -> Result<(), AppError> {
+        Self::schedule_consumption_plan(
+            state,
+            power_consumer_id,
+            PowerConsumer::default_consumption_duration(),
+            &PowerConsumer::get_default_charging_finish_time(),
+            false,
+        )
+        .await
+    }
+
+    /// meter is read before and the device is switched off after the plan is canceled under the state lock
+    pub async fn cancel_consumption_plan(state: &SharedState, power_consumer_id: &str) -> Result<(), AppError> {
+        let (switch_backend, energy_meter) = {
+            let app_state = state.read().await;
+            let power_consumer = app_state.power_consumers_service.get_power_consumer(power_consumer_id)?;
+            let energy_meter = power_consumer.energy_meter().filter(|_| power_consumer.has_plan_in_progress());
+            (power_consumer.switch_backend().clone(), energy_meter.cloned())
+        };
+        let meter_reading = match energy_meter {
+            Some(energy_meter) => energy_meter
+                .read(power_consumer_id)
+                .await
+                .inspect_err(|e| tracing::warn!("Meter of {} has not been read: {}", power_consumer_id, e))
+                .ok(),
+            None => None,
+        };
+        let switch_off = state
+            .write()
+            .await
+            .power_consumers_service
+            .get_power_consumer_mut(power_consumer_id)
+            .ok_or(AppError::not_found("Power consumer not found"))?
+            .cancel_consumption_plan(Utc::now(), meter_reading);
+        if switch_off {
+            let _ = switch_backend.switch_device(power_consumer_id, false).await;
+        }
+        Ok(())
     }
 
-    pub async fn cancel_consumption_plan(&mut self, power_consumer_id: String) -> Result<PowerConsumerModel, AppError> {
-        let power_consumer =
-            self.power_consumers.get_mut(&power_consumer_id).ok_or(AppError::not_found("Power consumer not found"))?;
-        Ok(power_consumer.cancel_consumption_plan(Utc::now()).await)
+    /// the device is called without holding the state lock
+    pub async fn read_power(state: &SharedState, power_consumer_id: &str) -> Result<PowerReading, AppError> {
+        let switch_backend =
+            state.read().await.power_consumers_service.get_power_consumer(power_consumer_id)?.switch_backend().clone();
+        switch_backend
+            .read_power(power_consumer_id)
+            .await?
+            .ok_or(AppError::not_found("Power consumer does not measure power"))
     }
 }
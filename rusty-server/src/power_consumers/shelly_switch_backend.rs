@@ -0,0 +1,203 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::{
+    model::{AppError, PowerReading, SwitchCapabilities},
+    settings::LocalDeviceConfig,
+};
+
+use super::SwitchBackend;
+
+/// Energy counter of the switch, total is in Wh
+#[derive(Deserialize)]
+struct ShellyEnergy {
+    total: f64,
+}
+
+/// Response of `Switch.GetStatus`, switches without power metering do not report `apower` and `aenergy`
+#[derive(Deserialize)]
+struct ShellySwitchStatus {
+    output: bool,
+    apower: Option<f64>,
+    aenergy: Option<ShellyEnergy>,
+}
+
+/// Api to Shelly Gen2 devices (Plus and Pro series), it calls RPC methods of the local HTTP API
+/// `Switch.Set` and `Switch.GetStatus`, switch id is the channel of the device.
+/// Power metering is taken from the device configuration, otherwise it is learned from the last switch status,
+/// device which has not reported its status yet is expected to measure power
+pub struct ShellySwitchBackend {
+    http_client: reqwest::Client,
+    devices: HashMap<String, LocalDeviceConfig>,
+    reported_power_metering: RwLock<HashMap<String, bool>>,
+}
+
+impl ShellySwitchBackend {
+    pub fn new(http_client: reqwest::Client, devices: HashMap<String, LocalDeviceConfig>) -> Self {
+        Self { http_client, devices, reported_power_metering: RwLock::new(HashMap::new()) }
+    }
+
+    fn device(&self, device_id: &str) -> Result<&LocalDeviceConfig, AppError> {
+        self.devices
+            .get(device_id)
+            .ok_or_else(|| AppError::user_error(&format!("Device {} has no Shelly address configured", device_id)))
+    }
+
+    async fn call(&self, device_id: &str, method: &str, params: &[(&str, String)]) -> Result<String, AppError> {
+        let device = self.device(device_id)?;
+        let request_failed = |e: reqwest::Error| {
+            AppError::system_error(&format!("Shelly {} call of device {} failed: {}", method, device_id, e))
+        };
+        self.http_client
+            .get(format!("{}/rpc/{}", device.base_url, method))
+            .query(&[("id", device.channel.to_string())])
+            .query(params)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(request_failed)?
+            .text()
+            .await
+            .map_err(request_failed)
+    }
+
+    async fn get_status(&self, device_id: &str) -> Result<ShellySwitchStatus, AppError> {
+        let text = self.call(device_id, "Switch.GetStatus", &[]).await?;
+        let status: ShellySwitchStatus = serde_json::from_str(&text)
+            .map_err(|e| AppError::system_error(&format!("Shelly switch status is not parsable: {}", e)))?;
+        self.reported_power_metering.write().unwrap().insert(device_id.to_owned(), status.apower.is_some());
+        Ok(status)
+    }
+}
+
+#[async_trait]
+impl SwitchBackend for ShellySwitchBackend {
+    async fn switch_device(&self, device_id: &str, switch_on: bool) -> Result<(), AppError> {
+        self.call(device_id, "Switch.Set", &[("on", switch_on.to_string())]).await.map(|_| ())
+    }
+
+    async fn read_state(&self, device_id: &str) -> Result<bool, AppError> {
+        self.get_status(device_id).await.map(|status| status.output)
+    }
+
+    async fn read_power(&self, device_id: &str) -> Result<Option<PowerReading>, AppError> {
+        let status = self.get_status(device_id).await?;
+        Ok(status.apower.map(|power_w| PowerReading {
+            power_w,
+            energy_total_kwh: status.aenergy.map(|energy| energy.total / 1000.0),
+            measured_at: Utc::now(),
+        }))
+    }
+
+    fn capabilities(&self, device_id: &str) -> SwitchCapabilities {
+        let read_power = self
+            .devices
+            .get(device_id)
+            .and_then(|device| device.power_metering)
+            .or_else(|| self.reported_power_metering.read().unwrap().get(device_id).copied())
+            .unwrap_or(true);
+        SwitchCapabilities { read_state: true, read_power }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{power_consumers::SwitchBackend, settings::LocalDeviceConfig};
+
+    use super::ShellySwitchBackend;
+
+    fn create_backend(server: &MockServer, channel: u32) -> ShellySwitchBackend {
+        ShellySwitchBackend::new(
+            reqwest::Client::new(),
+            HashMap::from([(
+                "boiler".to_owned(),
+                LocalDeviceConfig { base_url: server.uri(), channel, power_metering: None },
+            )]),
+        )
+    }
+
+    fn status_response(fixture: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_string(fixture)
+    }
+
+    #[tokio::test]
+    async fn switch_device_should_call_switch_set_of_the_channel() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/rpc/Switch.Set"))
+            .and(query_param("id", "1"))
+            .and(query_param("on", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"was_on": false}"#))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        create_backend(&server, 1).switch_device("boiler", true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn device_error_should_fail_switching() {
+        let server = MockServer::start().await;
+        Mock::given(path("/rpc/Switch.Set"))
+            .respond_with(ResponseTemplate::new(500).set_body_string(r#"{"code": -105, "message": "Argument 'id'"}"#))
+            .mount(&server)
+            .await;
+
+        let backend = create_backend(&server, 0);
+        assert!(backend.switch_device("boiler", false).await.is_err());
+        assert!(backend.switch_device("heater", false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn state_and_power_should_be_read_from_switch_status() {
+        let server = MockServer::start().await;
+        Mock::given(path("/rpc/Switch.GetStatus"))
+            .and(query_param("id", "0"))
+            .respond_with(status_response(include_str!("../../tests/fixtures/shelly/switch_status_pm.json")))
+            .mount(&server)
+            .await;
+
+        let backend = create_backend(&server, 0);
+        assert!(backend.read_state("boiler").await.unwrap());
+        let power_reading = backend.read_power("boiler").await.unwrap().unwrap();
+        assert_eq!(power_reading.power_w, 1843.2);
+        assert_eq!(power_reading.energy_total_kwh, Some(5.685));
+    }
+
+    #[tokio::test]
+    async fn power_should_be_missing_when_switch_does_not_measure_it() {
+        let server = MockServer::start().await;
+        Mock::given(path("/rpc/Switch.GetStatus"))
+            .respond_with(status_response(include_str!("../../tests/fixtures/shelly/switch_status.json")))
+            .mount(&server)
+            .await;
+
+        let backend = create_backend(&server, 0);
+        assert!(backend.capabilities("boiler").read_power, "status is not known yet");
+        assert!(!backend.read_state("boiler").await.unwrap());
+        assert_eq!(backend.read_power("boiler").await.unwrap(), None);
+        assert!(!backend.capabilities("boiler").read_power);
+    }
+
+    #[test]
+    fn configured_power_metering_should_be_reported() {
+        let backend = ShellySwitchBackend::new(
+            reqwest::Client::new(),
+            HashMap::from([(
+                "boiler".to_owned(),
+                LocalDeviceConfig { base_url: "http://shelly".to_owned(), channel: 0, power_metering: Some(false) },
+            )]),
+        );
+        assert!(!backend.capabilities("boiler").read_power);
+    }
+}
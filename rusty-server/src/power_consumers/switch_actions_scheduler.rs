@@ -1,5 +1,3 @@
-use std::sync::Arc;
-
 use chrono::{DateTime, Local, TimeDelta, Utc};
 
 use crate::{
@@ -7,19 +5,22 @@ use crate::{
     SharedState,
 };
 
-use super::home_assistant_service::HomeAssistantService;
+use super::{measure_actual_consumption, EnergyMeter, PowerConsumer, SwitchBackend};
 
 /// SwitchActionsScheduler is responsible for executing switch actions at required time
-/// by spawning tokio delayed tasks
+/// by spawning tokio delayed tasks, actions are executed by the switch backend of the power consumer.
+/// Energy meter of the power consumer is read before each action, actual consumption is measured
+/// when the plan is executed. Action is claimed under the state lock, meter is read and the device is switched
+/// without holding it, the result is recorded under the lock again.
 ///
+#[derive(Default)]
 pub struct SwitchActionsScheduler {
     state: Option<SharedState>,
-    home_assistant_service: Arc<HomeAssistantService>,
 }
 
 impl SwitchActionsScheduler {
-    pub fn new(home_assistant_service: Arc<HomeAssistantService>) -> Self {
-        Self { state: None, home_assistant_service }
+    pub fn new() -> Self {
+        Self::default()
     }
 
     pub fn set_state(&mut self, state: Option<SharedState>) {
@@ -44,20 +45,28 @@ impl SwitchActionsScheduler {
             }
             if !has_scheduled_action {
                 consumption_plan.state = Executed;
+                consumption_plan.actual_consumption = measure_actual_consumption(consumption_plan);
             }
         }
     }
 
     async fn execute_switch_action(
-        home_assistant_service: &HomeAssistantService,
+        switch_backend: &dyn SwitchBackend,
+        energy_meter: Option<&EnergyMeter>,
         power_consumer_id: &str,
         switch_action: &mut SwitchAction,
     ) {
         use SwitchActionState::*;
 
         if *switch_action.state() == Scheduled {
+            if let Some(energy_meter) = energy_meter {
+                match energy_meter.read(power_consumer_id).await {
+                    Ok(meter_reading) => switch_action.set_meter_reading(Some(meter_reading)),
+                    Err(e) => tracing::warn!("Meter of {} has not been read: {}", power_consumer_id, e),
+                }
+            }
             switch_action.set_state(Executed);
-            let _ = home_assistant_service
+            let _ = switch_backend
                 .switch_device(power_consumer_id, switch_action.switch_on())
                 .await
                 .map(|()| {
@@ -70,9 +79,62 @@ impl SwitchActionsScheduler {
         }
     }
 
+    /// executes scheduled switch action of the current plan of the power consumer,
+    /// the action is skipped when it has been canceled or executed meanwhile
+    pub async fn execute_scheduled_switch_action(state: &SharedState, power_consumer_id: &str, switch_action_id: &str) {
+        let (switch_backend, energy_meter, mut switch_action) = {
+            let power_consumers_service = &mut state.write().await.power_consumers_service;
+            let Some(power_consumer) = power_consumers_service.get_power_consumer_mut(power_consumer_id) else {
+                return;
+            };
+            let switch_backend = power_consumer.switch_backend().clone();
+            let energy_meter = power_consumer.energy_meter().cloned();
+            let Some(switch_action) = power_consumer
+                .consumption_plan_mut()
+                .and_then(|consumption_plan| consumption_plan.get_switch_action_by_id_mut(switch_action_id))
+            else {
+                return;
+            };
+            if *switch_action.state() != SwitchActionState::Scheduled {
+                return;
+            }
+            let claimed_switch_action = switch_action.clone();
+            switch_action.set_state(SwitchActionState::Executed);
+            (switch_backend, energy_meter, claimed_switch_action)
+        };
+
+        Self::execute_switch_action(
+            switch_backend.as_ref(),
+            energy_meter.as_deref(),
+            power_consumer_id,
+            &mut switch_action,
+        )
+        .await;
+
+        let switched_on_after_cancel = {
+            let power_consumers_service = &mut state.write().await.power_consumers_service;
+            let Some(consumption_plan) = power_consumers_service
+                .get_power_consumer_mut(power_consumer_id)
+                .and_then(PowerConsumer::consumption_plan_mut)
+            else {
+                return;
+            };
+            let switched_on_after_cancel =
+                switch_action.switch_on() && consumption_plan.state != ConsumptionPlanState::Processing;
+            if let Some(claimed_switch_action) = consumption_plan.get_switch_action_by_id_mut(switch_action_id) {
+                *claimed_switch_action = switch_action;
+            }
+            Self::switch_consumption_plan_state(consumption_plan);
+            switched_on_after_cancel
+        };
+        // plan could have been canceled while the device was being switched on
+        if switched_on_after_cancel {
+            let _ = switch_backend.switch_device(power_consumer_id, false).await;
+        }
+    }
+
     async fn spawn_scheduled_task_for_switch_action(
         state: SharedState,
-        home_assistant_service: Arc<HomeAssistantService>,
         power_consumer_id: String,
         switch_action_id: String,
         sleep_for: u64,
@@ -81,46 +143,106 @@ impl SwitchActionsScheduler {
         use tokio::time::sleep;
 
         sleep(Duration::from_millis(sleep_for)).await;
-
-        let power_consumers_service = &mut state.write().await.power_consumers_service;
-        if let Some(power_consumer) = power_consumers_service.get_power_consumer_mut(&power_consumer_id) {
-            if let Some(consumption_plan) = power_consumer.consumption_plan_mut() {
-                if let Some(switch_action) = consumption_plan.get_switch_action_by_id_mut(&switch_action_id) {
-                    Self::execute_switch_action(&home_assistant_service, &power_consumer_id, switch_action).await;
-                    Self::switch_consumption_plan_state(consumption_plan);
-                }
-            }
-        }
+        Self::execute_scheduled_switch_action(&state, &power_consumer_id, &switch_action_id).await;
     }
 
-    pub async fn schedule_switch_actions(
+    /// spawns tasks of the switch actions, actions which are due now are moved to now
+    /// and their ids are returned to be executed by the caller without holding the state lock
+    pub fn schedule_switch_actions(
         &self,
-        ha_device_name: &String,
+        device_id: &str,
         consumption_plan: &mut ConsumptionPlan,
         now: &DateTime<Utc>,
-    ) {
+    ) -> Vec<String> {
         let scheduling_threshold = *now + TimeDelta::seconds(15);
-        let power_consumer_id = ha_device_name.clone();
-        let mut switch_executed_without_scheduling = false;
+        let power_consumer_id = device_id.to_owned();
+        let mut due_switch_actions = Vec::new();
 
         for switch_action in consumption_plan.flat_switch_actions_mut() {
             if *switch_action.at() < scheduling_threshold {
                 switch_action.set_at(*now);
-                switch_executed_without_scheduling = true;
-                Self::execute_switch_action(&self.home_assistant_service, &power_consumer_id, switch_action).await;
+                due_switch_actions.push(switch_action.id().as_hyphenated().to_string());
             } else {
                 let sleep_for = (*switch_action.at() - Utc::now()).num_milliseconds() as u64;
                 tokio::spawn(Self::spawn_scheduled_task_for_switch_action(
                     self.state.as_ref().unwrap().clone(),
-                    self.home_assistant_service.clone(),
                     power_consumer_id.clone(),
                     switch_action.id().as_hyphenated().to_string().clone(),
                     sleep_for,
                 ));
             }
         }
-        if switch_executed_without_scheduling {
-            Self::switch_consumption_plan_state(consumption_plan);
-        }
+        due_switch_actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+    use crate::{
+        model::{SwitchAction, SwitchActionState},
+        power_consumers::{EnergyMeter, HomeAssistantService, InMemorySwitchBackend, SwitchBackend},
+        settings::{EnergyMeterConfig, HttpCallConfig},
+    };
+
+    use super::SwitchActionsScheduler;
+
+    #[tokio::test]
+    async fn switch_action_should_be_executed_by_given_backend() {
+        let switch_backend = InMemorySwitchBackend::new();
+        let mut switch_action = SwitchAction::new(Utc::now(), true);
+
+        SwitchActionsScheduler::execute_switch_action(&switch_backend, None, "switch.boiler", &mut switch_action).await;
+
+        assert_eq!(*switch_action.state(), SwitchActionState::Executed);
+        assert!(switch_backend.read_state("switch.boiler").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_switch_action_should_keep_backend_error() {
+        let switch_backend =
+            HomeAssistantService::new(&HttpCallConfig { base_url: "".to_owned(), token: "".to_owned() });
+        let mut switch_action = SwitchAction::new(Utc::now(), true);
+
+        SwitchActionsScheduler::execute_switch_action(&switch_backend, None, "switch.boiler", &mut switch_action).await;
+
+        assert_eq!(*switch_action.state(), SwitchActionState::Executed);
+        assert_eq!(
+            serde_json::to_value(&switch_action).unwrap()["result"],
+            "[SystemError] Home assistant authorization token is missing"
+        );
+    }
+
+    #[tokio::test]
+    async fn energy_meter_should_be_read_before_switching() {
+        let server = MockServer::start().await;
+        Mock::given(path("/api/states/sensor.boiler_energy"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"entity_id": "sensor.boiler_energy", "state": "152.4", "attributes": {"unit_of_measurement": "kWh"}}"#,
+            ))
+            .mount(&server)
+            .await;
+        let switch_backend = Arc::new(InMemorySwitchBackend::new());
+        let energy_meter = EnergyMeter::new(
+            EnergyMeterConfig::HomeAssistantEnergy { entity_id: "sensor.boiler_energy".to_owned() },
+            Arc::new(HomeAssistantService::new(&HttpCallConfig { base_url: server.uri(), token: "token".to_owned() })),
+            switch_backend.clone(),
+        );
+        let mut switch_action = SwitchAction::new(Utc::now(), true);
+
+        SwitchActionsScheduler::execute_switch_action(
+            switch_backend.as_ref(),
+            Some(&energy_meter),
+            "switch.boiler",
+            &mut switch_action,
+        )
+        .await;
+
+        assert_eq!(switch_action.meter_reading().unwrap().energy_total_kwh, Some(152.4));
+        assert!(switch_backend.read_state("switch.boiler").await.unwrap());
     }
 }
@@ -0,0 +1,135 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    model::{AppError, LiveDeviceState, PowerReading, SwitchCapabilities},
+    price_list_providers::create_http_client,
+    settings::{PowerConsumerConfig, Settings},
+};
+
+use super::{
+    HomeAssistantService, InMemorySwitchBackend, MqttSwitchBackend, ShellySwitchBackend, TasmotaSwitchBackend,
+};
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub enum SwitchBackendTypes {
+    #[default]
+    HomeAssistant,
+    Mock,
+    Mqtt,
+    Shelly,
+    Tasmota,
+}
+
+/// This trait should be implemented by integrations which control power consumer devices,
+/// device is identified by the `device_id` of the power consumer configuration.
+/// Calls must not block async runtime, remote calls need to use async http client
+#[async_trait]
+pub trait SwitchBackend: Send + Sync {
+    async fn switch_device(&self, device_id: &str, switch_on: bool) -> Result<(), AppError>;
+
+    /// returns true when the device is switched on
+    async fn read_state(&self, device_id: &str) -> Result<bool, AppError>;
+
+    /// returns power metering of the device, None when the device does not measure power
+    async fn read_power(&self, _device_id: &str) -> Result<Option<PowerReading>, AppError> {
+        Ok(None)
+    }
+
+    fn capabilities(&self, device_id: &str) -> SwitchCapabilities;
+
+    /// returns the last state pushed by the device, None when backend does not receive state changes
+    fn live_state(&self, _device_id: &str) -> Option<LiveDeviceState> {
+        None
+    }
+}
+
+/// Switch backend shared between power consumers and switch actions scheduler
+pub type SharedSwitchBackend = Arc<dyn SwitchBackend>;
+
+/// SwitchBackends creates each switch backend once, power consumers which pick the same backend share it.
+/// MQTT backend is created only when some power consumer uses it, it connects to the broker from `mqtt_config`.
+/// Shelly and Tasmota backends call local HTTP API of the devices
+pub struct SwitchBackends {
+    home_assistant: Arc<HomeAssistantService>,
+    mock: SharedSwitchBackend,
+    mqtt: Option<SharedSwitchBackend>,
+    shelly: SharedSwitchBackend,
+    tasmota: SharedSwitchBackend,
+}
+
+impl SwitchBackends {
+    pub fn new(settings: &Settings) -> Result<Self, AppError> {
+        let http_client = create_http_client(&settings.local_device_http_client)?;
+        let local_devices = |backend_type| {
+            backend_devices(settings, backend_type, "local device address", |config| config.local_device.clone())
+        };
+        let home_assistant_entities =
+            backend_devices(settings, SwitchBackendTypes::HomeAssistant, "Home Assistant entity", |config| {
+                Some(config.home_assistant.clone().unwrap_or_default())
+            })?;
+        let home_assistant =
+            HomeAssistantService::new(&settings.home_assistant_config).with_entities(home_assistant_entities)?;
+        Ok(Self {
+            home_assistant: Arc::new(home_assistant),
+            mock: Arc::new(InMemorySwitchBackend::new()),
+            mqtt: create_mqtt_switch_backend(settings)?,
+            shelly: Arc::new(ShellySwitchBackend::new(http_client.clone(), local_devices(SwitchBackendTypes::Shelly)?)),
+            tasmota: Arc::new(TasmotaSwitchBackend::new(http_client, local_devices(SwitchBackendTypes::Tasmota)?)),
+        })
+    }
+
+    /// Home Assistant backend is used by WebSocket client which feeds its live states and by power consumers import
+    pub fn home_assistant(&self) -> Arc<HomeAssistantService> {
+        self.home_assistant.clone()
+    }
+
+    pub fn get(&self, backend_type: &SwitchBackendTypes) -> Result<SharedSwitchBackend, AppError> {
+        match backend_type {
+            SwitchBackendTypes::HomeAssistant => Ok(self.home_assistant.clone()),
+            SwitchBackendTypes::Mock => Ok(self.mock.clone()),
+            SwitchBackendTypes::Mqtt => {
+                self.mqtt.clone().ok_or_else(|| AppError::user_error("MQTT switch backend is not configured"))
+            }
+            SwitchBackendTypes::Shelly => Ok(self.shelly.clone()),
+            SwitchBackendTypes::Tasmota => Ok(self.tasmota.clone()),
+        }
+    }
+}
+
+/// returns device configurations of the power consumers which use given backend,
+/// each of them needs to have the configuration required by the backend
+fn backend_devices<T>(
+    settings: &Settings,
+    backend_type: SwitchBackendTypes,
+    configuration_name: &str,
+    device_config: impl Fn(&PowerConsumerConfig) -> Option<T>,
+) -> Result<HashMap<String, T>, AppError> {
+    settings
+        .power_consumers
+        .iter()
+        .filter(|config| config.backend == backend_type)
+        .map(|config| {
+            device_config(config).map(|device| (config.device_id.to_owned(), device)).ok_or_else(|| {
+                AppError::user_error(&format!(
+                    "Power consumer {} has no {} configured",
+                    config.device_id, configuration_name
+                ))
+            })
+        })
+        .collect()
+}
+
+fn create_mqtt_switch_backend(settings: &Settings) -> Result<Option<SharedSwitchBackend>, AppError> {
+    let devices = backend_devices(settings, SwitchBackendTypes::Mqtt, "MQTT topics", |config| config.mqtt.clone())?;
+    if devices.is_empty() {
+        return Ok(None);
+    }
+    let mqtt_config = settings
+        .mqtt_config
+        .as_ref()
+        .ok_or_else(|| AppError::user_error("MQTT switch backend requires mqtt_config"))?;
+    Ok(Some(Arc::new(MqttSwitchBackend::new(mqtt_config, devices)?)))
+}
@@ -0,0 +1,243 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    model::{AppError, PowerReading, SwitchCapabilities},
+    settings::LocalDeviceConfig,
+};
+
+use super::SwitchBackend;
+
+/// Energy sensor of `Status 8` response, devices with metered channels report power as array,
+/// total energy is in kWh
+#[derive(Deserialize)]
+struct TasmotaEnergy {
+    #[serde(rename = "Power")]
+    power: Value,
+    #[serde(rename = "Total")]
+    total: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct TasmotaSensors {
+    #[serde(rename = "ENERGY")]
+    energy: Option<TasmotaEnergy>,
+}
+
+#[derive(Deserialize)]
+struct TasmotaSensorsStatus {
+    #[serde(rename = "StatusSNS")]
+    sensors: TasmotaSensors,
+}
+
+/// returns the value of the channel when device reports value per channel
+fn channel_value(value: &Value, channel: u32) -> Option<f64> {
+    match value {
+        Value::Array(values) => values.get(channel as usize).and_then(Value::as_f64),
+        value => value.as_f64(),
+    }
+}
+
+/// Api to Tasmota devices, it sends commands to `cm` endpoint of the local HTTP API,
+/// relay `Power1` is switched for the channel 0. Power is read by `Status 8` when the device has energy sensor.
+/// Power metering is taken from the device configuration, otherwise it is learned from the last `Status 8`,
+/// device which has not reported its sensors yet is expected to measure power
+pub struct TasmotaSwitchBackend {
+    http_client: reqwest::Client,
+    devices: HashMap<String, LocalDeviceConfig>,
+    reported_power_metering: RwLock<HashMap<String, bool>>,
+}
+
+impl TasmotaSwitchBackend {
+    pub fn new(http_client: reqwest::Client, devices: HashMap<String, LocalDeviceConfig>) -> Self {
+        Self { http_client, devices, reported_power_metering: RwLock::new(HashMap::new()) }
+    }
+
+    fn device(&self, device_id: &str) -> Result<&LocalDeviceConfig, AppError> {
+        self.devices
+            .get(device_id)
+            .ok_or_else(|| AppError::user_error(&format!("Device {} has no Tasmota address configured", device_id)))
+    }
+
+    async fn command(&self, device_id: &str, command: &str) -> Result<String, AppError> {
+        let device = self.device(device_id)?;
+        let request_failed = |e: reqwest::Error| {
+            AppError::system_error(&format!("Tasmota command {} of device {} failed: {}", command, device_id, e))
+        };
+        self.http_client
+            .get(format!("{}/cm", device.base_url))
+            .query(&[("cmnd", command)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(request_failed)?
+            .text()
+            .await
+            .map_err(request_failed)
+    }
+
+    /// sends Power command of the device channel and returns relay state from the response,
+    /// single relay devices respond with `POWER` instead of `POWER1`, other relays are always numbered
+    async fn power_command(&self, device_id: &str, state: Option<&str>) -> Result<bool, AppError> {
+        let channel = self.device(device_id)?.channel;
+        let relay = format!("Power{}", channel + 1);
+        let command = state.map(|state| format!("{} {}", relay, state)).unwrap_or_else(|| relay.clone());
+        let text = self.command(device_id, &command).await?;
+        let response = serde_json::from_str::<HashMap<String, Value>>(&text)
+            .map_err(|e| AppError::system_error(&format!("Tasmota response is not parsable: {}", e)))?;
+        let relay_state =
+            response.get(&relay.to_uppercase()).or_else(|| if channel == 0 { response.get("POWER") } else { None });
+
+        match relay_state.and_then(Value::as_str) {
+            Some("ON") => Ok(true),
+            Some("OFF") => Ok(false),
+            _ => Err(AppError::system_error(&format!("Device {} responded to {} with {}", device_id, command, text))),
+        }
+    }
+}
+
+#[async_trait]
+impl SwitchBackend for TasmotaSwitchBackend {
+    async fn switch_device(&self, device_id: &str, switch_on: bool) -> Result<(), AppError> {
+        let state = self.power_command(device_id, Some(if switch_on { "ON" } else { "OFF" })).await?;
+        if state != switch_on {
+            return Err(AppError::system_error(&format!("Device {} has not been switched", device_id)));
+        }
+        Ok(())
+    }
+
+    async fn read_state(&self, device_id: &str) -> Result<bool, AppError> {
+        self.power_command(device_id, None).await
+    }
+
+    async fn read_power(&self, device_id: &str) -> Result<Option<PowerReading>, AppError> {
+        let channel = self.device(device_id)?.channel;
+        let text = self.command(device_id, "Status 8").await?;
+        let status = serde_json::from_str::<TasmotaSensorsStatus>(&text)
+            .map_err(|e| AppError::system_error(&format!("Tasmota sensors status is not parsable: {}", e)))?;
+
+        let power_reading = status.sensors.energy.and_then(|energy| {
+            channel_value(&energy.power, channel).map(|power_w| PowerReading {
+                power_w,
+                energy_total_kwh: energy.total.and_then(|total| channel_value(&total, channel)),
+                measured_at: Utc::now(),
+            })
+        });
+        self.reported_power_metering.write().unwrap().insert(device_id.to_owned(), power_reading.is_some());
+        Ok(power_reading)
+    }
+
+    fn capabilities(&self, device_id: &str) -> SwitchCapabilities {
+        let read_power = self
+            .devices
+            .get(device_id)
+            .and_then(|device| device.power_metering)
+            .or_else(|| self.reported_power_metering.read().unwrap().get(device_id).copied())
+            .unwrap_or(true);
+        SwitchCapabilities { read_state: true, read_power }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use wiremock::{
+        matchers::{path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{power_consumers::SwitchBackend, settings::LocalDeviceConfig};
+
+    use super::TasmotaSwitchBackend;
+
+    fn create_backend(server: &MockServer, channel: u32) -> TasmotaSwitchBackend {
+        TasmotaSwitchBackend::new(
+            reqwest::Client::new(),
+            HashMap::from([(
+                "plug".to_owned(),
+                LocalDeviceConfig { base_url: server.uri(), channel, power_metering: None },
+            )]),
+        )
+    }
+
+    async fn mount_command(server: &MockServer, command: &str, response: &str) {
+        Mock::given(path("/cm"))
+            .and(query_param("cmnd", command))
+            .respond_with(ResponseTemplate::new(200).set_body_string(response))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn switch_device_should_send_power_command_of_the_relay() {
+        let server = MockServer::start().await;
+        mount_command(&server, "Power1 ON", r#"{"POWER":"ON"}"#).await;
+        mount_command(&server, "Power2 OFF", r#"{"POWER2":"OFF"}"#).await;
+
+        create_backend(&server, 0).switch_device("plug", true).await.unwrap();
+        create_backend(&server, 1).switch_device("plug", false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unexpected_response_should_fail_switching() {
+        let server = MockServer::start().await;
+        mount_command(&server, "Power1 ON", r#"{"POWER":"OFF"}"#).await;
+        mount_command(&server, "Power1 OFF", r#"{"Command":"Unknown"}"#).await;
+
+        let backend = create_backend(&server, 0);
+        assert!(backend.switch_device("plug", true).await.is_err());
+        assert!(backend.switch_device("plug", false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn state_of_another_relay_should_not_be_taken_for_the_second_channel() {
+        let server = MockServer::start().await;
+        mount_command(&server, "Power2 ON", r#"{"POWER":"ON"}"#).await;
+
+        assert!(create_backend(&server, 1).switch_device("plug", true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn state_should_be_read_by_power_command() {
+        let server = MockServer::start().await;
+        mount_command(&server, "Power1", r#"{"POWER1":"ON","POWER2":"OFF"}"#).await;
+
+        assert!(create_backend(&server, 0).read_state("plug").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn power_should_be_read_from_energy_sensor() {
+        let server = MockServer::start().await;
+        mount_command(&server, "Status 8", include_str!("../../tests/fixtures/tasmota/status_8_energy.json")).await;
+
+        let power_reading = create_backend(&server, 0).read_power("plug").await.unwrap().unwrap();
+        assert_eq!(power_reading.power_w, 1204.0);
+        assert_eq!(power_reading.energy_total_kwh, Some(152.374));
+    }
+
+    #[tokio::test]
+    async fn power_of_the_channel_should_be_read_from_metered_channels() {
+        let server = MockServer::start().await;
+        mount_command(&server, "Status 8", include_str!("../../tests/fixtures/tasmota/status_8_channels.json")).await;
+
+        let power_reading = create_backend(&server, 1).read_power("plug").await.unwrap().unwrap();
+        assert_eq!(power_reading.power_w, 36.0);
+        assert_eq!(power_reading.energy_total_kwh, Some(3.118));
+    }
+
+    #[tokio::test]
+    async fn power_should_be_missing_when_device_has_no_energy_sensor() {
+        let server = MockServer::start().await;
+        mount_command(&server, "Status 8", include_str!("../../tests/fixtures/tasmota/status_8_no_energy.json")).await;
+
+        let backend = create_backend(&server, 0);
+        assert_eq!(backend.read_power("plug").await.unwrap(), None);
+        assert!(!backend.capabilities("plug").read_power);
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    model::{AppError, Money, PriceCategory, PriceList},
+    settings::PriceCategoryStrategy,
+};
+
+use super::SingleDayPriceList;
+
+impl PriceCategoryStrategy {
+    /// Function returns category for each of the prices of a single day
+    pub fn categorize(&self, prices: &[Money]) -> Vec<PriceCategory> {
+        match self {
+            PriceCategoryStrategy::Absolute(thresholds) => {
+                prices.iter().map(|price| thresholds.evaluate(*price)).collect()
+            }
+            PriceCategoryStrategy::Percentiles { min_percentile, max_percentile } => {
+                let mut sorted_prices = prices.to_vec();
+                sorted_prices.sort();
+                let min_threshold = Self::percentile(&sorted_prices, *min_percentile);
+                let max_threshold = Self::percentile(&sorted_prices, *max_percentile);
+                Self::categorize_by_inclusive_thresholds(prices, min_threshold, max_threshold)
+            }
+            PriceCategoryStrategy::MeanDeviation { below_mean_percent, above_mean_percent } => {
+                let mean = Money::mean(prices).unwrap_or_default();
+                let mean_abs = if mean.is_negative() { -mean } else { mean };
+                let min_threshold = mean - mean_abs.percent(*below_mean_percent);
+                let max_threshold = mean + mean_abs.percent(*above_mean_percent);
+                prices
+                    .iter()
+                    .map(|price| {
+                        let price = *price;
+                        if price < min_threshold {
+                            PriceCategory::Min
+                        } else if price > max_threshold {
+                            PriceCategory::Max
+                        } else {
+                            PriceCategory::Medium
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// nearest rank percentile of sorted prices
+    fn percentile(sorted_prices: &[Money], percentile: u32) -> Option<Money> {
+        if sorted_prices.is_empty() {
+            return None;
+        }
+        let rank = (percentile.min(100) as f64 / 100.0 * sorted_prices.len() as f64).ceil() as usize;
+        Some(sorted_prices[rank.clamp(1, sorted_prices.len()) - 1])
+    }
+
+    /// when all prices are equal the day has no cheap or expensive hours, so all of them are in the Medium category
+    fn categorize_by_inclusive_thresholds(
+        prices: &[Money],
+        min_threshold: Option<Money>,
+        max_threshold: Option<Money>,
+    ) -> Vec<PriceCategory> {
+        prices
+            .iter()
+            .map(|price| match (min_threshold, max_threshold) {
+                (Some(min_threshold), Some(max_threshold)) if min_threshold < max_threshold => {
+                    if *price <= min_threshold {
+                        PriceCategory::Min
+                    } else if *price >= max_threshold {
+                        PriceCategory::Max
+                    } else {
+                        PriceCategory::Medium
+                    }
+                }
+                _ => PriceCategory::Medium,
+            })
+            .collect()
+    }
+}
+
+/// CategorizedPriceList wraps any price list provider and assigns price categories to the whole day price list
+/// with configured strategy, so categories are evaluated the same way regardless of the provider.
+/// Relative strategies keep the web-app colouring meaningful on days when all prices are low or high.
+pub struct CategorizedPriceList<T: SingleDayPriceList> {
+    price_list_provider: T,
+    strategy: PriceCategoryStrategy,
+}
+
+impl<T: SingleDayPriceList> CategorizedPriceList<T> {
+    pub fn new(price_list_provider: T, strategy: PriceCategoryStrategy) -> Self {
+        Self { price_list_provider, strategy }
+    }
+}
+
+#[async_trait]
+impl<T: SingleDayPriceList> SingleDayPriceList for CategorizedPriceList<T> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        let price_list = self.price_list_provider.get_price_list(for_day).await?;
+        let prices: Vec<Money> = price_list.items().iter().map(|item| item.price()).collect();
+
+        Ok(Arc::new(
+            price_list.with_items(
+                price_list
+                    .items()
+                    .iter()
+                    .zip(self.strategy.categorize(&prices))
+                    .map(|(item, category)| {
+                        let mut categorized_item = item.clone();
+                        categorized_item.set_category(category);
+                        categorized_item
+                    })
+                    .collect(),
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        model::{
+            Money,
+            PriceCategory::{self, *},
+        },
+        price_list_providers::{SingleDayPriceList, TimeOfUsePriceListProvider},
+        settings::{PriceCategoryStrategy, PriceCategoryThresholds},
+    };
+    use chrono::{Local, TimeZone, Utc};
+
+    use super::CategorizedPriceList;
+
+    const PRICES: [i64; 8] = [10000, 12000, 14000, 16000, 18000, 20000, 22000, 24000];
+
+    fn categorize(strategy: PriceCategoryStrategy, prices: &[i64]) -> Vec<PriceCategory> {
+        strategy.categorize(&prices.iter().map(|price| Money::from_legacy_price(*price)).collect::<Vec<_>>())
+    }
+
+    fn thresholds(min: i64, max: i64) -> PriceCategoryThresholds {
+        PriceCategoryThresholds { min: Money::from_legacy_price(min), max: Money::from_legacy_price(max) }
+    }
+
+    #[test]
+    fn absolute_thresholds_should_categorize_by_price() {
+        let strategy = PriceCategoryStrategy::Absolute(thresholds(12000, 20000));
+        assert_eq!(categorize(strategy, &PRICES), vec![Min, Medium, Medium, Medium, Medium, Medium, Max, Max]);
+    }
+
+    #[test]
+    fn percentiles_should_categorize_relatively_to_the_day() {
+        let strategy = PriceCategoryStrategy::Percentiles { min_percentile: 25, max_percentile: 75 };
+        assert_eq!(categorize(strategy, &PRICES), vec![Min, Min, Medium, Medium, Medium, Max, Max, Max]);
+
+        let windy_day: Vec<i64> = PRICES.iter().map(|price| price / 10).collect();
+        let strategy = PriceCategoryStrategy::Percentiles { min_percentile: 25, max_percentile: 75 };
+        assert_eq!(categorize(strategy, &windy_day), vec![Min, Min, Medium, Medium, Medium, Max, Max, Max]);
+    }
+
+    #[test]
+    fn percentiles_of_flat_day_should_be_medium() {
+        let strategy = PriceCategoryStrategy::Percentiles { min_percentile: 25, max_percentile: 75 };
+        assert_eq!(categorize(strategy, &[5000, 5000, 5000]), vec![Medium, Medium, Medium]);
+    }
+
+    #[test]
+    fn mean_deviation_should_categorize_prices_far_from_mean() {
+        let strategy = PriceCategoryStrategy::MeanDeviation { below_mean_percent: 20, above_mean_percent: 20 };
+        assert_eq!(categorize(strategy, &PRICES), vec![Min, Min, Medium, Medium, Medium, Medium, Max, Max]);
+    }
+
+    #[test]
+    fn mean_deviation_should_handle_negative_mean() {
+        let strategy = PriceCategoryStrategy::MeanDeviation { below_mean_percent: 50, above_mean_percent: 50 };
+        assert_eq!(categorize(strategy, &[-4000, -2000, 0]), vec![Min, Medium, Max]);
+    }
+
+    #[tokio::test]
+    async fn categorized_price_list_should_override_provider_categories() {
+        let price_list = CategorizedPriceList::new(
+            TimeOfUsePriceListProvider::w12(),
+            PriceCategoryStrategy::Absolute(thresholds(50000, 200000)),
+        );
+        let for_day = Local.with_ymd_and_hms(2024, 8, 26, 0, 0, 0).unwrap().with_timezone(&Utc);
+        let price_list = price_list.get_price_list(&for_day).await.unwrap();
+        assert!(price_list.items().iter().all(|item| *item.category() == Medium));
+    }
+}
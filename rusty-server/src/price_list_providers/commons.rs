@@ -1,12 +1,47 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use chrono::{DateTime, Datelike, Local, TimeZone, Utc};
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use rust_decimal::Decimal;
 
-use crate::model::{AppError, PriceListItem};
+use crate::{
+    model::{AppError, Money, PriceCategory, PriceList},
+    settings::{HttpClientConfig, PriceCategoryThresholds},
+};
 
-/// This traits should be implemented by price list providers
-pub trait SingleDayPriceList {
-    fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<Vec<PriceListItem>>, AppError>;
+/// This traits should be implemented by price list providers,
+/// price list fetching must not block async runtime, remote calls need to use async http client.
+/// Price list of the day is returned in an envelope with its provider name, fetch time and status
+#[async_trait]
+pub trait SingleDayPriceList: Send + Sync {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError>;
+}
+
+#[async_trait]
+impl<T: SingleDayPriceList + ?Sized> SingleDayPriceList for Box<T> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        (**self).get_price_list(for_day).await
+    }
+}
+
+#[async_trait]
+impl<T: SingleDayPriceList + ?Sized> SingleDayPriceList for Arc<T> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        (**self).get_price_list(for_day).await
+    }
+}
+
+/// Price list provider shared between application state and services
+pub type SharedSingleDayPriceList = Arc<dyn SingleDayPriceList>;
+
+/// http client used by remote price list providers, timeouts protect server from slow price list pages
+pub fn create_http_client(config: &HttpClientConfig) -> Result<reqwest::Client, AppError> {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+        .read_timeout(Duration::from_millis(config.read_timeout_ms))
+        .http1_title_case_headers()
+        .build()
+        .map_err(|e| AppError::system_error(&format!("Http client can not be created: {}", e)))
 }
 
 /// we need to convert datetime to local time zone to cut off time part - get time at the midnight
@@ -19,11 +54,46 @@ pub fn cut_off_time_from_date(date_time: &DateTime<Utc>) -> DateTime<Utc> {
         .unwrap()
 }
 
-/// date needs to be parsed as date in the local time zone and next converted to utc
+/// returns midnight of the next local day, it is not always 24 hours after given day
+/// because of daylight saving time changes
+pub fn next_day_start(day: &DateTime<Utc>) -> DateTime<Utc> {
+    cut_off_time_from_date(&(cut_off_time_from_date(day) + TimeDelta::hours(36)))
+}
+
+/// market prices are categorized by default thresholds
+pub fn evaluate_price_category(price: Money) -> PriceCategory {
+    PriceCategoryThresholds::default().evaluate(price)
+}
+
+/// Function parses decimal price per MWh, e.g. `-12,34` or `1 234.5`, into Money per kWh without floating point
+/// conversion, so negative and near zero prices are kept exactly.
+pub fn parse_price(text: &str) -> Result<Money, AppError> {
+    parse_decimal(text).map(Money::from_price_per_mwh)
+}
+
+/// Function parses decimal number with decimal comma or point, spaces and unicode minus are allowed
+pub fn parse_decimal(text: &str) -> Result<Decimal, AppError> {
+    let normalized: String = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            ',' => '.',
+            '\u{2212}' => '-',
+            c => c,
+        })
+        .collect();
+    Decimal::from_str_exact(&normalized)
+        .map_err(|_| AppError::system_error(&format!("Price '{}' can not be parsed!", text)))
+}
+
+/// date needs to be parsed as date in the local time zone and next converted to utc,
+/// local midnight offset depends on daylight saving time
 pub fn parse_date(date: String) -> Result<DateTime<Utc>, AppError> {
-    DateTime::parse_from_str(&(date + " 00:00:00 +01:00"), "%d-%m-%Y  %H:%M:%S %z")
-        .map(|d| d.with_timezone(&Utc))
-        .map_err(|_e| AppError::user_error("Input date has incorrect format"))
+    NaiveDate::parse_from_str(date.trim(), "%d-%m-%Y")
+        .ok()
+        .and_then(|date| Local.from_local_datetime(&date.and_time(NaiveTime::MIN)).earliest())
+        .map(|date| date.with_timezone(&Utc))
+        .ok_or_else(|| AppError::user_error("Input date has incorrect format"))
 }
 
 #[cfg(test)]
@@ -31,7 +101,9 @@ mod tests {
     use chrono::{Datelike, Local, TimeZone, Timelike, Utc};
 
     use super::cut_off_time_from_date;
+    use super::next_day_start;
     use super::parse_date;
+    use super::parse_price;
 
     #[test]
     fn cut_off_time_from_date_test() {
@@ -60,4 +132,32 @@ mod tests {
         println!("parsed date : {}", parse_date("12-12-2024".to_owned()).unwrap());
         assert_eq!(parse_date("12-12-2024".to_owned()).unwrap(), Utc.with_ymd_and_hms(2024, 12, 11, 23, 0, 0).unwrap(),);
     }
+
+    #[test]
+    fn parse_date_should_respect_daylight_saving_time() {
+        assert_eq!(parse_date("26-08-2024".to_owned()).unwrap(), Utc.with_ymd_and_hms(2024, 8, 25, 22, 0, 0).unwrap());
+        assert!(parse_date("32-08-2024".to_owned()).is_err());
+    }
+
+    #[test]
+    fn next_day_start_should_respect_daylight_saving_time() {
+        let day = Local.with_ymd_and_hms(2024, 3, 31, 0, 0, 0).unwrap().with_timezone(&Utc);
+        let next_day = Local.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap().with_timezone(&Utc);
+        assert_eq!(next_day_start(&day), next_day);
+        assert_eq!((next_day - day).num_hours(), 23);
+    }
+
+    #[test]
+    fn parse_price_should_keep_negative_and_near_zero_prices_exactly() {
+        let price = |text| parse_price(text).unwrap().legacy_price();
+        assert_eq!(price("123,45"), 12345);
+        assert_eq!(price(" -0,01 "), -1);
+        assert_eq!(price("0,04"), 4);
+        assert_eq!(price("-5"), -500);
+        assert_eq!(price("1\u{a0}234.5"), 123450);
+        assert_eq!(price("\u{2212}12.34"), -1234);
+        assert_eq!(parse_price("0.125").unwrap(), "0.000125".parse().unwrap());
+        assert!(parse_price("-").is_err());
+        assert!(parse_price("12,3a").is_err());
+    }
 }
@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    model::{AppError, Money, PriceBreakdown, PriceList, PriceListItem},
+    settings::PriceCompositionConfig,
+};
+
+use super::{SingleDayPriceList, TimeOfUsePriceListProvider};
+
+/// ComposedPriceList wraps any price list provider and turns its energy prices into end user prices.
+/// Distribution charge is taken from the distribution tariff band which matches price list item start,
+/// fees and excise are added to each kWh and VAT is calculated exactly from the sum of all components.
+/// Each returned price list item has price breakdown, price category is copied from the wrapped price list
+/// and it is evaluated again from the composed price by CategorizedPriceList.
+pub struct ComposedPriceList<T: SingleDayPriceList> {
+    price_list_provider: T,
+    composition: PriceCompositionConfig,
+    distribution_tariff: Option<TimeOfUsePriceListProvider>,
+}
+
+impl<T: SingleDayPriceList> ComposedPriceList<T> {
+    pub fn new(price_list_provider: T, composition: PriceCompositionConfig) -> Self {
+        let distribution_tariff = composition.distribution_tariff.clone().map(TimeOfUsePriceListProvider::new);
+        Self { price_list_provider, composition, distribution_tariff }
+    }
+
+    async fn get_distribution_price_list(&self, for_day: &DateTime<Utc>) -> Result<Vec<PriceListItem>, AppError> {
+        match &self.distribution_tariff {
+            Some(distribution_tariff) => Ok(distribution_tariff.get_price_list(for_day).await?.items().to_vec()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// distribution tariff has hourly bands, so charge is taken from the band which contains item start
+    fn find_distribution_charge(distribution_price_list: &[PriceListItem], starts_at: &DateTime<Utc>) -> Money {
+        distribution_price_list
+            .iter()
+            .find(|item| item.starts_at() <= starts_at && *starts_at < *item.starts_at() + *item.duration())
+            .map(|item| item.price())
+            .unwrap_or_default()
+    }
+
+    fn compose_price(&self, energy: Money, distribution: Money) -> PriceBreakdown {
+        let energy = match self.composition.minimal_energy_price {
+            Some(minimal_energy_price)
+                if energy >= Money::default()
+                    && energy < self.composition.minimal_energy_price_threshold.unwrap_or(minimal_energy_price) =>
+            {
+                minimal_energy_price
+            }
+            _ => energy,
+        };
+        let fees = self.composition.fees.iter().map(|fee| fee.price).sum();
+        let excise = self.composition.excise;
+        let net_price = energy + distribution + fees + excise;
+        let vat = net_price.percent(self.composition.vat_rate_percent);
+
+        PriceBreakdown { energy, distribution, fees, excise, vat }
+    }
+}
+
+#[async_trait]
+impl<T: SingleDayPriceList> SingleDayPriceList for ComposedPriceList<T> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        let energy_price_list = self.price_list_provider.get_price_list(for_day).await?;
+        let distribution_price_list = self.get_distribution_price_list(for_day).await?;
+
+        Ok(Arc::new(
+            energy_price_list.with_items(
+                energy_price_list
+                    .items()
+                    .iter()
+                    .map(|item| {
+                        let distribution = Self::find_distribution_charge(&distribution_price_list, item.starts_at());
+                        let breakdown = self.compose_price(item.price(), distribution);
+                        let price = breakdown.energy
+                            + breakdown.distribution
+                            + breakdown.fees
+                            + breakdown.excise
+                            + breakdown.vat;
+
+                        let mut composed_item =
+                            PriceListItem::new(*item.starts_at(), *item.duration(), price, item.category().clone());
+                        composed_item.set_breakdown(Some(breakdown));
+                        composed_item.set_source(item.source().map(str::to_owned));
+                        composed_item.set_warnings(item.warnings().to_vec());
+                        composed_item
+                    })
+                    .collect(),
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+
+    use crate::{
+        model::{AppError, Money, PriceBreakdown, PriceCategory, PriceList, PriceListItem},
+        settings::{FeeConfig, PriceCompositionConfig, Settings, TariffBandConfig, TimeOfUseTariffConfig},
+    };
+
+    use super::{ComposedPriceList, SingleDayPriceList};
+
+    struct FixedPriceList {
+        prices: Vec<i64>,
+    }
+
+    #[async_trait]
+    impl SingleDayPriceList for FixedPriceList {
+        async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+            let items = self
+                .prices
+                .iter()
+                .enumerate()
+                .map(|(i, price)| {
+                    PriceListItem::new(
+                        *for_day + TimeDelta::hours(i as i64),
+                        TimeDelta::hours(1),
+                        Money::from_legacy_price(*price),
+                        PriceCategory::Medium,
+                    )
+                })
+                .collect();
+            Ok(Arc::new(PriceList::new(*for_day, "DayAheadMarket", *for_day, items)))
+        }
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, 0, 0, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    fn money(price: i64) -> Money {
+        Money::from_legacy_price(price)
+    }
+
+    fn distribution_band(from_hour: u32, to_hour: u32, price: i64) -> TariffBandConfig {
+        TariffBandConfig {
+            from_hour,
+            to_hour,
+            price: money(price),
+            category: PriceCategory::Medium,
+            weekdays: Vec::new(),
+            months: Vec::new(),
+            valid_from: None,
+            valid_to: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_composition_should_keep_energy_price() {
+        let composed_price_list =
+            ComposedPriceList::new(FixedPriceList { prices: vec![45000, 10000] }, PriceCompositionConfig::default());
+        let price_list = composed_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+
+        assert_eq!(price_list.items()[0].price().legacy_price(), 45000);
+        assert_eq!(price_list.items()[1].price().legacy_price(), 10000);
+        assert_eq!(price_list.items()[1].category(), &PriceCategory::Medium);
+    }
+
+    #[tokio::test]
+    async fn price_should_be_composed_from_energy_distribution_fees_excise_and_vat() {
+        let composition = PriceCompositionConfig {
+            minimal_energy_price: None,
+            minimal_energy_price_threshold: None,
+            distribution_tariff: Some(TimeOfUseTariffConfig {
+                name: "G12 distribution".to_owned(),
+                bands: vec![distribution_band(22, 6, 5000), distribution_band(0, 24, 25000)],
+            }),
+            fees: vec![
+                FeeConfig { name: "capacity".to_owned(), price: money(1000) },
+                FeeConfig { name: "res".to_owned(), price: money(500) },
+            ],
+            excise: money(500),
+            vat_rate_percent: 23,
+        };
+        let composed_price_list = ComposedPriceList::new(FixedPriceList { prices: vec![40000; 24] }, composition);
+        let price_list = composed_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+
+        assert_eq!(
+            price_list.items()[0].breakdown(),
+            Some(&PriceBreakdown {
+                energy: money(40000),
+                distribution: money(5000),
+                fees: money(1500),
+                excise: money(500),
+                vat: money(10810)
+            })
+        );
+        assert_eq!(price_list.items()[0].price().legacy_price(), 57810);
+
+        assert_eq!(price_list.items()[12].breakdown().unwrap().distribution, money(25000));
+        assert_eq!(price_list.items()[12].price().legacy_price(), 82410);
+    }
+
+    #[tokio::test]
+    async fn energy_price_below_minimal_price_should_be_raised() {
+        let composition = PriceCompositionConfig { minimal_energy_price: Some(money(500)), ..Default::default() };
+        let composed_price_list = ComposedPriceList::new(FixedPriceList { prices: vec![3, 12300] }, composition);
+        let price_list = composed_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+
+        assert_eq!(price_list.items()[0].price().legacy_price(), 500);
+        assert_eq!(price_list.items()[1].price().legacy_price(), 12300);
+    }
+
+    #[tokio::test]
+    async fn energy_price_below_threshold_should_be_replaced_by_minimal_price() {
+        let composition = PriceCompositionConfig {
+            minimal_energy_price: Some(money(500)),
+            minimal_energy_price_threshold: Some(money(5)),
+            ..Default::default()
+        };
+        let composed_price_list =
+            ComposedPriceList::new(FixedPriceList { prices: vec![4, 5, 300, -2000] }, composition);
+        let price_list = composed_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+
+        let prices: Vec<i64> = price_list.items().iter().map(|item| item.price().legacy_price()).collect();
+        assert_eq!(prices, vec![500, 5, 300, -2000]);
+    }
+
+    #[tokio::test]
+    async fn shipped_composition_should_keep_negative_energy_price() {
+        let composition = Settings::shipped().unwrap().price_composition.unwrap();
+        let composed_price_list = ComposedPriceList::new(FixedPriceList { prices: vec![-2000, 3, 12300] }, composition);
+        let price_list = composed_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+
+        let prices: Vec<i64> = price_list.items().iter().map(|item| item.price().legacy_price()).collect();
+        assert_eq!(prices, vec![-2000, 3, 12300]);
+    }
+}
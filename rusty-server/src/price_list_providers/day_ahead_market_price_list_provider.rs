@@ -1,24 +1,82 @@
-use crate::model::{AppError, Currency, PriceCategory, PriceListItem};
+use crate::model::{AppError, Money, PriceList, PriceListItem};
+use async_trait::async_trait;
 use chrono::{DateTime, Local, TimeDelta, Utc};
-use moka::sync::Cache;
+use moka::future::Cache;
 use regex::Regex;
 use scraper::{Html, Selector};
-use std::{num::ParseFloatError, sync::Arc};
+use std::sync::Arc;
 
-use super::{commons::cut_off_time_from_date, parse_date, SingleDayPriceList};
+use super::{
+    commons::{cut_off_time_from_date, evaluate_price_category, next_day_start, parse_price},
+    parse_date, SingleDayPriceList,
+};
+
+/// name of the provider reported in price list metadata
+const DAY_AHEAD_MARKET_PROVIDER: &str = "DayAheadMarket";
+
+/// DayAheadMarketPageFetcher fetches day ahead market page content as text,
+/// it separates scraping from the network so scraping could be tested against stored pages
+#[async_trait]
+pub trait DayAheadMarketPageFetcher: Send + Sync {
+    async fn fetch_page(&self, url: &str) -> Result<String, AppError>;
+}
+
+/// HttpDayAheadMarketPageFetcher fetches the live day ahead market page, it is the default fetcher
+pub struct HttpDayAheadMarketPageFetcher {
+    http_client: reqwest::Client,
+}
+
+impl HttpDayAheadMarketPageFetcher {
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self { http_client }
+    }
+}
+
+#[async_trait]
+impl DayAheadMarketPageFetcher for HttpDayAheadMarketPageFetcher {
+    /// web server of day ahead market page incorrectly handles headers,
+    /// it requires them in capital letters so http client is configured to send title case headers
+    async fn fetch_page(&self, url: &str) -> Result<String, AppError> {
+        let request_failed = |e: reqwest::Error| {
+            if e.is_timeout() {
+                AppError::system_error(&format!("Day ahead market page request timed out: {}", e))
+            } else {
+                AppError::system_error(&format!("Day ahead market page request failed: {}", e))
+            }
+        };
+
+        self.http_client
+            .get(url)
+            .header("User-Agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Safari/537.36")
+            .header("Accept-Language", "en-GB,en-US;q=0.9,en;q=0.8,pl;q=0.7")
+            .send()
+            .await
+            .map_err(request_failed)?
+            .text()
+            .await
+            .map_err(request_failed)
+    }
+}
 
 ///  DayAheadMarketPriceListProvider scrapes price list from the day ahead market web page, using scraper crate
-/// and it stores price list in the moka cache to reduce external calls and speed application
+/// and it stores price list in the moka cache to reduce external calls and speed application,
+/// concurrent requests for the same missing day share single page fetch
 ///
 /// Price list for tomorrow is published at 2 pm today, so request for price list before 2 pm for tomorrow will fail
-/// Page is able to return price lists for two months back
+/// Page is able to return price lists for two months back.
+/// Page shows only the delivery day, so publication time of the price list is unknown
 pub struct DayAheadMarketPriceListProvider {
-    cache: Cache<DateTime<Utc>, Arc<Vec<PriceListItem>>>,
+    page_fetcher: Box<dyn DayAheadMarketPageFetcher>,
+    cache: Cache<DateTime<Utc>, Arc<PriceList>>,
 }
 
 impl DayAheadMarketPriceListProvider {
-    pub fn new() -> Self {
-        Self { cache: Cache::new(30) }
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self::with_page_fetcher(Box::new(HttpDayAheadMarketPageFetcher::new(http_client)))
+    }
+
+    pub fn with_page_fetcher(page_fetcher: Box<dyn DayAheadMarketPageFetcher>) -> Self {
+        Self { page_fetcher, cache: Cache::new(30) }
     }
 
     fn get_day_ahead_market_url(requested_date: DateTime<Utc>) -> String {
@@ -26,37 +84,6 @@ impl DayAheadMarketPriceListProvider {
         format!("https://tge.pl/energia-elektryczna-rdn?dateShow={}&dateAction=prev", day_before.format("%d-%m-%Y"))
     }
 
-    /// Function fetch day ahead market page content as text, it needs to use curl library
-    /// as web server of day ahead market page incorrectly handles headers,
-    /// it requires them in capital letters but http standard puts them in lower case and reqwest
-    /// implements headers in the recommended way
-    fn fetch_price_list_text(url: String) -> Result<String, AppError> {
-        use curl::easy::Easy;
-        use curl::easy::List;
-
-        let mut list = List::new();
-        list.append("User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Safari/537.36").unwrap();
-        list.append("Accept-Language: en-GB,en-US;q=0.9,en;q=0.8,pl;q=0.7").unwrap();
-
-        let mut text: String = String::new();
-        {
-            let mut easy = Easy::new();
-            let _ = easy
-                .url(&url)
-                .and_then(|_| easy.http_headers(list))
-                .and_then(|_| {
-                    let mut transfer = easy.transfer();
-                    let _ = transfer.write_function(|data| {
-                        String::from_utf8(Vec::from(data)).map(|new_text| text.push_str(&new_text)).unwrap();
-                        Ok(data.len())
-                    });
-                    transfer.perform()
-                })
-                .map_err(|e| AppError::system_error(&format!("{:?}", e)));
-        }
-        Ok(text)
-    }
-
     //function scapes price list publish date to validate if tis equal to required date,
     /// if price list is missing for tomorrow, page returns today price list
     fn parse_publish_date(html: &Html) -> Result<DateTime<Utc>, AppError> {
@@ -77,8 +104,9 @@ impl DayAheadMarketPriceListProvider {
             .and_then(parse_date)
     }
 
-    /// Function scrapes price from the price list html table
-    fn parse_price_list_table(html: &Html) -> Result<Vec<Currency>, AppError> {
+    /// Function scrapes price from the price list html table, prices are parsed exactly,
+    /// they could be negative or close to zero on sunny weekends
+    fn parse_price_list_table(html: &Html) -> Result<Vec<Money>, AppError> {
         let price_list_table_is_missing =
             || AppError::system_error("Price list table is missing on day ahead market page!");
         let selector = "#footable_kontrakty_godzinowe tbody tr td:nth-child(2)";
@@ -87,15 +115,13 @@ impl DayAheadMarketPriceListProvider {
             let result_list = html
                 .select(&selector)
                 .filter_map(|row| row.first_child().and_then(|td| td.value().as_text().map(|t| t.to_string())))
-                .map(|s| s.trim().to_owned())
-                .map(|s| s.replace(",", "."))
-                .map(|s| s.parse::<f32>())
-                .collect::<Vec<Result<f32, ParseFloatError>>>();
+                .map(|s| parse_price(&s))
+                .collect::<Result<Vec<Money>, AppError>>();
 
-            if result_list.iter().any(|i| i.is_err()) {
-                Err(AppError::system_error("Price list table has unparsable numbers!"))
-            } else {
-                Ok(result_list.into_iter().map(|f| (f.unwrap() * 100.0) as Currency).collect::<Vec<Currency>>())
+            match result_list {
+                Ok(prices) if prices.is_empty() => Err(price_list_table_is_missing()),
+                Ok(prices) => Ok(prices),
+                Err(_) => Err(AppError::system_error("Price list table has unparsable numbers!")),
             }
         })
     }
@@ -123,83 +149,83 @@ impl DayAheadMarketPriceListProvider {
         Ok(())
     }
 
-    /// Function converts list of 24 prices into price list items which covers entire day
-    fn convert_to_price_list_items(requested_date: &DateTime<Utc>, prices: Vec<Currency>) -> Vec<PriceListItem> {
-        use PriceCategory::*;
-        let minimal_price = 500;
-        let transfer_cost = 9000;
-        let price_category_min_threshold = 20000;
-        let price_category_max_threshold = 80000;
-
-        let reevaluate_price = |price| (if (price) < 5 { minimal_price } else { price }) + transfer_cost;
-        let evaluate_price_category = |price| {
-            if price < price_category_min_threshold {
-                Min
-            } else if price > price_category_max_threshold {
-                Max
-            } else {
-                Medium
-            }
-        };
-
-        prices
+    /// Function converts list of hourly prices into price list items which covers entire day,
+    /// day has 23 or 25 hours when time is changed, so number of prices has to match day length.
+    /// Prices are pure energy prices, distribution and other charges are added by ComposedPriceList
+    fn convert_to_price_list_items(
+        requested_date: &DateTime<Utc>,
+        prices: Vec<Money>,
+    ) -> Result<Vec<PriceListItem>, AppError> {
+        let day_hours = (next_day_start(requested_date) - *requested_date).num_hours();
+        if prices.len() as i64 != day_hours {
+            return Err(AppError::system_error(&format!(
+                "Price list table has {} prices but the day has {} hours!",
+                prices.len(),
+                day_hours
+            )));
+        }
+        Ok(prices
             .into_iter()
             .enumerate()
             .map(|(i, price)| {
-                let price = reevaluate_price(price);
                 let category = evaluate_price_category(price);
-
                 PriceListItem::new(*requested_date + TimeDelta::hours(i as i64), TimeDelta::hours(1), price, category)
             })
-            .collect()
+            .collect())
     }
 
     ///scraping logic
-    fn parse_price_list(requested_date: DateTime<Utc>) -> Result<Vec<PriceListItem>, AppError> {
+    async fn parse_price_list(&self, requested_date: DateTime<Utc>) -> Result<Vec<PriceListItem>, AppError> {
         let url = Self::get_day_ahead_market_url(requested_date);
-        let text = Self::fetch_price_list_text(url)?;
+        let text = self.page_fetcher.fetch_page(&url).await?;
+        Self::parse_price_list_text(requested_date, &text)
+    }
 
-        let html = Html::parse_document(&text);
+    fn parse_price_list_text(requested_date: DateTime<Utc>, text: &str) -> Result<Vec<PriceListItem>, AppError> {
+        let html = Html::parse_document(text);
         let publish_date = Self::parse_publish_date(&html);
         Self::validate_price_list_date(requested_date, publish_date)?;
 
-        Ok(Self::convert_to_price_list_items(&requested_date, Self::parse_price_list_table(&html)?))
-    }
-}
-
-impl Default for DayAheadMarketPriceListProvider {
-    fn default() -> Self {
-        Self::new()
+        Self::convert_to_price_list_items(&requested_date, Self::parse_price_list_table(&html)?)
     }
 }
 
+#[async_trait]
 impl SingleDayPriceList for DayAheadMarketPriceListProvider {
     /// Returns price list from the  cache if it is missing scapes web page for price list
     /// Moka cache keeps last 30 entries
-    fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<Vec<PriceListItem>>, AppError> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
         let for_day = cut_off_time_from_date(for_day);
 
-        if !self.cache.contains_key(&for_day) {
-            let price_list = Self::parse_price_list(for_day)?;
-            self.cache.insert(for_day, Arc::new(price_list));
-        }
-
         self.cache
-            .get(&for_day)
-            .ok_or(AppError::not_found(&format!("Price list for date {} is missing", for_day.format("%d-%m-%Y"))))
+            .try_get_with(for_day, async {
+                let price_list = self.parse_price_list(for_day).await?;
+                Ok::<_, AppError>(Arc::new(PriceList::new(for_day, DAY_AHEAD_MARKET_PROVIDER, Utc::now(), price_list)))
+            })
+            .await
+            .map_err(|e| (*e).clone())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use chrono::{DateTime, Local, TimeZone, Utc};
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
 
     use crate::{
         model::AppError,
-        price_list_providers::{commons::cut_off_time_from_date, DayAheadMarketPriceListProvider},
+        price_list_providers::{
+            commons::{create_http_client, cut_off_time_from_date, next_day_start},
+            DayAheadMarketPriceListProvider, SingleDayPriceList,
+        },
+        settings::HttpClientConfig,
     };
     use scraper::Html;
 
+    use super::DayAheadMarketPageFetcher;
+
     fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
         Local.with_ymd_and_hms(year, month, day, 0u32, 0u32, 0u32).map(|dt| dt.with_timezone(&Utc)).unwrap()
     }
@@ -253,19 +279,150 @@ mod tests {
                                             <td>skip 2</td>
                                             <td>111,00</td>
                                         </tr>
+                                        <tr>
+                                            <td>skip 3</td>
+                                            <td>-0,07</td>
+                                        </tr>
+                                        <tr>
+                                            <td>skip 4</td>
+                                            <td>0,03</td>
+                                        </tr>
                                     </tbody>
                                 </table>
                             </body>"#;
         let document = Html::parse_document(html);
 
-        let result = DayAheadMarketPriceListProvider::parse_price_list_table(&document).unwrap();
-        assert_eq!(result, vec![12300, 11100]);
+        let result: Vec<i64> = DayAheadMarketPriceListProvider::parse_price_list_table(&document)
+            .unwrap()
+            .iter()
+            .map(|price| price.legacy_price())
+            .collect();
+        assert_eq!(result, vec![12300, 11100, -7, 3]);
+    }
+
+    /// page fetcher which returns stored page and records requested urls
+    struct FixturePageFetcher {
+        page: &'static str,
+        requested_urls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl DayAheadMarketPageFetcher for FixturePageFetcher {
+        async fn fetch_page(&self, url: &str) -> Result<String, AppError> {
+            self.requested_urls.lock().unwrap().push(url.to_owned());
+            Ok(self.page.to_owned())
+        }
+    }
+
+    fn create_provider(page: &'static str) -> (DayAheadMarketPriceListProvider, Arc<Mutex<Vec<String>>>) {
+        let requested_urls = Arc::new(Mutex::new(Vec::new()));
+        let page_fetcher = FixturePageFetcher { page, requested_urls: requested_urls.clone() };
+        (DayAheadMarketPriceListProvider::with_page_fetcher(Box::new(page_fetcher)), requested_urls)
+    }
+
+    fn date_time(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, hour, 0, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn normal_day_price_list_should_be_scraped_from_the_page() {
+        let (provider, requested_urls) = create_provider(include_str!("../../tests/fixtures/tge/normal_day.html"));
+
+        let price_list = provider.get_price_list(&date_time(2024, 8, 26, 15)).await.unwrap();
+
+        assert_eq!(price_list.items().len(), 24);
+        assert_eq!(price_list.provider(), "DayAheadMarket");
+        assert_eq!(price_list.for_day(), &date(2024, 8, 26));
+        assert_eq!(price_list.metadata().published_at, None);
+        assert_eq!(price_list.items()[0].starts_at(), &date(2024, 8, 26));
+        assert_eq!(price_list.items()[0].price().legacy_price(), 41230);
+        assert_eq!(price_list.items()[12].price().legacy_price(), 3);
+        assert_eq!(price_list.items()[13].price().legacy_price(), -7);
+        assert_eq!(price_list.items()[23].starts_at(), &date_time(2024, 8, 26, 23));
+        assert_eq!(
+            *requested_urls.lock().unwrap(),
+            vec!["https://tge.pl/energia-elektryczna-rdn?dateShow=25-08-2024&dateAction=prev"]
+        );
+    }
+
+    #[tokio::test]
+    async fn price_list_should_be_fetched_once_and_next_taken_from_the_cache() {
+        let (provider, requested_urls) = create_provider(include_str!("../../tests/fixtures/tge/normal_day.html"));
+
+        provider.get_price_list(&date(2024, 8, 26)).await.unwrap();
+        provider.get_price_list(&date_time(2024, 8, 26, 20)).await.unwrap();
+
+        assert_eq!(requested_urls.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn day_with_time_change_should_have_25_hours() {
+        let (provider, _) = create_provider(include_str!("../../tests/fixtures/tge/dst_day.html"));
+
+        let price_list = provider.get_price_list(&date(2024, 10, 27)).await.unwrap();
+
+        assert_eq!(price_list.items().len(), 25);
+        // 02:00 happens twice, the second one is one hour after the first one
+        assert_eq!(*price_list.items()[3].starts_at() - *price_list.items()[2].starts_at(), TimeDelta::hours(1));
+        assert_eq!(price_list.items()[3].price().legacy_price(), 30555);
+        assert_eq!(*price_list.items()[24].starts_at() + *price_list.items()[24].duration(), date(2024, 10, 28));
     }
 
     #[test]
-    fn check_price_list_fetching() {
-        let price_list =
-            DayAheadMarketPriceListProvider::parse_price_list(cut_off_time_from_date(&Utc::now())).unwrap();
+    fn day_with_time_change_should_not_accept_24_prices() {
+        let text = include_str!("../../tests/fixtures/tge/normal_day.html").replace("26-08-2024", "27-10-2024");
+        match DayAheadMarketPriceListProvider::parse_price_list_text(date(2024, 10, 27), &text) {
+            Err(AppError::SystemError { message, code: _ }) => {
+                assert_eq!(message, "Price list table has 24 prices but the day has 25 hours!")
+            }
+            _ => panic!("Price list with missing hour should be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_price_list_table_should_be_reported() {
+        let (provider, _) = create_provider(include_str!("../../tests/fixtures/tge/missing_table.html"));
+
+        match provider.get_price_list(&date(2024, 8, 26)).await {
+            Err(AppError::SystemError { message, code: _ }) => {
+                assert_eq!(message, "Price list table is missing on day ahead market page!")
+            }
+            _ => panic!("Missing price list table should be reported as system error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn price_list_published_for_another_day_should_be_reported_as_missing() {
+        let (provider, _) = create_provider(include_str!("../../tests/fixtures/tge/wrong_publish_date.html"));
+
+        match provider.get_price_list(&date(2024, 8, 26)).await {
+            Err(AppError::UserError { message, code: _ }) => assert_eq!(
+                message,
+                "Missing price list for date: 26-08-2024, price lists are published for last 2 months!"
+            ),
+            _ => panic!("Price list for another day should be reported as not found"),
+        }
+    }
+
+    #[tokio::test]
+    async fn tomorrow_price_list_not_yet_published_should_be_reported_as_missing() {
+        //page without the publish date shows only the notice about publication time
+        let (provider, _) = create_provider(include_str!("../../tests/fixtures/tge/tomorrow_not_published.html"));
+        let tomorrow = next_day_start(&Utc::now());
+
+        match provider.get_price_list(&tomorrow).await {
+            Err(AppError::UserError { message, code: _ }) => {
+                assert!(message.ends_with(", for tomorrow price list is published at 2pm!"), "{}", message)
+            }
+            _ => panic!("Price list which is not yet published should be reported as not found"),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires access to tge.pl"]
+    async fn check_price_list_fetching() {
+        let provider = DayAheadMarketPriceListProvider::new(create_http_client(&HttpClientConfig::default()).unwrap());
+        let price_list = provider.parse_price_list(cut_off_time_from_date(&Utc::now())).await.unwrap();
         assert_eq!(price_list.len(), 24)
     }
 }
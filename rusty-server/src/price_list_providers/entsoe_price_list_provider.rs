@@ -0,0 +1,392 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta, Utc};
+use moka::future::Cache;
+use regex::Regex;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    model::{AppError, Money, PriceList, PriceListItem, CURRENCY},
+    settings::EntsoeConfig,
+};
+
+use super::{
+    commons::{cut_off_time_from_date, evaluate_price_category, next_day_start, parse_price},
+    SingleDayPriceList,
+};
+
+/// name of the provider reported in price list metadata
+const ENTSOE_PROVIDER: &str = "Entsoe";
+
+/// Root of the day ahead prices document (document type A44)
+#[derive(Deserialize, Debug)]
+struct PublicationMarketDocument {
+    #[serde(rename = "createdDateTime")]
+    created_date_time: Option<String>,
+    #[serde(rename = "TimeSeries", default)]
+    time_series: Vec<TimeSeries>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TimeSeries {
+    #[serde(rename = "currency_Unit.name")]
+    currency: String,
+    #[serde(rename = "Period", default)]
+    periods: Vec<Period>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Period {
+    #[serde(rename = "timeInterval")]
+    time_interval: TimeInterval,
+    resolution: String,
+    #[serde(rename = "Point", default)]
+    points: Vec<Point>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TimeInterval {
+    start: String,
+    end: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Point {
+    position: u32,
+    #[serde(rename = "price.amount")]
+    price_amount: String,
+}
+
+/// Document returned by the platform instead of prices, e.g. when there is no data for requested period
+#[derive(Deserialize, Debug)]
+struct AcknowledgementMarketDocument {
+    #[serde(rename = "Reason", default)]
+    reasons: Vec<Reason>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Reason {
+    text: String,
+}
+
+/// EntsoePriceListProvider fetches day ahead prices from the ENTSO-E transparency platform
+/// for the configured bidding zone and stores them in the moka cache like DayAheadMarketPriceListProvider.
+///
+/// Prices are published in currency per MWh, they are converted to money per kWh like prices from
+/// the polish day ahead market. Prices in EUR are converted to PLN with the configured exchange rate,
+/// documents in EUR are rejected when the rate is not configured, as well as documents in other currencies. Document periods could have 15 or 60 minutes resolution, if both are published
+/// the finer one is used. Curve type A03 skips positions which have the same price as previous position,
+/// such missing positions are filled with the previous price.
+/// Creation time of the document is reported as publication time of the price list.
+pub struct EntsoePriceListProvider {
+    config: EntsoeConfig,
+    http_client: reqwest::Client,
+    cache: Cache<DateTime<Utc>, Arc<PriceList>>,
+}
+
+impl EntsoePriceListProvider {
+    pub fn new(config: EntsoeConfig, http_client: reqwest::Client) -> Self {
+        Self { config, http_client, cache: Cache::new(30) }
+    }
+
+    fn get_entsoe_url(&self, requested_date: &DateTime<Utc>) -> String {
+        let period_format = "%Y%m%d%H%M";
+        format!(
+            "{}?securityToken={}&documentType=A44&in_Domain={}&out_Domain={}&periodStart={}&periodEnd={}",
+            self.config.base_url,
+            self.config.token,
+            self.config.bidding_zone,
+            self.config.bidding_zone,
+            requested_date.format(period_format),
+            next_day_start(requested_date).format(period_format)
+        )
+    }
+
+    /// platform answers with acknowledgement document and error status when there is no data,
+    /// so response body is returned regardless of the status
+    async fn fetch_price_list_text(&self, url: &str) -> Result<String, AppError> {
+        let request_failed = |e: reqwest::Error| {
+            if e.is_timeout() {
+                AppError::system_error(&format!("ENTSO-E request timed out: {}", e))
+            } else {
+                AppError::system_error(&format!("ENTSO-E request failed: {}", e))
+            }
+        };
+
+        self.http_client.get(url).send().await.map_err(request_failed)?.text().await.map_err(request_failed)
+    }
+
+    fn parse_time(time: &str) -> Result<DateTime<Utc>, AppError> {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%MZ")
+            .map(|date_time| date_time.and_utc())
+            .map_err(|_| AppError::system_error(&format!("ENTSO-E document has incorrect time: {}", time)))
+    }
+
+    /// resolution is ISO 8601 duration, platform uses PT15M, PT30M and PT60M
+    fn parse_resolution(resolution: &str) -> Result<TimeDelta, AppError> {
+        let re = Regex::new(r"^PT(\d+)([MH])$").unwrap(); //input is constant, save to  unwrap
+        re.captures(resolution)
+            .and_then(|captures| {
+                captures[1].parse::<i64>().ok().map(|value| match &captures[2] {
+                    "H" => TimeDelta::hours(value),
+                    _ => TimeDelta::minutes(value),
+                })
+            })
+            .filter(|resolution| *resolution > TimeDelta::zero())
+            .ok_or_else(|| {
+                AppError::system_error(&format!("ENTSO-E document has unsupported resolution: {}", resolution))
+            })
+    }
+
+    /// exchange rate to PLN of the document currency
+    fn exchange_rate(currency: &str, eur_exchange_rate: Option<Decimal>) -> Result<Decimal, AppError> {
+        match currency {
+            CURRENCY => Ok(Decimal::ONE),
+            "EUR" => eur_exchange_rate.ok_or_else(|| {
+                AppError::system_error("ENTSO-E prices are published in EUR but eur_exchange_rate is not configured")
+            }),
+            currency => {
+                Err(AppError::system_error(&format!("ENTSO-E document has unsupported currency: {}", currency)))
+            }
+        }
+    }
+
+    /// Function converts single period into price list items, one item per resolution step,
+    /// missing positions get price from the previous position, prices are converted with the exchange rate
+    fn convert_period_to_price_list_items(
+        period: &Period,
+        exchange_rate: Decimal,
+    ) -> Result<Vec<PriceListItem>, AppError> {
+        let start = Self::parse_time(&period.time_interval.start)?;
+        let end = Self::parse_time(&period.time_interval.end)?;
+        let resolution = Self::parse_resolution(&period.resolution)?;
+        let prices: HashMap<u32, Money> = period
+            .points
+            .iter()
+            .map(|point| {
+                parse_price(&point.price_amount)
+                    .map(|price| (point.position, Money::new(price.amount() * exchange_rate)))
+            })
+            .collect::<Result<_, AppError>>()?;
+
+        let positions = ((end - start).num_minutes() / resolution.num_minutes()) as u32;
+        let mut price_list_items = Vec::with_capacity(positions as usize);
+        let mut previous_price: Option<Money> = None;
+        for position in 1..=positions {
+            let price = prices.get(&position).copied().or(previous_price).ok_or_else(|| {
+                AppError::system_error(&format!("ENTSO-E period starting at {} has no price for first position", start))
+            })?;
+            previous_price = Some(price);
+
+            price_list_items.push(PriceListItem::new(
+                start + resolution * (position as i32 - 1),
+                resolution,
+                price,
+                evaluate_price_category(price),
+            ));
+        }
+        Ok(price_list_items)
+    }
+
+    fn parse_acknowledgement(text: &str) -> AppError {
+        let reason = quick_xml::de::from_str::<AcknowledgementMarketDocument>(text)
+            .ok()
+            .map(|document| document.reasons.into_iter().map(|reason| reason.text).collect::<Vec<_>>().join(", "))
+            .unwrap_or_default();
+        AppError::not_found(&format!("ENTSO-E has no price list: {}", reason))
+    }
+
+    /// Function parses publication market document and selects price list items for the requested day
+    fn parse_price_list(
+        requested_date: &DateTime<Utc>,
+        text: &str,
+        eur_exchange_rate: Option<Decimal>,
+    ) -> Result<PriceList, AppError> {
+        if text.contains("Acknowledgement_MarketDocument") {
+            return Err(Self::parse_acknowledgement(text));
+        }
+
+        let document = quick_xml::de::from_str::<PublicationMarketDocument>(text)
+            .map_err(|e| AppError::system_error(&format!("ENTSO-E document is not parsable: {}", e)))?;
+        let periods = document
+            .time_series
+            .iter()
+            .flat_map(|time_series| time_series.periods.iter().map(|period| (time_series.currency.as_str(), period)));
+
+        let mut finest_resolution: Option<TimeDelta> = None;
+        let mut price_list_items: Vec<PriceListItem> = Vec::new();
+        for (currency, period) in periods {
+            let exchange_rate = Self::exchange_rate(currency, eur_exchange_rate)?;
+            let resolution = Self::parse_resolution(&period.resolution)?;
+            if finest_resolution.is_some_and(|finest_resolution| finest_resolution < resolution) {
+                continue;
+            }
+            if finest_resolution.is_some_and(|finest_resolution| finest_resolution > resolution) {
+                price_list_items.clear();
+            }
+            finest_resolution = Some(resolution);
+            price_list_items.extend(Self::convert_period_to_price_list_items(period, exchange_rate)?);
+        }
+
+        let day_end = next_day_start(requested_date);
+        price_list_items.retain(|item| item.starts_at() >= requested_date && *item.starts_at() < day_end);
+        price_list_items.sort_by(|a, b| a.starts_at().cmp(b.starts_at()));
+        price_list_items.dedup_by(|a, b| a.starts_at() == b.starts_at());
+
+        if price_list_items.is_empty() {
+            return Err(AppError::not_found(&format!(
+                "Missing price list for date: {}",
+                requested_date.with_timezone(&Local).format("%d-%m-%Y")
+            )));
+        }
+        let mut price_list = PriceList::new(*requested_date, ENTSOE_PROVIDER, Utc::now(), price_list_items);
+        price_list.set_published_at(
+            document
+                .created_date_time
+                .and_then(|created| DateTime::parse_from_rfc3339(&created).ok())
+                .map(|created| created.with_timezone(&Utc)),
+        );
+        Ok(price_list)
+    }
+}
+
+#[async_trait]
+impl SingleDayPriceList for EntsoePriceListProvider {
+    /// Returns price list from the cache if it is missing fetches it from the transparency platform,
+    /// concurrent requests for the same missing day share single fetch
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        let for_day = cut_off_time_from_date(for_day);
+
+        self.cache
+            .try_get_with(for_day, async {
+                let text = self.fetch_price_list_text(&self.get_entsoe_url(&for_day)).await?;
+                Self::parse_price_list(&for_day, &text, self.config.eur_exchange_rate).map(Arc::new)
+            })
+            .await
+            .map_err(|e| (*e).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+    use rust_decimal::Decimal;
+
+    use crate::{
+        model::AppError,
+        price_list_providers::commons::create_http_client,
+        settings::{EntsoeConfig, HttpClientConfig},
+    };
+
+    use super::EntsoePriceListProvider;
+
+    fn date_time(year: i32, month: u32, day: u32, hour: u32, min: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, hour, min, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        date_time(year, month, day, 0, 0)
+    }
+
+    fn eur_exchange_rate() -> Option<Decimal> {
+        Some(Decimal::new(4, 0))
+    }
+
+    #[test]
+    fn check_entsoe_url() {
+        let provider = EntsoePriceListProvider::new(
+            EntsoeConfig {
+                base_url: "https://web-api.tp.entsoe.eu/api".to_owned(),
+                bidding_zone: "10YPL-AREA-----S".to_owned(),
+                token: "token".to_owned(),
+                eur_exchange_rate: None,
+            },
+            create_http_client(&HttpClientConfig::default()).unwrap(),
+        );
+        assert_eq!(
+            provider.get_entsoe_url(&date(2024, 1, 15)),
+            "https://web-api.tp.entsoe.eu/api?securityToken=token&documentType=A44&in_Domain=10YPL-AREA-----S\
+            &out_Domain=10YPL-AREA-----S&periodStart=202401142300&periodEnd=202401152300"
+        );
+    }
+
+    #[test]
+    fn parse_hourly_price_list() {
+        let text = include_str!("../../tests/fixtures/entsoe/day_ahead_60m.xml");
+        let price_list =
+            EntsoePriceListProvider::parse_price_list(&date(2024, 1, 15), text, eur_exchange_rate()).unwrap();
+
+        assert_eq!(price_list.items().len(), 24);
+        assert_eq!(price_list.provider(), "Entsoe");
+        assert_eq!(price_list.metadata().published_at, Some(date_time(2024, 1, 14, 13, 47) + TimeDelta::seconds(23)));
+        assert_eq!(price_list.items()[0].starts_at(), &date(2024, 1, 15));
+        assert_eq!(price_list.items()[0].duration(), &TimeDelta::hours(1));
+        assert_eq!(price_list.items()[0].price().legacy_price(), 35008);
+        assert_eq!(price_list.items()[23].starts_at(), &date_time(2024, 1, 15, 23, 0));
+        assert_eq!(price_list.items()[23].price().legacy_price(), 37240);
+    }
+
+    #[test]
+    fn parse_quarter_hour_price_list_with_missing_positions() {
+        let text = include_str!("../../tests/fixtures/entsoe/day_ahead_15m_missing_positions.xml");
+        let price_list =
+            EntsoePriceListProvider::parse_price_list(&date(2024, 1, 15), text, eur_exchange_rate()).unwrap();
+
+        assert_eq!(price_list.items().len(), 96, "finer resolution should be selected");
+        assert!(price_list.items().iter().all(|item| *item.duration() == TimeDelta::minutes(15)));
+        assert_eq!(price_list.items()[1].starts_at(), &date_time(2024, 1, 15, 0, 15));
+        //positions 2 and 3 are missing in the document
+        assert_eq!(price_list.items()[0].price().legacy_price(), 30048);
+        assert_eq!(price_list.items()[1].price().legacy_price(), 30048);
+        assert_eq!(price_list.items()[2].price().legacy_price(), 30048);
+        assert_eq!(price_list.items()[3].price().legacy_price(), -492);
+        //positions 95 and 96 are missing at the end of period
+        assert_eq!(price_list.items()[95].price(), price_list.items()[93].price());
+    }
+
+    #[test]
+    fn parse_short_day_price_list() {
+        let text = include_str!("../../tests/fixtures/entsoe/day_ahead_60m_dst.xml");
+        let price_list =
+            EntsoePriceListProvider::parse_price_list(&date(2024, 3, 31), text, eur_exchange_rate()).unwrap();
+
+        assert_eq!(price_list.items().len(), 23);
+        assert_eq!(price_list.items()[2].starts_at(), &date_time(2024, 3, 31, 3, 0));
+    }
+
+    #[test]
+    fn parse_acknowledgement_should_report_not_found() {
+        let text = include_str!("../../tests/fixtures/entsoe/acknowledgement_no_data.xml");
+        match EntsoePriceListProvider::parse_price_list(&date(2024, 1, 15), text, eur_exchange_rate()) {
+            Err(AppError::UserError { message, code: _ }) => {
+                assert_eq!(message, "ENTSO-E has no price list: No matching data found for Data item Day-ahead Prices")
+            }
+            _ => panic!("Acknowledgement document should be reported as not found"),
+        }
+    }
+
+    #[test]
+    fn parse_document_for_another_day_should_report_missing_price_list() {
+        let text = include_str!("../../tests/fixtures/entsoe/day_ahead_60m.xml");
+        assert!(EntsoePriceListProvider::parse_price_list(&date(2024, 1, 17), text, eur_exchange_rate()).is_err());
+    }
+
+    #[test]
+    fn parse_price_list_in_pln_should_keep_prices() {
+        let text = include_str!("../../tests/fixtures/entsoe/day_ahead_60m.xml").replace(">EUR<", ">PLN<");
+        let price_list = EntsoePriceListProvider::parse_price_list(&date(2024, 1, 15), &text, None).unwrap();
+
+        assert_eq!(price_list.items()[0].price().legacy_price(), 8752);
+    }
+
+    #[test]
+    fn parse_price_list_in_eur_without_exchange_rate_should_fail() {
+        let text = include_str!("../../tests/fixtures/entsoe/day_ahead_60m.xml");
+        assert!(EntsoePriceListProvider::parse_price_list(&date(2024, 1, 15), text, None).is_err());
+
+        let text = text.replace(">EUR<", ">CZK<");
+        assert!(EntsoePriceListProvider::parse_price_list(&date(2024, 1, 15), &text, eur_exchange_rate()).is_err());
+    }
+}
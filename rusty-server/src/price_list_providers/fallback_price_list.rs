@@ -0,0 +1,301 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+
+use crate::model::{AppError, PriceList, PriceListSourceStatus};
+
+use super::SingleDayPriceList;
+
+/// number of consecutive failures after which source is reported as repeatedly failing,
+/// price list which is not published yet is not a failure of the source
+const REPEATED_FAILURES_THRESHOLD: u32 = 3;
+
+/// PriceListSourcesMonitor collects successes and failures of price list sources,
+/// it is shared with application state to report sources health through the API
+#[derive(Default)]
+pub struct PriceListSourcesMonitor {
+    statuses: Mutex<Vec<PriceListSourceStatus>>,
+}
+
+impl PriceListSourcesMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update_status(&self, source: &str, update: impl FnOnce(&mut PriceListSourceStatus)) {
+        let mut statuses = self.statuses.lock().unwrap();
+        let index = match statuses.iter().position(|status| status.name == source) {
+            Some(index) => index,
+            None => {
+                statuses.push(PriceListSourceStatus {
+                    name: source.to_owned(),
+                    consecutive_failures: 0,
+                    total_failures: 0,
+                    repeatedly_failing: false,
+                    last_error: None,
+                    last_failure_at: None,
+                    last_success_at: None,
+                });
+                statuses.len() - 1
+            }
+        };
+        update(&mut statuses[index]);
+    }
+
+    pub fn record_success(&self, source: &str, now: DateTime<Utc>) {
+        self.update_status(source, |status| {
+            status.consecutive_failures = 0;
+            status.repeatedly_failing = false;
+            status.last_success_at = Some(now);
+        });
+    }
+
+    pub fn record_failure(&self, source: &str, error: &AppError, now: DateTime<Utc>) {
+        self.update_status(source, |status| {
+            status.last_error = Some(error.to_string());
+            if error.code() == StatusCode::NOT_FOUND {
+                return;
+            }
+            status.consecutive_failures += 1;
+            status.total_failures += 1;
+            status.repeatedly_failing = status.consecutive_failures >= REPEATED_FAILURES_THRESHOLD;
+            status.last_failure_at = Some(now);
+            if status.repeatedly_failing {
+                tracing::warn!("Price list source {} failed {} times: {}", source, status.consecutive_failures, error);
+            }
+        });
+    }
+
+    pub fn get_statuses(&self) -> Vec<PriceListSourceStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+}
+
+/// FallbackPriceList asks configured price list sources in order and returns the first price list which is found,
+/// e.g. when day ahead market page layout changes ENTSO-E prices are used.
+/// Each price list item is marked with the name of the source which answered,
+/// price list served by other than the first source has a warning about it.
+/// If none of the sources answers, errors of all of them are reported.
+/// Sources are tariffs only, archived, uploaded or forecast price lists are not a part of the chain.
+pub struct FallbackPriceList {
+    sources: Vec<(String, Box<dyn SingleDayPriceList>)>,
+    monitor: Arc<PriceListSourcesMonitor>,
+}
+
+impl FallbackPriceList {
+    pub fn new(sources: Vec<(String, Box<dyn SingleDayPriceList>)>, monitor: Arc<PriceListSourcesMonitor>) -> Self {
+        Self { sources, monitor }
+    }
+}
+
+#[async_trait]
+impl SingleDayPriceList for FallbackPriceList {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        let mut errors: Vec<(String, AppError)> = Vec::new();
+        for (name, source) in &self.sources {
+            match source.get_price_list(for_day).await {
+                Ok(price_list) => {
+                    self.monitor.record_success(name, Utc::now());
+                    let mut price_list = price_list.with_items(
+                        price_list
+                            .items()
+                            .iter()
+                            .map(|item| {
+                                let mut item = item.clone();
+                                item.set_source(Some(name.clone()));
+                                item
+                            })
+                            .collect(),
+                    );
+                    if let Some((first_name, _)) = errors.first() {
+                        price_list
+                            .add_warning(format!("Price list of {} is missing, it is served by {}", first_name, name));
+                    }
+                    return Ok(Arc::new(price_list));
+                }
+                Err(error) => {
+                    self.monitor.record_failure(name, &error, Utc::now());
+                    errors.push((name.clone(), error));
+                }
+            }
+        }
+
+        let message = format!(
+            "None of price list sources answered: {}",
+            errors.iter().map(|(name, error)| format!("{}: {}", name, error)).collect::<Vec<_>>().join("; ")
+        );
+        if errors.iter().all(|(_, error)| error.code() == StatusCode::NOT_FOUND) {
+            Err(AppError::not_found(&message))
+        } else {
+            Err(AppError::system_error(&message))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Local, TimeZone, Utc};
+
+    use crate::{
+        model::{AppError, PriceList},
+        price_list_providers::{SingleDayPriceList, TimeOfUsePriceListProvider},
+    };
+
+    use super::{FallbackPriceList, PriceListSourcesMonitor};
+
+    struct FailingPriceList {
+        not_found: bool,
+    }
+
+    #[async_trait]
+    impl SingleDayPriceList for FailingPriceList {
+        async fn get_price_list(&self, _for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+            if self.not_found {
+                Err(AppError::not_found("Missing price list"))
+            } else {
+                Err(AppError::system_error("Price list table is missing on day ahead market page!"))
+            }
+        }
+    }
+
+    /// price list source which fails for the first `failures` calls
+    struct FlakyPriceList {
+        failures: u32,
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl SingleDayPriceList for FlakyPriceList {
+        async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(AppError::system_error("Day ahead market is down"))
+            } else {
+                TimeOfUsePriceListProvider::w12().get_price_list(for_day).await
+            }
+        }
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, 0, 0, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_return_price_list_from_first_answering_source() {
+        let monitor = Arc::new(PriceListSourcesMonitor::new());
+        let fallback_price_list = FallbackPriceList::new(
+            vec![
+                ("DayAheadMarket".to_owned(), Box::new(FailingPriceList { not_found: false })),
+                ("W12".to_owned(), Box::new(TimeOfUsePriceListProvider::w12())),
+            ],
+            monitor.clone(),
+        );
+
+        let price_list = fallback_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+        assert_eq!(price_list.items().len(), 24);
+        assert!(price_list.items().iter().all(|item| item.source() == Some("W12")));
+        assert_eq!(price_list.warnings(), ["Price list of DayAheadMarket is missing, it is served by W12"]);
+
+        let statuses = monitor.get_statuses();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].name, "DayAheadMarket");
+        assert_eq!(statuses[0].consecutive_failures, 1);
+        assert_eq!(statuses[1].name, "W12");
+        assert!(statuses[1].last_success_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_should_be_reported() {
+        let monitor = Arc::new(PriceListSourcesMonitor::new());
+        let fallback_price_list = FallbackPriceList::new(
+            vec![
+                ("DayAheadMarket".to_owned(), Box::new(FailingPriceList { not_found: false })),
+                ("W12".to_owned(), Box::new(TimeOfUsePriceListProvider::w12())),
+            ],
+            monitor.clone(),
+        );
+
+        for _ in 0..3 {
+            fallback_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+        }
+        let status = &monitor.get_statuses()[0];
+        assert_eq!(status.consecutive_failures, 3);
+        assert!(status.repeatedly_failing);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("[SystemError] Price list table is missing on day ahead market page!")
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_price_list_should_not_be_counted_as_failure() {
+        let monitor = Arc::new(PriceListSourcesMonitor::new());
+        let fallback_price_list = FallbackPriceList::new(
+            vec![
+                ("DayAheadMarket".to_owned(), Box::new(FailingPriceList { not_found: true })),
+                ("W12".to_owned(), Box::new(TimeOfUsePriceListProvider::w12())),
+            ],
+            monitor.clone(),
+        );
+
+        for _ in 0..3 {
+            fallback_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+        }
+        let status = &monitor.get_statuses()[0];
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.total_failures, 0);
+        assert!(!status.repeatedly_failing);
+        assert_eq!(status.last_error.as_deref(), Some("[UserError] Missing price list"));
+    }
+
+    #[tokio::test]
+    async fn consecutive_failures_should_be_reset_by_success() {
+        let monitor = Arc::new(PriceListSourcesMonitor::new());
+        let fallback_price_list = FallbackPriceList::new(
+            vec![
+                ("DayAheadMarket".to_owned(), Box::new(FlakyPriceList { failures: 3, calls: AtomicU32::new(0) })),
+                ("W12".to_owned(), Box::new(TimeOfUsePriceListProvider::w12())),
+            ],
+            monitor.clone(),
+        );
+
+        for _ in 0..3 {
+            fallback_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+        }
+        assert!(monitor.get_statuses()[0].repeatedly_failing);
+
+        let price_list = fallback_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+        assert!(price_list.warnings().is_empty());
+        let status = &monitor.get_statuses()[0];
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.total_failures, 3);
+        assert!(!status.repeatedly_failing);
+    }
+
+    #[tokio::test]
+    async fn should_report_not_found_when_all_sources_miss_price_list() {
+        let fallback_price_list = FallbackPriceList::new(
+            vec![
+                ("DayAheadMarket".to_owned(), Box::new(FailingPriceList { not_found: true })),
+                ("Entsoe".to_owned(), Box::new(FailingPriceList { not_found: true })),
+            ],
+            Arc::new(PriceListSourcesMonitor::new()),
+        );
+
+        match fallback_price_list.get_price_list(&date(2024, 8, 26)).await {
+            Err(AppError::UserError { message, code: _ }) => assert_eq!(
+                message,
+                "None of price list sources answered: DayAheadMarket: [UserError] Missing price list; \
+                Entsoe: [UserError] Missing price list"
+            ),
+            _ => panic!("Missing price list should be reported as user error"),
+        }
+    }
+}
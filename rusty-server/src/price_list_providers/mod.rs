@@ -1,13 +1,42 @@
+mod categorized_price_list;
 mod commons;
+mod composed_price_list;
 mod day_ahead_market_price_list_provider;
+mod entsoe_price_list_provider;
+mod fallback_price_list;
+mod price_list_archive;
+mod price_list_export;
+mod price_list_overrides;
+mod price_list_prefetcher;
 mod tariff_selector;
+mod time_of_use_price_list_provider;
 mod time_period_price_list_service;
-mod w12_price_list_provider;
+mod validated_price_list;
 
+pub use self::categorized_price_list::CategorizedPriceList;
+pub use self::commons::create_http_client;
+pub use self::commons::next_day_start;
 pub use self::commons::parse_date;
+pub use self::commons::SharedSingleDayPriceList;
 pub use self::commons::SingleDayPriceList;
+pub use self::composed_price_list::ComposedPriceList;
+pub use self::day_ahead_market_price_list_provider::DayAheadMarketPageFetcher;
 pub use self::day_ahead_market_price_list_provider::DayAheadMarketPriceListProvider;
+pub use self::day_ahead_market_price_list_provider::HttpDayAheadMarketPageFetcher;
+pub use self::entsoe_price_list_provider::EntsoePriceListProvider;
+pub use self::fallback_price_list::FallbackPriceList;
+pub use self::fallback_price_list::PriceListSourcesMonitor;
+pub use self::price_list_archive::ArchivedPriceList;
+pub use self::price_list_archive::PriceListArchive;
+pub use self::price_list_export::price_list_to_csv;
+pub use self::price_list_export::price_list_to_icalendar;
+pub use self::price_list_export::PriceListFormat;
+pub use self::price_list_overrides::OverriddenPriceList;
+pub use self::price_list_overrides::PriceListOverrides;
+pub use self::price_list_prefetcher::PriceListPrefetcher;
+pub use self::tariff_selector::create_single_day_price_list;
 pub use self::tariff_selector::TariffSelector;
 pub use self::tariff_selector::TariffTypes;
+pub use self::time_of_use_price_list_provider::TimeOfUsePriceListProvider;
 pub use self::time_period_price_list_service::TimePeriodPriceListService;
-pub use self::w12_price_list_provider::W12PriceListProvider;
+pub use self::validated_price_list::ValidatedPriceList;
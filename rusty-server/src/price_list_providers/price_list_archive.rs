@@ -0,0 +1,357 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    model::{AppError, Money, PriceCategory, PriceList, PriceListBackfillReport, PriceListItem, PriceListMetadata},
+    settings::PriceListArchiveConfig,
+};
+
+use super::{commons::cut_off_time_from_date, SingleDayPriceList};
+
+/// Archived price list item keeps data fetched from the source, weights are calculated by the planner
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ArchivedPriceListItem {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    starts_at: DateTime<Utc>,
+    #[serde(serialize_with = "crate::model::serialize_time_delta")]
+    #[serde(deserialize_with = "crate::model::deserialize_time_delta")]
+    duration: TimeDelta,
+    price: Money,
+    category: PriceCategory,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    warnings: Vec<String>,
+}
+
+/// Archived day keeps price list metadata next to its items
+#[derive(Serialize, Deserialize, Debug)]
+struct ArchivedDay {
+    #[serde(flatten)]
+    metadata: PriceListMetadata,
+    items: Vec<ArchivedPriceListItem>,
+}
+
+/// PriceListArchive keeps every fetched day on disk, price list of each source is stored in its own directory
+/// as `<directory>/<source>/<yyyy-mm-dd>.json`, where date is local date of the day.
+/// Relative directory is resolved against the working directory at startup, so the archive does not move
+/// when the working directory changes later.
+/// Day ahead prices do not change after publication, so archived day is final.
+pub struct PriceListArchive {
+    directory: PathBuf,
+    backfill_days: u32,
+}
+
+impl PriceListArchive {
+    pub fn new(config: &PriceListArchiveConfig) -> Self {
+        let directory = std::path::absolute(&config.directory).unwrap_or_else(|_| PathBuf::from(&config.directory));
+        tracing::info!("Price lists are archived in {}", directory.display());
+        Self { directory, backfill_days: config.backfill_days }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// number of days walked back by backfill when it is not given
+    pub fn backfill_days(&self) -> u32 {
+        self.backfill_days
+    }
+
+    fn day_path(&self, source: &str, for_day: &DateTime<Utc>) -> PathBuf {
+        let day = cut_off_time_from_date(for_day).with_timezone(&Local);
+        self.directory.join(source).join(format!("{}.json", day.format("%Y-%m-%d")))
+    }
+
+    /// unreadable archive file is reported and treated as missing, so the day is fetched again
+    pub async fn load(&self, source: &str, for_day: &DateTime<Utc>) -> Option<PriceList> {
+        let path = self.day_path(source, for_day);
+        let text = tokio::fs::read_to_string(&path).await.ok()?;
+        match serde_json::from_str::<ArchivedDay>(&text) {
+            Ok(ArchivedDay { metadata, items }) => {
+                let items = items
+                    .into_iter()
+                    .map(|item| {
+                        let mut price_list_item =
+                            PriceListItem::new(item.starts_at, item.duration, item.price, item.category);
+                        price_list_item.set_source(item.source);
+                        price_list_item.set_warnings(item.warnings);
+                        price_list_item
+                    })
+                    .collect();
+                Some(PriceList::from_metadata(metadata, items))
+            }
+            Err(error) => {
+                tracing::warn!("Archived price list {} can not be read: {}", path.display(), error);
+                None
+            }
+        }
+    }
+
+    /// file is written next to its final location and renamed, so a half written day is never loaded
+    pub async fn store(&self, source: &str, for_day: &DateTime<Utc>, price_list: &PriceList) -> Result<(), AppError> {
+        let path = self.day_path(source, for_day);
+        let archive_failed = |error: std::io::Error| {
+            AppError::system_error(&format!("Price list archive {} failed: {}", path.display(), error))
+        };
+        let items: Vec<ArchivedPriceListItem> = price_list
+            .items()
+            .iter()
+            .map(|item| ArchivedPriceListItem {
+                starts_at: *item.starts_at(),
+                duration: *item.duration(),
+                price: item.price(),
+                category: item.category().clone(),
+                source: item.source().map(str::to_owned),
+                warnings: item.warnings().to_vec(),
+            })
+            .collect();
+        let archived_day = ArchivedDay { metadata: price_list.metadata().clone(), items };
+        let text = serde_json::to_string_pretty(&archived_day)
+            .map_err(|error| AppError::system_error(&format!("Price list can not be archived: {}", error)))?;
+
+        tokio::fs::create_dir_all(path.parent().unwrap_or(Path::new("."))).await.map_err(archive_failed)?;
+        let temporary_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temporary_path, text).await.map_err(archive_failed)?;
+        tokio::fs::rename(&temporary_path, &path).await.map_err(archive_failed)
+    }
+
+    /// day is archived if any source has stored it
+    pub async fn is_archived(&self, for_day: &DateTime<Utc>) -> bool {
+        let file_name = self.day_path("", for_day).file_name().map(|name| name.to_owned());
+        let Ok(mut sources) = tokio::fs::read_dir(&self.directory).await else {
+            return false;
+        };
+        while let Ok(Some(source)) = sources.next_entry().await {
+            if let Some(file_name) = &file_name {
+                if tokio::fs::try_exists(source.path().join(file_name)).await.unwrap_or(false) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Function walks back from the day before `today` through the available history, days which are not archived yet
+    /// are fetched by the given price list, so they are stored by its archived sources.
+    /// Price list has to be the tariff price list below the overrides, overridden days would not be archived otherwise.
+    /// Walk stops at the first day which could not be fetched, usually it is the end of history served by the source.
+    pub async fn backfill(
+        &self,
+        price_list: &dyn SingleDayPriceList,
+        today: &DateTime<Utc>,
+        days: u32,
+    ) -> PriceListBackfillReport {
+        let mut report = PriceListBackfillReport {
+            requested_days: days,
+            fetched_days: 0,
+            already_archived_days: 0,
+            stopped_at: None,
+            error: None,
+        };
+        let mut day = cut_off_time_from_date(today);
+        for _ in 0..days {
+            day = cut_off_time_from_date(&(day - TimeDelta::hours(12)));
+            if self.is_archived(&day).await {
+                report.already_archived_days += 1;
+                continue;
+            }
+            match price_list.get_price_list(&day).await {
+                Ok(_) if self.is_archived(&day).await => report.fetched_days += 1,
+                Ok(_) => {
+                    report.stopped_at = Some(day);
+                    report.error = Some("Price list of the configured tariff is not archived".to_owned());
+                    break;
+                }
+                Err(error) => {
+                    tracing::info!(
+                        "Price list backfill stopped at {}: {}",
+                        day.with_timezone(&Local).format("%d-%m-%Y"),
+                        error
+                    );
+                    report.stopped_at = Some(day);
+                    report.error = Some(error.to_string());
+                    break;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// ArchivedPriceList serves archived days from the archive before any remote call is made,
+/// days fetched by the wrapped price list are stored in the archive,
+/// failure of the archive is reported but it does not fail the price list
+pub struct ArchivedPriceList<T: SingleDayPriceList> {
+    price_list: T,
+    archive: Arc<PriceListArchive>,
+    source: String,
+}
+
+impl<T: SingleDayPriceList> ArchivedPriceList<T> {
+    pub fn new(price_list: T, archive: Arc<PriceListArchive>, source: &str) -> Self {
+        Self { price_list, archive, source: source.to_owned() }
+    }
+}
+
+#[async_trait]
+impl<T: SingleDayPriceList> SingleDayPriceList for ArchivedPriceList<T> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        if let Some(price_list) = self.archive.load(&self.source, for_day).await {
+            return Ok(Arc::new(price_list));
+        }
+        let price_list = self.price_list.get_price_list(for_day).await?;
+        if let Err(error) = self.archive.store(&self.source, for_day, &price_list).await {
+            tracing::warn!("{}", error);
+        }
+        Ok(price_list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Local, TimeZone, Utc};
+
+    use crate::{
+        model::{AppError, PriceList, PriceListStatus},
+        price_list_providers::{
+            OverriddenPriceList, PriceListOverrides, SharedSingleDayPriceList, SingleDayPriceList,
+            TimeOfUsePriceListProvider,
+        },
+        settings::PriceListArchiveConfig,
+    };
+
+    use super::{ArchivedPriceList, PriceListArchive};
+
+    /// price list which has history since the given day and counts remote calls
+    struct CountingPriceList {
+        history_since: DateTime<Utc>,
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl SingleDayPriceList for CountingPriceList {
+        async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if *for_day < self.history_since {
+                return Err(AppError::not_found("Missing price list"));
+            }
+            TimeOfUsePriceListProvider::w12().get_price_list(for_day).await
+        }
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, 0, 0, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    fn create_archive() -> Arc<PriceListArchive> {
+        let directory = std::env::temp_dir().join(format!("price-list-archive-{}", uuid::Uuid::new_v4()));
+        Arc::new(PriceListArchive::new(&PriceListArchiveConfig {
+            enabled: true,
+            directory: directory.to_string_lossy().into_owned(),
+            backfill_days: 62,
+        }))
+    }
+
+    fn create_archived_price_list(
+        archive: Arc<PriceListArchive>,
+        history_since: DateTime<Utc>,
+    ) -> ArchivedPriceList<CountingPriceList> {
+        ArchivedPriceList::new(CountingPriceList { history_since, calls: AtomicU32::new(0) }, archive, "DayAheadMarket")
+    }
+
+    #[tokio::test]
+    async fn archived_day_should_be_served_without_remote_call() {
+        let archive = create_archive();
+        let archived_price_list = create_archived_price_list(archive.clone(), date(2024, 1, 1));
+
+        let fetched = archived_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+        let archived = archived_price_list.get_price_list(&date(2024, 8, 26)).await.unwrap();
+
+        assert_eq!(archived_price_list.price_list.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(fetched.items(), archived.items());
+        assert_eq!(fetched.version(), archived.version());
+        assert_eq!(archived.provider(), "W12");
+        assert!(archive.is_archived(&date(2024, 8, 26)).await);
+        assert!(!archive.is_archived(&date(2024, 8, 27)).await);
+    }
+
+    #[tokio::test]
+    async fn archived_day_should_survive_restart() {
+        let archive = create_archive();
+        create_archived_price_list(archive.clone(), date(2024, 1, 1)).get_price_list(&date(2024, 3, 31)).await.unwrap();
+
+        let restarted_archive = Arc::new(PriceListArchive { directory: archive.directory.clone(), backfill_days: 62 });
+        let price_list = restarted_archive.load("DayAheadMarket", &date(2024, 3, 31)).await.unwrap();
+
+        assert_eq!(price_list.items().len(), 23);
+        assert!(restarted_archive.load("Entsoe", &date(2024, 3, 31)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn backfill_should_walk_back_until_history_ends() {
+        let archive = create_archive();
+        let archived_price_list = create_archived_price_list(archive.clone(), date(2024, 8, 20));
+        archived_price_list.get_price_list(&date(2024, 8, 24)).await.unwrap();
+
+        let report = archive.backfill(&archived_price_list, &date(2024, 8, 26), 30).await;
+
+        assert_eq!(report.fetched_days, 5);
+        assert_eq!(report.already_archived_days, 1);
+        assert_eq!(report.stopped_at, Some(date(2024, 8, 19)));
+        assert!(archive.is_archived(&date(2024, 8, 20)).await);
+        assert!(!archive.is_archived(&date(2024, 8, 26)).await, "backfill starts from yesterday");
+    }
+
+    #[tokio::test]
+    async fn backfill_should_archive_days_overridden_in_the_application_price_list() {
+        let archive = create_archive();
+        let tariff_price_list: SharedSingleDayPriceList =
+            Arc::new(create_archived_price_list(archive.clone(), date(2024, 8, 20)));
+        let price_list_overrides = Arc::new(PriceListOverrides::new());
+        let overridden_day = TimeOfUsePriceListProvider::w12().get_price_list(&date(2024, 8, 25)).await.unwrap();
+        price_list_overrides
+            .set_override(&date(2024, 8, 25), overridden_day.items().to_vec(), PriceListStatus::Final)
+            .unwrap();
+        let application_price_list = OverriddenPriceList::new(tariff_price_list.clone(), price_list_overrides);
+
+        let report = archive.backfill(&application_price_list, &date(2024, 8, 26), 1).await;
+        assert_eq!(report.fetched_days, 0, "overridden day is not archived");
+
+        let report = archive.backfill(tariff_price_list.as_ref(), &date(2024, 8, 26), 1).await;
+        assert_eq!(report.fetched_days, 1);
+        assert!(archive.is_archived(&date(2024, 8, 25)).await);
+    }
+
+    #[test]
+    fn relative_archive_directory_should_be_resolved_at_startup() {
+        let archive =
+            PriceListArchive::new(&PriceListArchiveConfig { directory: "archive".to_owned(), ..Default::default() });
+
+        assert!(archive.directory().is_absolute());
+        assert!(archive.directory().ends_with("archive"));
+    }
+
+    #[tokio::test]
+    async fn backfill_should_stop_when_tariff_is_not_archived() {
+        let archive = create_archive();
+
+        let report = archive.backfill(&TimeOfUsePriceListProvider::w12(), &date(2024, 8, 26), 30).await;
+
+        assert_eq!(report.fetched_days, 0);
+        assert_eq!(report.stopped_at, Some(date(2024, 8, 25)));
+    }
+}
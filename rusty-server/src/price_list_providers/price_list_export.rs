@@ -0,0 +1,181 @@
+use chrono::{DateTime, Local, Utc};
+
+use crate::model::{AppError, Money, PriceCategory, PriceListItem, MONEY_UNIT};
+
+/// Formats in which price list could be returned by the REST api
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PriceListFormat {
+    Json,
+    Csv,
+    ICalendar,
+}
+
+impl PriceListFormat {
+    /// `format` query parameter takes priority over the `Accept` header, JSON is returned by default
+    pub fn negotiate(format: Option<&str>, accept: Option<&str>) -> Result<Self, AppError> {
+        match format.map(|format| format.trim().to_lowercase()) {
+            Some(format) => match format.as_str() {
+                "json" => Ok(Self::Json),
+                "csv" => Ok(Self::Csv),
+                "ics" | "ical" | "icalendar" => Ok(Self::ICalendar),
+                _ => Err(AppError::user_error(&format!(
+                    "Price list format '{}' is not supported, use json, csv or ics",
+                    format
+                ))),
+            },
+            None => Ok(match accept {
+                Some(accept) if accept.contains("text/csv") => Self::Csv,
+                Some(accept) if accept.contains("text/calendar") => Self::ICalendar,
+                _ => Self::Json,
+            }),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::ICalendar => "text/calendar; charset=utf-8",
+        }
+    }
+}
+
+/// CSV for spreadsheets, times are local, duration is in minutes and price is in currency per kWh
+pub fn price_list_to_csv(price_list: &[PriceListItem]) -> String {
+    let format_time = |time: &DateTime<Utc>| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string();
+    let mut csv = format!("start,end,duration,price [{}],category,source\n", MONEY_UNIT);
+    for item in price_list {
+        let category = match item.category() {
+            PriceCategory::Min => "min",
+            PriceCategory::Medium => "medium",
+            PriceCategory::Max => "max",
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            format_time(item.starts_at()),
+            format_time(&(*item.starts_at() + *item.duration())),
+            item.duration().num_minutes(),
+            item.price().amount(),
+            category,
+            item.source().unwrap_or_default()
+        ));
+    }
+    csv
+}
+
+/// iCalendar feed with an event for every window of consecutive cheap (`PriceCategory::Min`) price list items,
+/// so cheap hours could be subscribed from the phone calendar.
+/// `generated_at` is used as the event time stamp.
+pub fn price_list_to_icalendar(price_list: &[PriceListItem], generated_at: &DateTime<Utc>) -> String {
+    let format_time = |time: &DateTime<Utc>| time.format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//ha-smart-power-switcher//price list//EN".to_owned(),
+        "CALSCALE:GREGORIAN".to_owned(),
+        "METHOD:PUBLISH".to_owned(),
+        "X-WR-CALNAME:Cheap energy".to_owned(),
+    ];
+
+    for window in
+        price_list.chunk_by(|a, b| a.category() == b.category() && *a.starts_at() + *a.duration() == *b.starts_at())
+    {
+        if *window[0].category() != PriceCategory::Min {
+            continue;
+        }
+        let starts_at = window[0].starts_at();
+        let ends_at = *window[window.len() - 1].starts_at() + *window[window.len() - 1].duration();
+        let prices: Vec<Money> = window.iter().map(|item| item.price()).collect();
+        let lowest_price = prices.iter().min().copied().unwrap_or_default();
+        lines.extend([
+            "BEGIN:VEVENT".to_owned(),
+            format!("UID:cheap-energy-{}@ha-smart-power-switcher", starts_at.timestamp()),
+            format!("DTSTAMP:{}", format_time(generated_at)),
+            format!("DTSTART:{}", format_time(starts_at)),
+            format!("DTEND:{}", format_time(&ends_at)),
+            "SUMMARY:Cheap energy".to_owned(),
+            format!(
+                "DESCRIPTION:Average price {}\\nLowest price {}",
+                Money::mean(&prices).unwrap_or_default(),
+                lowest_price
+            ),
+            "TRANSP:TRANSPARENT".to_owned(),
+            "END:VEVENT".to_owned(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_owned());
+
+    // iCalendar requires CRLF line endings
+    lines.iter().map(|line| line.to_owned() + "\r\n").collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+
+    use crate::model::{Money, PriceCategory, PriceListItem};
+
+    use super::{price_list_to_csv, price_list_to_icalendar, PriceListFormat};
+
+    fn create_price_list(day: &DateTime<Utc>, prices: &[(i64, PriceCategory)]) -> Vec<PriceListItem> {
+        prices
+            .iter()
+            .enumerate()
+            .map(|(hour, (price, category))| {
+                PriceListItem::new(
+                    *day + TimeDelta::hours(hour as i64),
+                    TimeDelta::hours(1),
+                    Money::from_legacy_price(*price),
+                    category.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn format_should_be_negotiated_from_query_parameter_or_accept_header() {
+        assert_eq!(PriceListFormat::negotiate(None, None).unwrap(), PriceListFormat::Json);
+        assert_eq!(PriceListFormat::negotiate(Some("CSV"), Some("application/json")).unwrap(), PriceListFormat::Csv);
+        assert_eq!(PriceListFormat::negotiate(None, Some("text/calendar")).unwrap(), PriceListFormat::ICalendar);
+        assert_eq!(PriceListFormat::negotiate(None, Some("text/csv, */*")).unwrap(), PriceListFormat::Csv);
+        assert_eq!(PriceListFormat::negotiate(None, Some("*/*")).unwrap(), PriceListFormat::Json);
+        assert!(PriceListFormat::negotiate(Some("xml"), None).is_err());
+    }
+
+    #[test]
+    fn price_list_should_be_exported_as_csv() {
+        let day = Local.with_ymd_and_hms(2024, 8, 26, 0, 0, 0).unwrap().with_timezone(&Utc);
+        let price_list = create_price_list(&day, &[(45123, PriceCategory::Medium), (-1500, PriceCategory::Min)]);
+
+        assert_eq!(
+            price_list_to_csv(&price_list),
+            "start,end,duration,price [PLN/kWh],category,source\n\
+             2024-08-26 00:00,2024-08-26 01:00,60,0.45123,medium,\n\
+             2024-08-26 01:00,2024-08-26 02:00,60,-0.015,min,\n"
+        );
+    }
+
+    #[test]
+    fn consecutive_cheap_items_should_make_single_calendar_event() {
+        let day = Utc.with_ymd_and_hms(2024, 8, 25, 22, 0, 0).unwrap();
+        let price_list = create_price_list(
+            &day,
+            &[
+                (10000, PriceCategory::Min),
+                (15000, PriceCategory::Min),
+                (50000, PriceCategory::Medium),
+                (90000, PriceCategory::Max),
+                (-2000, PriceCategory::Min),
+            ],
+        );
+
+        let calendar = price_list_to_icalendar(&price_list, &day);
+
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(calendar.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(calendar.matches("BEGIN:VEVENT").count(), 2);
+        assert!(calendar.contains("DTSTART:20240825T220000Z\r\nDTEND:20240826T000000Z\r\n"));
+        assert!(calendar.contains("DESCRIPTION:Average price 0.125 PLN/kWh\\nLowest price 0.1 PLN/kWh\r\n"));
+        assert!(calendar.contains("DTSTART:20240826T020000Z\r\nDTEND:20240826T030000Z\r\n"));
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, LocalResult, NaiveTime, TimeDelta, TimeZone, Utc};
+use serde::Deserialize;
+
+use crate::{
+    model::{AppError, Money, PriceList, PriceListItem, PriceListStatus},
+    settings::PriceListValidationConfig,
+};
+
+use super::{
+    commons::{cut_off_time_from_date, evaluate_price_category, next_day_start, parse_decimal},
+    SingleDayPriceList,
+};
+
+/// name of the price list source reported for uploaded price lists
+pub const MANUAL_PRICE_LIST_SOURCE: &str = "Manual";
+
+/// Single uploaded price item, price is decimal money per kWh given as string or number
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PriceItemUpload {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    starts_at: DateTime<Utc>,
+    #[serde(deserialize_with = "crate::model::deserialize_time_delta")]
+    duration: TimeDelta,
+    #[serde(deserialize_with = "crate::model::deserialize_money_amount")]
+    price: Money,
+}
+
+/// PriceListOverrides keeps price lists uploaded by hand, e.g. when every provider is down,
+/// uploaded price list takes priority over the configured provider for its day.
+/// Upload time is reported as fetch time of the price list, uploaded prices could be marked as estimated,
+/// e.g. when they are copied from a forecast before day ahead prices are published.
+/// Overrides are kept in memory only, they are lost on restart and the configured provider is used again.
+/// Uploaded prices are validated like prices of the configured tariff when validation is enabled.
+#[derive(Default)]
+pub struct PriceListOverrides {
+    overrides: RwLock<HashMap<DateTime<Utc>, Arc<PriceList>>>,
+    validation: Option<PriceListValidationConfig>,
+}
+
+impl PriceListOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// uploaded price lists are validated by the price list validation
+    pub fn with_validation(mut self, validation: PriceListValidationConfig) -> Self {
+        self.validation = Some(validation);
+        self
+    }
+
+    pub fn get_override(&self, for_day: &DateTime<Utc>) -> Option<Arc<PriceList>> {
+        self.overrides.read().unwrap().get(&cut_off_time_from_date(for_day)).cloned()
+    }
+
+    /// Function validates uploaded price list and stores it as override of the given day
+    pub fn set_override(
+        &self,
+        for_day: &DateTime<Utc>,
+        price_list: Vec<PriceListItem>,
+        status: PriceListStatus,
+    ) -> Result<Arc<PriceList>, AppError> {
+        let for_day = cut_off_time_from_date(for_day);
+        let price_list = Self::validate_price_list(&for_day, price_list)?;
+        let mut price_list = PriceList::new(for_day, MANUAL_PRICE_LIST_SOURCE, Utc::now(), price_list);
+        price_list.set_status(status);
+        // rejected upload is a mistake of the api user
+        let price_list = match &self.validation {
+            Some(validation) => validation.validate(&for_day, &price_list).map_err(|error| match error {
+                AppError::SystemError { message, code: _ } => AppError::user_error(&message),
+                error => error,
+            })?,
+            None => price_list,
+        };
+        let price_list = Arc::new(price_list);
+        self.overrides.write().unwrap().insert(for_day, price_list.clone());
+        Ok(price_list)
+    }
+
+    pub fn remove_override(&self, for_day: &DateTime<Utc>) -> Option<Arc<PriceList>> {
+        self.overrides.write().unwrap().remove(&cut_off_time_from_date(for_day))
+    }
+
+    /// price list has to cover entire day without gaps and overlaps
+    fn validate_price_list(
+        for_day: &DateTime<Utc>,
+        mut price_list: Vec<PriceListItem>,
+    ) -> Result<Vec<PriceListItem>, AppError> {
+        let format_time = |time: &DateTime<Utc>| time.with_timezone(&Local).format("%d-%m-%Y %H:%M").to_string();
+        if price_list.is_empty() {
+            return Err(AppError::user_error("Price list is empty!"));
+        }
+        price_list.sort_by(|a, b| a.starts_at().cmp(b.starts_at()));
+
+        let mut expected_start = *for_day;
+        for item in &price_list {
+            if *item.duration() <= TimeDelta::zero() {
+                return Err(AppError::user_error(&format!(
+                    "Price list item starting at {} has no duration!",
+                    format_time(item.starts_at())
+                )));
+            }
+            if *item.starts_at() > expected_start {
+                return Err(AppError::user_error(&format!(
+                    "Price list has a gap between {} and {}!",
+                    format_time(&expected_start),
+                    format_time(item.starts_at())
+                )));
+            }
+            if *item.starts_at() < expected_start {
+                return Err(AppError::user_error(&format!(
+                    "Price list item starting at {} overlaps previous item or starts before the day!",
+                    format_time(item.starts_at())
+                )));
+            }
+            expected_start = *item.starts_at() + *item.duration();
+        }
+
+        let day_end = next_day_start(for_day);
+        if expected_start != day_end {
+            return Err(AppError::user_error(&format!(
+                "Price list should end at {} but it ends at {}!",
+                format_time(&day_end),
+                format_time(&expected_start)
+            )));
+        }
+        Ok(price_list)
+    }
+
+    fn create_price_list_item(starts_at: DateTime<Utc>, duration: TimeDelta, price: Money) -> PriceListItem {
+        let mut item = PriceListItem::new(starts_at, duration, price, evaluate_price_category(price));
+        item.set_source(Some(MANUAL_PRICE_LIST_SOURCE.to_owned()));
+        item
+    }
+
+    /// JSON is a list of items with `startsAt` and `duration` in milliseconds and `price` as money per kWh, e.g.
+    /// `[{"startsAt": 1724623200000, "duration": 3600000, "price": "0.45123"}]`
+    pub fn parse_json(text: &str) -> Result<Vec<PriceListItem>, AppError> {
+        serde_json::from_str::<Vec<PriceItemUpload>>(text)
+            .map(|items| {
+                items
+                    .into_iter()
+                    .map(|item| Self::create_price_list_item(item.starts_at, item.duration, item.price))
+                    .collect()
+            })
+            .map_err(|e| AppError::user_error(&format!("Price list JSON is invalid: {}", e)))
+    }
+
+    /// CSV has `start,duration,price` rows with optional header, start is local time `HH:MM` of the given day,
+    /// duration is in minutes and price is in currency per kWh like in JSON.
+    /// Semicolon could be used as separator, then decimal comma is allowed in prices, e.g. `13:00;60;-0,01234`
+    pub fn parse_csv(for_day: &DateTime<Utc>, text: &str) -> Result<Vec<PriceListItem>, AppError> {
+        let local_day = cut_off_time_from_date(for_day).with_timezone(&Local).date_naive();
+        let mut previous_end: Option<DateTime<Utc>> = None;
+        let mut price_list = Vec::new();
+        let split_columns = |line: &str| -> Vec<String> {
+            let separator = if line.contains(';') { ';' } else { ',' };
+            line.split(separator).map(|column| column.trim().to_owned()).collect()
+        };
+        // first line is a header when it does not start with time
+        let is_header = |line_number: usize, line: &str| {
+            line_number == 0
+                && split_columns(line).first().is_none_or(|start| NaiveTime::parse_from_str(start, "%H:%M").is_err())
+        };
+
+        for (line_number, line) in text
+            .lines()
+            .enumerate()
+            .filter(|(line_number, line)| !line.trim().is_empty() && !is_header(*line_number, line))
+        {
+            let invalid_line = |reason: &str| AppError::user_error(&format!("Line {}: {}", line_number + 1, reason));
+            let columns = split_columns(line);
+            let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+            let [start, duration, price] = columns[..] else {
+                return Err(invalid_line("start, duration and price columns are expected"));
+            };
+
+            let start = NaiveTime::parse_from_str(start, "%H:%M").map_err(|_| invalid_line("start should be HH:MM"))?;
+            // during the autumn time change the same local hour happens twice, the later one continues the list
+            let starts_at = match Local.from_local_datetime(&local_day.and_time(start)) {
+                LocalResult::Single(starts_at) => starts_at,
+                LocalResult::Ambiguous(earliest, latest) => {
+                    if previous_end.is_some_and(|previous_end| previous_end > earliest.with_timezone(&Utc)) {
+                        latest
+                    } else {
+                        earliest
+                    }
+                }
+                LocalResult::None => return Err(invalid_line("start does not exist because of time change")),
+            }
+            .with_timezone(&Utc);
+            let duration = duration
+                .parse::<i64>()
+                .map(TimeDelta::minutes)
+                .map_err(|_| invalid_line("duration should be number of minutes"))?;
+            let price = parse_decimal(price).map(Money::new).map_err(|_| invalid_line("price is not a number"))?;
+
+            previous_end = Some(starts_at + duration);
+            price_list.push(Self::create_price_list_item(starts_at, duration, price));
+        }
+        Ok(price_list)
+    }
+}
+
+/// OverriddenPriceList returns uploaded price list if there is one for the requested day,
+/// otherwise it asks wrapped price list provider
+pub struct OverriddenPriceList<T: SingleDayPriceList> {
+    price_list_provider: T,
+    overrides: Arc<PriceListOverrides>,
+}
+
+impl<T: SingleDayPriceList> OverriddenPriceList<T> {
+    pub fn new(price_list_provider: T, overrides: Arc<PriceListOverrides>) -> Self {
+        Self { price_list_provider, overrides }
+    }
+}
+
+#[async_trait]
+impl<T: SingleDayPriceList> SingleDayPriceList for OverriddenPriceList<T> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        match self.overrides.get_override(for_day) {
+            Some(price_list) => Ok(price_list),
+            None => self.price_list_provider.get_price_list(for_day).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+
+    use crate::{
+        model::{AppError, Money, PriceList, PriceListStatus},
+        price_list_providers::{SingleDayPriceList, TimeOfUsePriceListProvider},
+        settings::PriceListValidationConfig,
+    };
+
+    use super::{OverriddenPriceList, PriceListOverrides};
+
+    fn date_time(year: i32, month: u32, day: u32, hour: u32, min: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, hour, min, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    fn hourly_csv(hours: impl Iterator<Item = &'static str>) -> String {
+        std::iter::once("start;duration;price".to_owned())
+            .chain(hours.enumerate().map(|(i, start)| format!("{};60;0,{:03}", start, i * 10 + 5)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    const HOURS: [&str; 24] = [
+        "00:00", "01:00", "02:00", "03:00", "04:00", "05:00", "06:00", "07:00", "08:00", "09:00", "10:00", "11:00",
+        "12:00", "13:00", "14:00", "15:00", "16:00", "17:00", "18:00", "19:00", "20:00", "21:00", "22:00", "23:00",
+    ];
+
+    fn assert_user_error(result: Result<Arc<PriceList>, AppError>, expected: &str) {
+        match result {
+            Err(AppError::UserError { message, code: _ }) => assert_eq!(message, expected),
+            _ => panic!("User error '{}' is expected", expected),
+        }
+    }
+
+    #[tokio::test]
+    async fn uploaded_csv_should_override_provider() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        let overrides = Arc::new(PriceListOverrides::new());
+        let price_list = OverriddenPriceList::new(TimeOfUsePriceListProvider::w12(), overrides.clone());
+
+        let uploaded = PriceListOverrides::parse_csv(&day, &hourly_csv(HOURS.into_iter())).unwrap();
+        overrides.set_override(&day, uploaded, PriceListStatus::Estimated).unwrap();
+
+        let overridden = price_list.get_price_list(&day).await.unwrap();
+        assert_eq!(overridden.items().len(), 24);
+        assert_eq!(overridden.items()[1].price(), "0.015".parse::<Money>().unwrap());
+        assert_eq!(overridden.items()[1].source(), Some("Manual"));
+        assert_eq!(overridden.provider(), "Manual");
+        assert_eq!(overridden.status(), PriceListStatus::Estimated);
+
+        let next_day = price_list.get_price_list(&date_time(2024, 8, 27, 0, 0)).await.unwrap();
+        assert_eq!(next_day.items()[0].source(), None);
+        assert_eq!(next_day.status(), PriceListStatus::Final);
+
+        overrides.remove_override(&day);
+        assert_eq!(price_list.get_price_list(&day).await.unwrap().items()[1].price().legacy_price(), 80000);
+    }
+
+    #[test]
+    fn uploaded_json_should_be_parsed() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        let json = format!(
+            r#"[{{"startsAt": {}, "duration": 43200000, "price": "-0.01"}},
+                {{"startsAt": {}, "duration": 43200000, "price": 0.45123}}]"#,
+            day.timestamp_millis(),
+            date_time(2024, 8, 26, 12, 0).timestamp_millis()
+        );
+        let price_list = PriceListOverrides::parse_json(&json).unwrap();
+        let price_list = PriceListOverrides::new().set_override(&day, price_list, PriceListStatus::Final).unwrap();
+        assert_eq!(price_list.items()[0].price(), Money::from_legacy_price(-1000));
+        assert_eq!(price_list.items()[1].price(), Money::from_legacy_price(45123));
+    }
+
+    #[test]
+    fn price_list_with_gap_or_overlap_should_be_rejected() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        let overrides = PriceListOverrides::new();
+
+        let missing_hour =
+            PriceListOverrides::parse_csv(&day, &hourly_csv(HOURS.into_iter().filter(|h| *h != "05:00")));
+        assert_user_error(
+            overrides.set_override(&day, missing_hour.unwrap(), PriceListStatus::Final),
+            "Price list has a gap between 26-08-2024 05:00 and 26-08-2024 06:00!",
+        );
+
+        let duplicated_hour = PriceListOverrides::parse_csv(&day, &hourly_csv(HOURS.into_iter().chain(["23:00"])));
+        assert_user_error(
+            overrides.set_override(&day, duplicated_hour.unwrap(), PriceListStatus::Final),
+            "Price list item starting at 26-08-2024 23:00 overlaps previous item or starts before the day!",
+        );
+
+        let missing_end = PriceListOverrides::parse_csv(&day, &hourly_csv(HOURS.into_iter().take(23)));
+        assert_user_error(
+            overrides.set_override(&day, missing_end.unwrap(), PriceListStatus::Final),
+            "Price list should end at 27-08-2024 00:00 but it ends at 26-08-2024 23:00!",
+        );
+        assert!(overrides.get_override(&day).is_none());
+    }
+
+    #[test]
+    fn csv_should_follow_daylight_saving_time() {
+        let day = date_time(2024, 10, 27, 0, 0);
+        let hours = HOURS[..3].iter().chain(HOURS[2..].iter()).copied();
+        let price_list = PriceListOverrides::parse_csv(&day, &hourly_csv(hours)).unwrap();
+        let price_list = PriceListOverrides::new().set_override(&day, price_list, PriceListStatus::Final).unwrap();
+        assert_eq!(price_list.items().len(), 25);
+        assert_eq!(*price_list.items()[3].starts_at() - *price_list.items()[2].starts_at(), TimeDelta::hours(1));
+    }
+
+    #[test]
+    fn csv_without_header_should_keep_the_first_line() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        let csv = hourly_csv(HOURS.into_iter());
+        let without_header = csv.lines().skip(1).collect::<Vec<_>>().join("\n");
+
+        let price_list = PriceListOverrides::parse_csv(&day, &without_header).unwrap();
+        assert_eq!(price_list.len(), 24);
+        assert_eq!(price_list[0].price(), "0.005".parse::<Money>().unwrap());
+        assert_eq!(PriceListOverrides::parse_csv(&day, &csv).unwrap(), price_list);
+    }
+
+    #[test]
+    fn csv_and_json_prices_should_be_per_kwh() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        let json = |price: &str| {
+            PriceListOverrides::parse_json(&format!(
+                r#"[{{"startsAt": {}, "duration": 86400000, "price": {}}}]"#,
+                day.timestamp_millis(),
+                price
+            ))
+            .unwrap()[0]
+                .price()
+        };
+        let csv =
+            |price: &str| PriceListOverrides::parse_csv(&day, &format!("00:00;1440;{}", price)).unwrap()[0].price();
+
+        assert_eq!(csv("0,45123"), json(r#""0.45123""#));
+        assert_eq!(csv("0.45123"), json("0.45123"));
+        assert_eq!(csv("45123"), json("45123"));
+        assert_eq!(csv("1"), json("1"));
+        assert_eq!(json("1"), "1".parse().unwrap());
+    }
+
+    #[test]
+    fn overrides_should_be_kept_in_memory_only() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        let price_list = PriceListOverrides::parse_csv(&day, "00:00;1440;0,45").unwrap();
+        PriceListOverrides::new().set_override(&day, price_list, PriceListStatus::Final).unwrap();
+
+        let restarted_overrides = PriceListOverrides::new();
+        assert!(restarted_overrides.get_override(&day).is_none());
+    }
+
+    #[test]
+    fn uploaded_prices_should_be_validated() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        let overrides = PriceListOverrides::new().with_validation(PriceListValidationConfig::default());
+
+        let rogue_price = PriceListOverrides::parse_csv(&day, "00:00;720;0,45\n12:00;720;99,999").unwrap();
+        assert_user_error(
+            overrides.set_override(&day, rogue_price, PriceListStatus::Final),
+            "Price list for 26-08-2024 is invalid: price 99.999 PLN/kWh at 26-08-2024 12:00 is out of plausible range -5 - 50 PLN/kWh",
+        );
+        assert!(overrides.get_override(&day).is_none());
+
+        let suspicious_price = PriceListOverrides::parse_csv(&day, "00:00;720;0,45\n12:00;720;-1,2").unwrap();
+        let price_list = overrides.set_override(&day, suspicious_price, PriceListStatus::Final).unwrap();
+        assert_eq!(price_list.items()[1].warnings(), ["Price -1.2 PLN/kWh is out of usual range -0.5 - 2 PLN/kWh"]);
+        assert_eq!(price_list.provider(), "Manual");
+    }
+
+    #[test]
+    fn invalid_csv_line_should_be_reported() {
+        let day = date_time(2024, 8, 26, 0, 0);
+        match PriceListOverrides::parse_csv(&day, "start,duration,price\n00:00,60") {
+            Err(AppError::UserError { message, code: _ }) => {
+                assert_eq!(message, "Line 2: start, duration and price columns are expected")
+            }
+            _ => panic!("Missing column should be reported"),
+        }
+    }
+}
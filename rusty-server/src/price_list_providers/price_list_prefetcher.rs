@@ -0,0 +1,268 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+use tokio::sync::watch;
+
+use crate::{
+    model::{PriceListPrefetchState, PriceListPrefetchStatus, PriceListStatus},
+    settings::PriceListPrefetchConfig,
+};
+
+use super::{
+    commons::{cut_off_time_from_date, next_day_start},
+    SharedSingleDayPriceList,
+};
+
+/// PriceListPrefetcher fetches tomorrow's price list in the background, so the first user request
+/// after the publication time does not wait for the remote call and failing source is noticed earlier.
+/// Fetched price list stays in the providers caches, subscribers are notified about each fetched day,
+/// e.g. power consumers service re-plans consumption which waits for tomorrow's prices.
+pub struct PriceListPrefetcher {
+    single_day_price_list: SharedSingleDayPriceList,
+    config: PriceListPrefetchConfig,
+    status: Mutex<PriceListPrefetchStatus>,
+    fetched_day: watch::Sender<Option<DateTime<Utc>>>,
+}
+
+impl PriceListPrefetcher {
+    pub fn new(single_day_price_list: SharedSingleDayPriceList, config: PriceListPrefetchConfig) -> Self {
+        Self {
+            single_day_price_list,
+            config,
+            status: Mutex::new(PriceListPrefetchStatus {
+                for_day: None,
+                state: PriceListPrefetchState::Waiting,
+                attempts: 0,
+                last_error: None,
+                last_attempt_at: None,
+                fetched_at: None,
+                next_attempt_at: None,
+            }),
+            fetched_day: watch::channel(None).0,
+        }
+    }
+
+    pub fn get_status(&self) -> PriceListPrefetchStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// receiver is updated with the day of each fetched price list
+    pub fn subscribe(&self) -> watch::Receiver<Option<DateTime<Utc>>> {
+        self.fetched_day.subscribe()
+    }
+
+    /// publication time of next day price list in local time of the given day,
+    /// when configured time does not exist because of DST change the next hour is taken
+    fn publication_time(&self, day: &DateTime<Utc>) -> DateTime<Utc> {
+        let day_start = cut_off_time_from_date(day);
+        let local_day = day_start.with_timezone(&Local).date_naive();
+        (0..2)
+            .find_map(|shift| {
+                local_day
+                    .and_hms_opt(self.config.publication_hour + shift, self.config.publication_minute, 0)
+                    .and_then(|time| Local.from_local_datetime(&time).earliest())
+            })
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(|| next_day_start(&day_start))
+    }
+
+    fn update_status(&self, for_day: DateTime<Utc>, update: impl FnOnce(&mut PriceListPrefetchStatus)) {
+        let mut status = self.status.lock().unwrap();
+        if status.for_day != Some(for_day) {
+            status.for_day = Some(for_day);
+            status.state = PriceListPrefetchState::Waiting;
+            status.attempts = 0;
+            status.last_error = None;
+            status.fetched_at = None;
+        }
+        update(&mut status);
+    }
+
+    /// Function makes single prefetch step at `now` and returns time of the next step,
+    /// before publication time it waits, after it fetches tomorrow's price list until final prices are returned,
+    /// estimated price list is retried like a failure
+    pub async fn prefetch(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = cut_off_time_from_date(&now);
+        let tomorrow = next_day_start(&today);
+        let publication_time = self.publication_time(&today);
+
+        if now < publication_time {
+            self.update_status(tomorrow, |status| status.next_attempt_at = Some(publication_time));
+            return publication_time;
+        }
+
+        let already_fetched = {
+            let status = self.status.lock().unwrap();
+            status.for_day == Some(tomorrow) && status.state == PriceListPrefetchState::Fetched
+        };
+        if already_fetched {
+            return self.publication_time(&tomorrow);
+        }
+
+        let fetched = match self.single_day_price_list.get_price_list(&tomorrow).await {
+            Ok(price_list) if price_list.status() == PriceListStatus::Final => Ok(()),
+            Ok(_) => Err("Price list is estimated, final prices are not published yet".to_owned()),
+            Err(error) => Err(error.to_string()),
+        };
+        match fetched {
+            Ok(()) => {
+                let next_attempt_at = self.publication_time(&tomorrow);
+                self.update_status(tomorrow, |status| {
+                    status.state = PriceListPrefetchState::Fetched;
+                    status.attempts += 1;
+                    status.last_error = None;
+                    status.last_attempt_at = Some(now);
+                    status.fetched_at = Some(now);
+                    status.next_attempt_at = Some(next_attempt_at);
+                });
+                tracing::info!("Price list for {} prefetched", tomorrow.with_timezone(&Local).format("%d-%m-%Y"));
+                self.fetched_day.send_replace(Some(tomorrow));
+                next_attempt_at
+            }
+            Err(error) => {
+                let next_attempt_at = now + TimeDelta::seconds(self.config.retry_interval_secs as i64);
+                self.update_status(tomorrow, |status| {
+                    status.state = PriceListPrefetchState::Failing;
+                    status.attempts += 1;
+                    status.last_error = Some(error.clone());
+                    status.last_attempt_at = Some(now);
+                    status.next_attempt_at = Some(next_attempt_at);
+                    tracing::warn!("Price list prefetch attempt {} failed: {}", status.attempts, error);
+                });
+                next_attempt_at
+            }
+        }
+    }
+
+    /// background job which should be spawned on the tokio runtime
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let next_attempt_at = self.prefetch(Utc::now()).await;
+            tokio::time::sleep((next_attempt_at - Utc::now()).to_std().unwrap_or_default()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+
+    use crate::{
+        model::{AppError, PriceList, PriceListPrefetchState},
+        price_list_providers::{SingleDayPriceList, TimeOfUsePriceListProvider},
+        settings::PriceListPrefetchConfig,
+    };
+
+    use super::PriceListPrefetcher;
+
+    /// price list which is not published for the first `failures` calls and estimated for the next `estimates` calls
+    struct LatePriceList {
+        failures: u32,
+        estimates: u32,
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl SingleDayPriceList for LatePriceList {
+        async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            if call < self.failures {
+                Err(AppError::not_found("Price list is not published yet"))
+            } else if call < self.failures + self.estimates {
+                TimeOfUsePriceListProvider::w12().estimated().get_price_list(for_day).await
+            } else {
+                TimeOfUsePriceListProvider::w12().get_price_list(for_day).await
+            }
+        }
+    }
+
+    fn date_time(year: i32, month: u32, day: u32, hour: u32, min: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, hour, min, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    fn create_prefetcher(failures: u32, estimates: u32) -> (Arc<LatePriceList>, PriceListPrefetcher) {
+        let price_list = Arc::new(LatePriceList { failures, estimates, calls: AtomicU32::new(0) });
+        (price_list.clone(), PriceListPrefetcher::new(price_list, PriceListPrefetchConfig::default()))
+    }
+
+    #[tokio::test]
+    async fn prefetch_should_wait_for_publication_time() {
+        let (price_list, prefetcher) = create_prefetcher(0, 0);
+
+        let next_attempt_at = prefetcher.prefetch(date_time(2024, 8, 26, 9, 30)).await;
+        assert_eq!(next_attempt_at, date_time(2024, 8, 26, 14, 0));
+        assert_eq!(price_list.calls.load(Ordering::SeqCst), 0);
+
+        let status = prefetcher.get_status();
+        assert_eq!(status.for_day, Some(date_time(2024, 8, 27, 0, 0)));
+        assert_eq!(status.state, PriceListPrefetchState::Waiting);
+    }
+
+    #[tokio::test]
+    async fn prefetch_should_be_retried_until_price_list_is_fetched() {
+        let (price_list, prefetcher) = create_prefetcher(2, 0);
+        let mut fetched_day = prefetcher.subscribe();
+
+        let next_attempt_at = prefetcher.prefetch(date_time(2024, 8, 26, 14, 0)).await;
+        assert_eq!(next_attempt_at, date_time(2024, 8, 26, 14, 5));
+        let status = prefetcher.get_status();
+        assert_eq!(status.state, PriceListPrefetchState::Failing);
+        assert_eq!(status.last_error.as_deref(), Some("[UserError] Price list is not published yet"));
+
+        prefetcher.prefetch(date_time(2024, 8, 26, 14, 5)).await;
+        assert!(!fetched_day.has_changed().unwrap());
+
+        let next_attempt_at = prefetcher.prefetch(date_time(2024, 8, 26, 14, 10)).await;
+        assert_eq!(next_attempt_at, date_time(2024, 8, 27, 14, 0));
+        let status = prefetcher.get_status();
+        assert_eq!(status.state, PriceListPrefetchState::Fetched);
+        assert_eq!(status.attempts, 3);
+        assert_eq!(status.fetched_at, Some(date_time(2024, 8, 26, 14, 10)));
+        assert!(fetched_day.has_changed().unwrap());
+        assert_eq!(*fetched_day.borrow_and_update(), Some(date_time(2024, 8, 27, 0, 0)));
+
+        prefetcher.prefetch(date_time(2024, 8, 26, 18, 0)).await;
+        assert_eq!(price_list.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn estimated_price_list_should_be_retried_until_it_is_final() {
+        let (price_list, prefetcher) = create_prefetcher(0, 1);
+        let mut fetched_day = prefetcher.subscribe();
+
+        let next_attempt_at = prefetcher.prefetch(date_time(2024, 8, 26, 14, 0)).await;
+        assert_eq!(next_attempt_at, date_time(2024, 8, 26, 14, 5));
+        let status = prefetcher.get_status();
+        assert_eq!(status.state, PriceListPrefetchState::Failing);
+        assert_eq!(status.last_error.as_deref(), Some("Price list is estimated, final prices are not published yet"));
+        assert!(!fetched_day.has_changed().unwrap());
+
+        let next_attempt_at = prefetcher.prefetch(date_time(2024, 8, 26, 14, 5)).await;
+        assert_eq!(next_attempt_at, date_time(2024, 8, 27, 14, 0));
+        let status = prefetcher.get_status();
+        assert_eq!(status.state, PriceListPrefetchState::Fetched);
+        assert_eq!(status.attempts, 2);
+        assert_eq!(status.last_error, None);
+        assert_eq!(*fetched_day.borrow_and_update(), Some(date_time(2024, 8, 27, 0, 0)));
+        assert_eq!(price_list.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn status_should_be_reset_for_the_next_day() {
+        let (_, prefetcher) = create_prefetcher(0, 0);
+        prefetcher.prefetch(date_time(2024, 8, 26, 15, 0)).await;
+
+        let next_attempt_at = prefetcher.prefetch(date_time(2024, 8, 27, 0, 0) + TimeDelta::minutes(1)).await;
+        assert_eq!(next_attempt_at, date_time(2024, 8, 27, 14, 0));
+        let status = prefetcher.get_status();
+        assert_eq!(status.for_day, Some(date_time(2024, 8, 28, 0, 0)));
+        assert_eq!(status.state, PriceListPrefetchState::Waiting);
+        assert_eq!(status.attempts, 0);
+    }
+}
@@ -1,45 +1,240 @@
 use std::sync::Arc;
 
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
 
-use crate::model::{AppError, PriceListItem};
+use crate::{
+    model::{AppError, PriceList},
+    settings::{PriceCategoryStrategy, PriceCompositionConfig, Settings},
+};
 
-use super::{DayAheadMarketPriceListProvider, SingleDayPriceList, W12PriceListProvider};
+use super::{
+    commons::create_http_client, ArchivedPriceList, CategorizedPriceList, ComposedPriceList,
+    DayAheadMarketPriceListProvider, EntsoePriceListProvider, FallbackPriceList, OverriddenPriceList, PriceListArchive,
+    PriceListOverrides, PriceListSourcesMonitor, SharedSingleDayPriceList, SingleDayPriceList,
+    TimeOfUsePriceListProvider, ValidatedPriceList,
+};
 
 #[derive(Debug, Deserialize, Clone)]
 pub enum TariffTypes {
     W12,
+    TimeOfUse,
     DayAheadMarket,
+    Entsoe,
+    Fallback,
 }
 
-/// TariffSelector allows to configure application with one of two
-/// possible tariff: day ahead market where each hour has different prise
-/// or W12 where there is off peak price between 2pm - 6 am and 1pm-3pm and weekends
+/// TariffSelector allows to configure application with one of
+/// possible tariff: day ahead market where each hour has different prise,
+/// ENTSO-E day ahead prices for bidding zones outside Poland,
+/// time of use tariff described in the settings
+/// or W12 where there is off peak price between 10pm - 6 am and 1pm-3pm and weekends
 /// in peek hours have double price. This tariff is provided mainly for testing
 /// cos price list fetch requires remote call.
+/// Remote price lists share one http client configured by `price_list_http_client`.
+/// Fallback tariff asks tariffs from `price_list_fallback_chain` in order until one of them answers.
+/// Price list of each tariff is validated, so invalid day of the first tariff in the fallback chain is skipped.
+/// When price list archive is enabled remote price lists are served from the archive before any remote call,
+/// only validated days are archived.
+/// Time of use tariff which follows other sources of the fallback chain only estimates their prices,
+/// so its price lists are marked as estimated.
 ///
 pub struct TariffSelector {
-    current_tariff: TariffTypes,
-    w12_price_list_provider: W12PriceListProvider,
-    day_ahead_market_price_list_provider: DayAheadMarketPriceListProvider,
+    price_list_provider: Box<dyn SingleDayPriceList>,
 }
 
 impl TariffSelector {
-    pub fn new(current_tariff: TariffTypes) -> Self {
-        Self {
-            current_tariff,
-            w12_price_list_provider: W12PriceListProvider {},
-            day_ahead_market_price_list_provider: DayAheadMarketPriceListProvider::new(),
+    pub fn new(
+        settings: &Settings,
+        price_list_sources_monitor: Arc<PriceListSourcesMonitor>,
+        price_list_archive: Option<Arc<PriceListArchive>>,
+    ) -> Result<Self, AppError> {
+        let http_client = create_http_client(&settings.price_list_http_client)?;
+        let price_list_provider: Box<dyn SingleDayPriceList> = match settings.tariff_type {
+            TariffTypes::Fallback => {
+                if settings.price_list_fallback_chain.is_empty() {
+                    return Err(AppError::user_error("Fallback tariff requires price_list_fallback_chain in settings"));
+                }
+                let sources = settings
+                    .price_list_fallback_chain
+                    .iter()
+                    .enumerate()
+                    .map(|(index, tariff_type)| {
+                        Self::create_price_list_provider(
+                            tariff_type,
+                            settings,
+                            &http_client,
+                            &price_list_archive,
+                            index > 0,
+                        )
+                        .map(|provider| (format!("{:?}", tariff_type), provider))
+                    })
+                    .collect::<Result<Vec<_>, AppError>>()?;
+                Box::new(FallbackPriceList::new(sources, price_list_sources_monitor))
+            }
+            ref tariff_type => {
+                Self::create_price_list_provider(tariff_type, settings, &http_client, &price_list_archive, false)?
+            }
+        };
+        Ok(Self { price_list_provider })
+    }
+
+    /// only remote price lists are archived, time of use tariffs are calculated from the settings
+    fn create_price_list_provider(
+        tariff_type: &TariffTypes,
+        settings: &Settings,
+        http_client: &reqwest::Client,
+        price_list_archive: &Option<Arc<PriceListArchive>>,
+        estimated: bool,
+    ) -> Result<Box<dyn SingleDayPriceList>, AppError> {
+        let time_of_use = |provider: TimeOfUsePriceListProvider| -> Box<dyn SingleDayPriceList> {
+            if estimated {
+                Box::new(provider.estimated())
+            } else {
+                Box::new(provider)
+            }
+        };
+        let mut price_list_provider: Box<dyn SingleDayPriceList> = match tariff_type {
+            TariffTypes::W12 => time_of_use(TimeOfUsePriceListProvider::w12()),
+            TariffTypes::TimeOfUse => settings
+                .time_of_use_tariff
+                .clone()
+                .map(|tariff| time_of_use(TimeOfUsePriceListProvider::new(tariff)))
+                .ok_or_else(|| AppError::user_error("TimeOfUse tariff requires time_of_use_tariff in settings"))?,
+            TariffTypes::DayAheadMarket => Box::new(DayAheadMarketPriceListProvider::new(http_client.clone())),
+            TariffTypes::Entsoe => settings
+                .entsoe_config
+                .clone()
+                .map(|config| {
+                    Box::new(EntsoePriceListProvider::new(config, http_client.clone())) as Box<dyn SingleDayPriceList>
+                })
+                .ok_or_else(|| AppError::user_error("Entsoe tariff requires entsoe_config in settings"))?,
+            TariffTypes::Fallback => {
+                return Err(AppError::user_error("Fallback tariff can not be a part of fallback chain"))
+            }
+        };
+        if settings.price_list_validation.enabled {
+            price_list_provider =
+                Box::new(ValidatedPriceList::new(price_list_provider, settings.price_list_validation.clone()));
+        }
+        match (tariff_type, price_list_archive) {
+            (TariffTypes::DayAheadMarket | TariffTypes::Entsoe, Some(price_list_archive)) => Ok(Box::new(
+                ArchivedPriceList::new(price_list_provider, price_list_archive.clone(), &format!("{:?}", tariff_type)),
+            )),
+            _ => Ok(price_list_provider),
         }
     }
 }
 
+#[async_trait]
 impl SingleDayPriceList for TariffSelector {
-    fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<Vec<PriceListItem>>, AppError> {
-        match self.current_tariff {
-            TariffTypes::W12 => self.w12_price_list_provider.get_price_list(for_day),
-            TariffTypes::DayAheadMarket => self.day_ahead_market_price_list_provider.get_price_list(for_day),
-        }
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        self.price_list_provider.get_price_list(for_day).await
+    }
+}
+
+/// composed prices are not comparable with categories of the energy prices,
+/// so they are categorized by default thresholds unless price category strategy is configured,
+/// time of use tariffs keep categories of their bands unless price category strategy is configured
+fn effective_price_category_strategy(
+    tariff_type: &TariffTypes,
+    price_category_strategy: Option<&PriceCategoryStrategy>,
+    price_composition: Option<&PriceCompositionConfig>,
+) -> Option<PriceCategoryStrategy> {
+    match (tariff_type, price_category_strategy, price_composition) {
+        (_, Some(price_category_strategy), _) => Some(price_category_strategy.clone()),
+        (TariffTypes::W12 | TariffTypes::TimeOfUse, None, _) => None,
+        (_, None, Some(_)) => Some(PriceCategoryStrategy::default()),
+        (_, None, None) => None,
+    }
+}
+
+/// Creates price list used by the application, selected tariff price list is overridden by uploaded price lists,
+/// next it is wrapped by optional price composition and by price category strategy.
+/// Tariff price list is shared, so the archive backfill could fetch days below the overrides.
+pub fn create_single_day_price_list(
+    settings: &Settings,
+    tariff_price_list: SharedSingleDayPriceList,
+    price_list_overrides: Arc<PriceListOverrides>,
+) -> SharedSingleDayPriceList {
+    let mut price_list: Box<dyn SingleDayPriceList> =
+        Box::new(OverriddenPriceList::new(tariff_price_list, price_list_overrides));
+    if let Some(price_composition) = &settings.price_composition {
+        price_list = Box::new(ComposedPriceList::new(price_list, price_composition.clone()));
+    }
+    if let Some(price_category_strategy) = effective_price_category_strategy(
+        &settings.tariff_type,
+        settings.price_category_strategy.as_ref(),
+        settings.price_composition.as_ref(),
+    ) {
+        price_list = Box::new(CategorizedPriceList::new(price_list, price_category_strategy));
+    }
+    Arc::from(price_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, Local, TimeZone, Utc};
+
+    use crate::{
+        model::PriceCategory,
+        price_list_providers::{PriceListOverrides, TimeOfUsePriceListProvider},
+        settings::{PriceCategoryStrategy, PriceCompositionConfig, Settings},
+    };
+
+    use super::{create_single_day_price_list, effective_price_category_strategy, TariffTypes};
+
+    fn date_time(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, hour, 0, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    #[test]
+    fn composed_prices_should_be_categorized_by_default() {
+        let composition = PriceCompositionConfig::default();
+        let percentiles = PriceCategoryStrategy::Percentiles { min_percentile: 25, max_percentile: 75 };
+        let market = TariffTypes::DayAheadMarket;
+
+        assert!(effective_price_category_strategy(&market, None, None).is_none());
+        assert!(matches!(
+            effective_price_category_strategy(&market, None, Some(&composition)),
+            Some(PriceCategoryStrategy::Absolute(_))
+        ));
+        assert!(matches!(
+            effective_price_category_strategy(&market, Some(&percentiles), Some(&composition)),
+            Some(PriceCategoryStrategy::Percentiles { min_percentile: 25, max_percentile: 75 })
+        ));
+    }
+
+    #[test]
+    fn time_of_use_prices_should_keep_band_categories_unless_strategy_is_configured() {
+        let composition = PriceCompositionConfig::default();
+        let percentiles = PriceCategoryStrategy::Percentiles { min_percentile: 25, max_percentile: 75 };
+        let time_of_use = TariffTypes::TimeOfUse;
+
+        assert!(effective_price_category_strategy(&time_of_use, None, Some(&composition)).is_none());
+        assert!(matches!(
+            effective_price_category_strategy(&time_of_use, Some(&percentiles), Some(&composition)),
+            Some(PriceCategoryStrategy::Percentiles { min_percentile: 25, max_percentile: 75 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn shipped_time_of_use_tariff_should_keep_band_categories() {
+        let mut settings = Settings::shipped().unwrap();
+        settings.tariff_type = TariffTypes::TimeOfUse;
+        let tariff_price_list = Arc::new(TimeOfUsePriceListProvider::new(settings.time_of_use_tariff.clone().unwrap()));
+        let price_list =
+            create_single_day_price_list(&settings, tariff_price_list, Arc::new(PriceListOverrides::new()));
+
+        let price_list = price_list.get_price_list(&date_time(2024, 8, 26, 0)).await.unwrap();
+        let categories: Vec<&PriceCategory> =
+            [0, 10, 13, 22].iter().map(|hour| price_list.items()[*hour].category()).collect();
+        assert_eq!(
+            categories,
+            vec![&PriceCategory::Min, &PriceCategory::Max, &PriceCategory::Min, &PriceCategory::Min]
+        );
     }
 }
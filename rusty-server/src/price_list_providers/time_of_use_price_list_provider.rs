@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Local, TimeDelta, Timelike, Utc, Weekday};
+
+use crate::{
+    model::{AppError, Money, PriceCategory, PriceList, PriceListItem, PriceListStatus},
+    settings::{TariffBandConfig, TimeOfUseTariffConfig},
+};
+
+use super::{
+    commons::{cut_off_time_from_date, next_day_start},
+    SingleDayPriceList,
+};
+
+const ONE_HOUR: TimeDelta = TimeDelta::hours(1);
+
+/// TimeOfUsePriceListProvider creates price list from the time of use tariff configuration,
+/// tariffs like G11, G12, G12w or G13 are described by the list of time bands with price and category.
+/// Each hour of the day gets price from the first band which matches its local time.
+/// Tariff prices are final, unless the tariff only estimates market prices, e.g. in the fallback chain.
+pub struct TimeOfUsePriceListProvider {
+    tariff: TimeOfUseTariffConfig,
+    status: PriceListStatus,
+}
+
+impl TimeOfUsePriceListProvider {
+    pub fn new(tariff: TimeOfUseTariffConfig) -> Self {
+        Self { tariff, status: PriceListStatus::Final }
+    }
+
+    /// price lists of the tariff are marked as estimated
+    pub fn estimated(mut self) -> Self {
+        self.status = PriceListStatus::Estimated;
+        self
+    }
+
+    /// W12 is a tariff with off peak hours where price is low,
+    /// it is between 10pm - 6 am and 1pm-3pm, weekends are in off peek prices
+    /// In peek hours have double price. This tariff is provided mainly for testing
+    pub fn w12() -> Self {
+        let band = |from_hour, to_hour, price, category, weekdays: &[Weekday]| TariffBandConfig {
+            from_hour,
+            to_hour,
+            price: Money::from_legacy_price(price),
+            category,
+            weekdays: weekdays.to_vec(),
+            months: Vec::new(),
+            valid_from: None,
+            valid_to: None,
+        };
+        use PriceCategory::*;
+        use Weekday::*;
+
+        Self::new(TimeOfUseTariffConfig {
+            name: "W12".to_owned(),
+            bands: vec![
+                band(0, 24, 80000, Min, &[Sat, Sun]),
+                band(22, 6, 80000, Min, &[]),
+                band(13, 15, 80000, Min, &[]),
+                band(0, 24, 160000, Max, &[]),
+            ],
+        })
+    }
+
+    fn band_matches(band: &TariffBandConfig, local_time: &DateTime<Local>) -> bool {
+        let hour = local_time.hour();
+        let date = local_time.date_naive();
+        //band with the same from and to hour spans midnight and covers the whole day
+        let hour_matches = if band.from_hour < band.to_hour {
+            band.from_hour <= hour && hour < band.to_hour
+        } else {
+            band.from_hour <= hour || hour < band.to_hour
+        };
+
+        hour_matches
+            && (band.weekdays.is_empty() || band.weekdays.contains(&local_time.weekday()))
+            && (band.months.is_empty() || band.months.contains(&local_time.month()))
+            && band.valid_from.is_none_or(|valid_from| valid_from <= date)
+            && band.valid_to.is_none_or(|valid_to| date <= valid_to)
+    }
+
+    fn find_band(&self, starts_at: &DateTime<Utc>) -> Result<&TariffBandConfig, AppError> {
+        let local_time = starts_at.with_timezone(&Local);
+        self.tariff.bands.iter().find(|band| Self::band_matches(band, &local_time)).ok_or_else(|| {
+            AppError::system_error(&format!(
+                "Tariff {} has no band for {}",
+                self.tariff.name,
+                local_time.format("%d-%m-%Y %H:%M")
+            ))
+        })
+    }
+}
+
+#[async_trait]
+impl SingleDayPriceList for TimeOfUsePriceListProvider {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        let for_day = cut_off_time_from_date(for_day);
+        let day_end = next_day_start(&for_day);
+
+        let mut price_list = Vec::new();
+        let mut starts_at = for_day;
+        while starts_at < day_end {
+            let band = self.find_band(&starts_at)?;
+            price_list.push(PriceListItem::new(starts_at, ONE_HOUR, band.price, band.category.clone()));
+            starts_at += ONE_HOUR;
+        }
+        let mut price_list = PriceList::new(for_day, &self.tariff.name, Utc::now(), price_list);
+        price_list.set_status(self.status);
+        Ok(Arc::new(price_list))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc, Weekday};
+
+    use crate::{
+        model::{Money, PriceCategory},
+        settings::{TariffBandConfig, TimeOfUseTariffConfig},
+    };
+
+    use super::{cut_off_time_from_date, SingleDayPriceList, TimeOfUsePriceListProvider};
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, 0, 0, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    fn band(from_hour: u32, to_hour: u32, price: i64, months: &[u32]) -> TariffBandConfig {
+        TariffBandConfig {
+            from_hour,
+            to_hour,
+            price: Money::from_legacy_price(price),
+            category: PriceCategory::Medium,
+            weekdays: Vec::new(),
+            months: months.to_vec(),
+            valid_from: None,
+            valid_to: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn w12_price_list_provider_test() {
+        let price_list_provider = TimeOfUsePriceListProvider::w12();
+        let now = Utc::now();
+        println!("now: {}", now);
+        let for_day = cut_off_time_from_date(&now);
+        println!("for_day: {}", for_day);
+        let price_list = price_list_provider.get_price_list(&for_day).await.unwrap();
+        assert!(price_list.items().len() >= 23);
+        assert_eq!(price_list.items()[0].starts_at(), &for_day);
+    }
+
+    #[tokio::test]
+    async fn w12_working_day_has_off_peak_hours_at_night_and_noon() {
+        let price_list = TimeOfUsePriceListProvider::w12().get_price_list(&date(2024, 8, 26)).await.unwrap();
+        let off_peak_hours: Vec<usize> = price_list
+            .items()
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.price().legacy_price() == 80000)
+            .map(|(hour, _)| hour)
+            .collect();
+        assert_eq!(off_peak_hours, vec![0, 1, 2, 3, 4, 5, 13, 14, 22, 23]);
+        assert_eq!(price_list.items()[6].category(), &PriceCategory::Max);
+    }
+
+    #[tokio::test]
+    async fn w12_weekend_is_off_peak() {
+        let price_list = TimeOfUsePriceListProvider::w12().get_price_list(&date(2024, 8, 24)).await.unwrap();
+        assert!(price_list.items().iter().all(|item| item.price().legacy_price() == 80000));
+    }
+
+    #[tokio::test]
+    async fn seasonal_band_should_be_selected_by_month() {
+        let provider = TimeOfUsePriceListProvider::new(TimeOfUseTariffConfig {
+            name: "G13".to_owned(),
+            bands: vec![band(7, 13, 100000, &[4, 5, 6, 7, 8, 9]), band(7, 13, 120000, &[]), band(0, 24, 60000, &[])],
+        });
+        let summer = provider.get_price_list(&date(2024, 7, 1)).await.unwrap();
+        let winter = provider.get_price_list(&date(2024, 1, 2)).await.unwrap();
+        assert_eq!(summer.items()[7].price().legacy_price(), 100000);
+        assert_eq!(winter.items()[7].price().legacy_price(), 120000);
+        assert_eq!(winter.items()[13].price().legacy_price(), 60000);
+    }
+
+    #[tokio::test]
+    async fn band_should_apply_only_in_date_range_and_weekdays() {
+        let mut holiday_band = band(0, 24, 50000, &[]);
+        holiday_band.valid_from = NaiveDate::from_ymd_opt(2024, 12, 24);
+        holiday_band.valid_to = NaiveDate::from_ymd_opt(2024, 12, 26);
+        holiday_band.weekdays = vec![Weekday::Tue, Weekday::Wed];
+        let provider = TimeOfUsePriceListProvider::new(TimeOfUseTariffConfig {
+            name: "G11".to_owned(),
+            bands: vec![holiday_band, band(0, 24, 90000, &[])],
+        });
+        assert_eq!(
+            provider.get_price_list(&date(2024, 12, 24)).await.unwrap().items()[10].price().legacy_price(),
+            50000
+        );
+        assert_eq!(
+            provider.get_price_list(&date(2024, 12, 26)).await.unwrap().items()[10].price().legacy_price(),
+            90000
+        );
+        assert_eq!(
+            provider.get_price_list(&date(2024, 12, 27)).await.unwrap().items()[10].price().legacy_price(),
+            90000
+        );
+    }
+
+    #[tokio::test]
+    async fn price_list_should_follow_daylight_saving_time() {
+        let provider = TimeOfUsePriceListProvider::w12();
+        assert_eq!(provider.get_price_list(&date(2024, 3, 31)).await.unwrap().items().len(), 23);
+        assert_eq!(provider.get_price_list(&date(2024, 10, 27)).await.unwrap().items().len(), 25);
+    }
+
+    #[tokio::test]
+    async fn missing_band_should_be_reported() {
+        let provider = TimeOfUsePriceListProvider::new(TimeOfUseTariffConfig {
+            name: "G12".to_owned(),
+            bands: vec![band(0, 6, 1, &[])],
+        });
+        assert!(provider.get_price_list(&date(2024, 1, 2)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn band_with_the_same_from_and_to_hour_should_cover_the_whole_day() {
+        let provider = TimeOfUsePriceListProvider::new(TimeOfUseTariffConfig {
+            name: "G11".to_owned(),
+            bands: vec![band(6, 6, 70000, &[])],
+        });
+        let price_list = provider.get_price_list(&date(2024, 1, 2)).await.unwrap();
+        assert_eq!(price_list.items().len(), 24);
+        assert!(price_list.items().iter().all(|item| item.price().legacy_price() == 70000));
+    }
+}
@@ -1,51 +1,183 @@
-use std::sync::Arc;
+use std::collections::BTreeMap;
 
-use chrono::{DateTime, TimeDelta, Utc};
+use chrono::{DateTime, Local, TimeDelta, Timelike, Utc};
+use rust_decimal::Decimal;
 
-use crate::model::{AppError, PriceListItem};
+use crate::model::{AppError, HourlyAveragePrice, Money, PriceListItem, PriceListStats, TimePeriodPriceList};
 
-use super::{commons::cut_off_time_from_date, SingleDayPriceList, TariffSelector};
+use super::{
+    commons::{cut_off_time_from_date, next_day_start},
+    SharedSingleDayPriceList,
+};
+
+/// the longest time range which could be requested by the api user
+pub const MAX_TIME_RANGE_DAYS: i64 = 366;
 
 /// Price list providers returns price list for single required day,
 /// charging could span few days, this service takes as input time range
 /// and collects price list items from potentially few daily price list into
 /// one continuous price list which covers from_the_time - to_the_time.
-/// It uses TariffSelector to get price list for required day.
+/// It uses configured price list provider (TariffSelector) to get price list for required day.
 /// It returns copy of each selected price list item because scheduler
-/// applies weights to each price list item
+/// applies weights to each price list item, metadata of each day's price list is returned with them,
+/// so plans could record versions of price lists they were computed from
 pub struct TimePeriodPriceListService {
-    single_day_price_list: Arc<TariffSelector>,
+    single_day_price_list: SharedSingleDayPriceList,
 }
 
 impl TimePeriodPriceListService {
-    pub fn new(single_day_price_list: Arc<TariffSelector>) -> Self {
+    pub fn new(single_day_price_list: SharedSingleDayPriceList) -> Self {
         Self { single_day_price_list }
     }
 
-    pub fn get_price_list(
+    pub async fn get_price_list(
         &self,
         from_the_time: &DateTime<Utc>,
         to_the_time: &DateTime<Utc>,
     ) -> Result<Vec<PriceListItem>, AppError> {
+        Ok(self.get_time_period_price_list(from_the_time, to_the_time).await?.items)
+    }
+
+    /// metadata is returned for each day which has items in the time range
+    pub async fn get_time_period_price_list(
+        &self,
+        from_the_time: &DateTime<Utc>,
+        to_the_time: &DateTime<Utc>,
+    ) -> Result<TimePeriodPriceList, AppError> {
         let from_the_day = cut_off_time_from_date(from_the_time);
         let to_the_day = cut_off_time_from_date(to_the_time);
 
-        let mut price_list: Vec<PriceListItem> = Vec::new();
+        let mut time_period_price_list = TimePeriodPriceList { price_lists: Vec::new(), items: Vec::new() };
         let mut next_day = from_the_day;
         while next_day <= to_the_day {
-            let single_day_price_list = self.single_day_price_list.get_price_list(&next_day)?;
-            price_list.extend(
-                single_day_price_list
-                    .iter()
-                    .filter(|item| {
-                        (*item.starts_at() + *item.duration()) > *from_the_time && *item.starts_at() < *to_the_time
-                    })
-                    .cloned(),
-            );
-            next_day += TimeDelta::days(1);
+            let single_day_price_list = self.single_day_price_list.get_price_list(&next_day).await?;
+            let items: Vec<PriceListItem> = single_day_price_list
+                .items()
+                .iter()
+                .filter(|item| {
+                    (*item.starts_at() + *item.duration()) > *from_the_time && *item.starts_at() < *to_the_time
+                })
+                .cloned()
+                .collect();
+            if !items.is_empty() {
+                time_period_price_list.price_lists.push(single_day_price_list.metadata().clone());
+                time_period_price_list.items.extend(items);
+            }
+            next_day = next_day_start(&next_day);
+        }
+
+        Ok(time_period_price_list)
+    }
+
+    /// time range requested by the api user must not be empty and can not be longer than a year,
+    /// each day of the range could be fetched from the remote price list provider
+    pub fn validate_time_range(from_the_time: &DateTime<Utc>, to_the_time: &DateTime<Utc>) -> Result<(), AppError> {
+        if from_the_time >= to_the_time {
+            return Err(AppError::user_error("Time range start must be before its end!"));
+        }
+        if *to_the_time - *from_the_time > TimeDelta::days(MAX_TIME_RANGE_DAYS) {
+            return Err(AppError::user_error(&format!(
+                "Time range can not be longer than {} days!",
+                MAX_TIME_RANGE_DAYS
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn get_price_list_stats(
+        &self,
+        from_the_time: &DateTime<Utc>,
+        to_the_time: &DateTime<Utc>,
+        percentile: u32,
+    ) -> Result<PriceListStats, AppError> {
+        if !(1..=100).contains(&percentile) {
+            return Err(AppError::user_error("Percentile must be between 1 and 100!"));
+        }
+        let price_list = self.get_price_list(from_the_time, to_the_time).await?;
+        Self::calculate_price_list_stats(&price_list, from_the_time, to_the_time, percentile)
+            .ok_or_else(|| AppError::not_found("There are no prices in the requested time range"))
+    }
+
+    /// average of prices weighted by their durations in seconds
+    fn weighted_mean(prices: &[(Money, i64)]) -> Option<Money> {
+        let total_duration: i64 = prices.iter().map(|(_, duration)| duration).sum();
+        if total_duration <= 0 {
+            return None;
         }
+        let weighted_sum: Decimal =
+            prices.iter().map(|(price, duration)| price.amount() * Decimal::from(*duration)).sum();
+        Some(Money::new(weighted_sum / Decimal::from(total_duration)))
+    }
 
-        Ok(price_list)
+    /// the lowest price which together with cheaper prices covers at least `percentile` of the total duration,
+    /// it is the nearest rank method where each second of the time range is ranked
+    fn weighted_percentile(sorted_prices: &[(Money, i64)], total_duration: i64, percentile: u32) -> Option<Money> {
+        let mut covered_duration = 0;
+        sorted_prices
+            .iter()
+            .find(|(_, duration)| {
+                covered_duration += duration;
+                covered_duration * 100 >= total_duration * percentile as i64
+            })
+            .map(|(price, _)| *price)
+    }
+
+    /// median is weighted percentile 50, when prices split the duration exactly in half it is mean of both middle prices
+    fn weighted_median(sorted_prices: &[(Money, i64)], total_duration: i64) -> Option<Money> {
+        let mut covered_duration = 0;
+        for (index, (price, duration)) in sorted_prices.iter().enumerate() {
+            covered_duration += duration;
+            if covered_duration * 2 == total_duration {
+                return sorted_prices.get(index + 1).and_then(|(next_price, _)| Money::mean(&[*price, *next_price]));
+            }
+            if covered_duration * 2 > total_duration {
+                return Some(*price);
+            }
+        }
+        None
+    }
+
+    /// prices are weighted by duration of their price list items, so quarter hour items count a quarter of hourly ones
+    fn calculate_price_list_stats(
+        price_list: &[PriceListItem],
+        from_the_time: &DateTime<Utc>,
+        to_the_time: &DateTime<Utc>,
+        percentile: u32,
+    ) -> Option<PriceListStats> {
+        let mut prices: Vec<(Money, i64)> =
+            price_list.iter().map(|item| (item.price(), item.duration().num_seconds())).collect();
+        prices.sort();
+        let count = prices.len();
+        if count == 0 {
+            return None;
+        }
+        let total_duration: i64 = prices.iter().map(|(_, duration)| duration).sum();
+
+        let mut hourly_prices: BTreeMap<u32, Vec<(Money, i64)>> = BTreeMap::new();
+        for item in price_list {
+            hourly_prices
+                .entry(item.starts_at().with_timezone(&Local).hour())
+                .or_default()
+                .push((item.price(), item.duration().num_seconds()));
+        }
+
+        Some(PriceListStats {
+            from: *from_the_time,
+            to: *to_the_time,
+            items_count: count,
+            min: prices[0].0,
+            max: prices[count - 1].0,
+            mean: Self::weighted_mean(&prices)?,
+            median: Self::weighted_median(&prices, total_duration)?,
+            percentile,
+            percentile_price: Self::weighted_percentile(&prices, total_duration, percentile)?,
+            hourly_averages: hourly_prices
+                .into_iter()
+                .filter_map(|(hour, prices)| {
+                    Self::weighted_mean(&prices).map(|average_price| HourlyAveragePrice { hour, average_price })
+                })
+                .collect(),
+        })
     }
 }
 
@@ -53,9 +185,12 @@ impl TimePeriodPriceListService {
 mod tests {
     use std::sync::Arc;
 
-    use chrono::{DateTime, Local, TimeZone, Utc};
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
 
-    use crate::price_list_providers::{TariffSelector, TariffTypes};
+    use crate::{
+        model::{HourlyAveragePrice, Money, PriceCategory, PriceListItem},
+        price_list_providers::TimeOfUsePriceListProvider,
+    };
 
     use super::TimePeriodPriceListService;
 
@@ -64,36 +199,141 @@ mod tests {
     }
 
     fn create_time_period_price_list_service() -> TimePeriodPriceListService {
-        TimePeriodPriceListService::new(Arc::new(TariffSelector::new(TariffTypes::W12)))
+        TimePeriodPriceListService::new(Arc::new(TimeOfUsePriceListProvider::w12()))
     }
 
-    #[test]
-    fn should_return_price_list_for_the_requested_period_in_single_day_price_list() {
+    #[tokio::test]
+    async fn should_return_price_list_for_the_requested_period_in_single_day_price_list() {
         let time_period_price_list_service = create_time_period_price_list_service();
 
         let start_time = date_time(2024, 8, 24, 13, 30);
         let end_time = date_time(2024, 8, 24, 13, 45);
-        let price_list = time_period_price_list_service.get_price_list(&start_time, &end_time).unwrap();
+        let price_list = time_period_price_list_service.get_price_list(&start_time, &end_time).await.unwrap();
         assert_eq!(price_list.len(), 1)
     }
 
-    #[test]
-    fn should_return_price_list_for_the_requested_period_spanning_two_single_day_price_lists() {
+    #[tokio::test]
+    async fn should_return_price_list_for_the_requested_period_spanning_two_single_day_price_lists() {
         let time_period_price_list_service = create_time_period_price_list_service();
 
         let start_time = date_time(2024, 8, 24, 13, 30);
         let end_time = date_time(2024, 8, 24, 14, 45);
-        let price_list = time_period_price_list_service.get_price_list(&start_time, &end_time).unwrap();
+        let price_list = time_period_price_list_service.get_price_list(&start_time, &end_time).await.unwrap();
         assert_eq!(price_list.len(), 2)
     }
 
-    #[test]
-    fn should_return_price_list_for_the_requested_one_day_period() {
+    #[tokio::test]
+    async fn should_return_metadata_of_each_day_of_the_requested_period() {
+        let time_period_price_list_service = create_time_period_price_list_service();
+
+        let start_time = date_time(2024, 8, 24, 23, 30);
+        let end_time = date_time(2024, 8, 26, 0, 0);
+        let time_period_price_list =
+            time_period_price_list_service.get_time_period_price_list(&start_time, &end_time).await.unwrap();
+
+        assert_eq!(time_period_price_list.items.len(), 25);
+        let days: Vec<DateTime<Utc>> =
+            time_period_price_list.price_lists.iter().map(|metadata| metadata.for_day).collect();
+        assert_eq!(days, vec![date_time(2024, 8, 24, 0, 0), date_time(2024, 8, 25, 0, 0)]);
+        assert!(time_period_price_list.price_lists.iter().all(|metadata| metadata.provider == "W12"));
+    }
+
+    #[tokio::test]
+    async fn should_return_each_day_of_the_period_with_dst_change() {
+        let time_period_price_list_service = create_time_period_price_list_service();
+
+        let start_time = date_time(2024, 10, 26, 0, 0);
+        let end_time = date_time(2024, 10, 28, 0, 0);
+        let time_period_price_list =
+            time_period_price_list_service.get_time_period_price_list(&start_time, &end_time).await.unwrap();
+
+        assert_eq!(time_period_price_list.items.len(), 49);
+        let days: Vec<DateTime<Utc>> =
+            time_period_price_list.price_lists.iter().map(|metadata| metadata.for_day).collect();
+        assert_eq!(days, vec![date_time(2024, 10, 26, 0, 0), date_time(2024, 10, 27, 0, 0)]);
+        assert_eq!(time_period_price_list.items.last().unwrap().starts_at(), &date_time(2024, 10, 27, 23, 0));
+    }
+
+    #[tokio::test]
+    async fn should_return_price_list_for_the_requested_one_day_period() {
         let time_period_price_list_service = create_time_period_price_list_service();
 
         let start_time = date_time(2024, 8, 24, 13, 0);
         let end_time = date_time(2024, 8, 25, 13, 0);
-        let price_list = time_period_price_list_service.get_price_list(&start_time, &end_time).unwrap();
+        let price_list = time_period_price_list_service.get_price_list(&start_time, &end_time).await.unwrap();
         assert_eq!(price_list.len(), 24)
     }
+
+    #[test]
+    fn time_range_should_be_validated() {
+        let start_time = date_time(2024, 8, 24, 13, 0);
+        assert!(TimePeriodPriceListService::validate_time_range(&start_time, &date_time(2024, 8, 25, 13, 0)).is_ok());
+        assert!(TimePeriodPriceListService::validate_time_range(&start_time, &start_time).is_err());
+        assert!(TimePeriodPriceListService::validate_time_range(&start_time, &date_time(2025, 9, 24, 13, 0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_return_price_list_stats_for_the_requested_period() {
+        let time_period_price_list_service = create_time_period_price_list_service();
+
+        // Monday, cheap hours in W12 are 13-15 and 22-6
+        let start_time = date_time(2024, 8, 26, 0, 0);
+        let end_time = date_time(2024, 8, 27, 0, 0);
+        let stats = time_period_price_list_service.get_price_list_stats(&start_time, &end_time, 90).await.unwrap();
+
+        assert_eq!(stats.items_count, 24);
+        assert_eq!(stats.min, Money::from_legacy_price(80000));
+        assert_eq!(stats.max, Money::from_legacy_price(160000));
+        assert_eq!(stats.mean.legacy_price(), 126667);
+        assert_eq!(stats.median, Money::from_legacy_price(160000));
+        assert_eq!(stats.percentile_price, Money::from_legacy_price(160000));
+        assert_eq!(stats.hourly_averages.len(), 24);
+        assert_eq!(
+            stats.hourly_averages[13],
+            HourlyAveragePrice { hour: 13, average_price: Money::from_legacy_price(80000) }
+        );
+
+        let stats = time_period_price_list_service.get_price_list_stats(&start_time, &end_time, 40).await.unwrap();
+        assert_eq!(stats.percentile_price, Money::from_legacy_price(80000));
+        assert!(time_period_price_list_service.get_price_list_stats(&start_time, &end_time, 0).await.is_err());
+    }
+
+    #[test]
+    fn price_list_stats_should_be_weighted_by_item_duration() {
+        let item = |hour: u32, minute: u32, minutes: i64, price: i64| {
+            PriceListItem::new(
+                date_time(2024, 8, 26, hour, minute),
+                TimeDelta::minutes(minutes),
+                Money::from_legacy_price(price),
+                PriceCategory::Medium,
+            )
+        };
+        // one hour at 10000 and the next hour split into four quarters at 50000
+        let price_list = vec![
+            item(0, 0, 60, 10000),
+            item(1, 0, 15, 50000),
+            item(1, 15, 15, 50000),
+            item(1, 30, 15, 50000),
+            item(1, 45, 15, 50000),
+        ];
+        let stats = TimePeriodPriceListService::calculate_price_list_stats(
+            &price_list,
+            &date_time(2024, 8, 26, 0, 0),
+            &date_time(2024, 8, 26, 2, 0),
+            25,
+        )
+        .unwrap();
+
+        assert_eq!(stats.items_count, 5);
+        assert_eq!(stats.mean, Money::from_legacy_price(30000));
+        assert_eq!(stats.median, Money::from_legacy_price(30000));
+        assert_eq!(stats.percentile_price, Money::from_legacy_price(10000));
+        assert_eq!(
+            stats.hourly_averages,
+            vec![
+                HourlyAveragePrice { hour: 0, average_price: Money::from_legacy_price(10000) },
+                HourlyAveragePrice { hour: 1, average_price: Money::from_legacy_price(50000) }
+            ]
+        );
+    }
 }
@@ -0,0 +1,217 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, Utc};
+
+use crate::{
+    model::{AppError, PriceList},
+    settings::PriceListValidationConfig,
+};
+
+use super::{
+    commons::{cut_off_time_from_date, next_day_start},
+    SingleDayPriceList,
+};
+
+impl PriceListValidationConfig {
+    /// Function checks that price list covers the local day with continuous items and its prices are plausible,
+    /// suspicious prices get warnings on the item and on the price list, price list which can not be used
+    /// is rejected with system error
+    pub fn validate(&self, for_day: &DateTime<Utc>, price_list: &PriceList) -> Result<PriceList, AppError> {
+        let format_time = |time: &DateTime<Utc>| time.with_timezone(&Local).format("%d-%m-%Y %H:%M").to_string();
+        let day_start = cut_off_time_from_date(for_day);
+        let day_end = next_day_start(&day_start);
+        let day = day_start.with_timezone(&Local).format("%d-%m-%Y");
+        let invalid =
+            |reason: String| AppError::system_error(&format!("Price list for {} is invalid: {}", day, reason));
+
+        let Some(first_item) = price_list.items().first() else {
+            return Err(invalid("it is empty".to_owned()));
+        };
+        let resolution = *first_item.duration();
+        if price_list.items().iter().all(|item| *item.duration() == resolution) && resolution.num_minutes() > 0 {
+            let expected_count = (day_end - day_start).num_minutes() / resolution.num_minutes();
+            if price_list.items().len() as i64 != expected_count {
+                return Err(invalid(format!(
+                    "it has {} items but the day has {} items of {} minutes",
+                    price_list.items().len(),
+                    expected_count,
+                    resolution.num_minutes()
+                )));
+            }
+        }
+
+        let mut expected_start = day_start;
+        for item in price_list.items() {
+            if *item.starts_at() != expected_start {
+                return Err(invalid(format!(
+                    "item should start at {} but it starts at {}",
+                    format_time(&expected_start),
+                    format_time(item.starts_at())
+                )));
+            }
+            if item.duration().num_milliseconds() <= 0 {
+                return Err(invalid(format!("item starting at {} has no duration", format_time(item.starts_at()))));
+            }
+            if item.price() < self.min_price || item.price() > self.max_price {
+                return Err(invalid(format!(
+                    "price {} at {} is out of plausible range {} - {}",
+                    item.price(),
+                    format_time(item.starts_at()),
+                    self.min_price.amount(),
+                    self.max_price
+                )));
+            }
+            expected_start = *item.starts_at() + *item.duration();
+        }
+        if expected_start != day_end {
+            return Err(invalid(format!(
+                "it should end at {} but it ends at {}",
+                format_time(&day_end),
+                format_time(&expected_start)
+            )));
+        }
+
+        let mut warnings = Vec::new();
+        let mut items = price_list.items().to_vec();
+        for item in items.iter_mut() {
+            if item.price() < self.suspicious_min_price || item.price() > self.suspicious_max_price {
+                let warning = format!(
+                    "Price {} is out of usual range {} - {}",
+                    item.price(),
+                    self.suspicious_min_price.amount(),
+                    self.suspicious_max_price
+                );
+                warnings.push(format!("{} at {}", warning, format_time(item.starts_at())));
+                item.add_warning(warning);
+            }
+        }
+        let mut validated_price_list = price_list.with_items(items);
+        warnings.into_iter().for_each(|warning| validated_price_list.add_warning(warning));
+        Ok(validated_price_list)
+    }
+}
+
+/// ValidatedPriceList validates price list of the wrapped provider before it is archived or used by plans,
+/// see PriceListValidationConfig
+pub struct ValidatedPriceList<T: SingleDayPriceList> {
+    price_list_provider: T,
+    validation: PriceListValidationConfig,
+}
+
+impl<T: SingleDayPriceList> ValidatedPriceList<T> {
+    pub fn new(price_list_provider: T, validation: PriceListValidationConfig) -> Self {
+        Self { price_list_provider, validation }
+    }
+}
+
+#[async_trait]
+impl<T: SingleDayPriceList> SingleDayPriceList for ValidatedPriceList<T> {
+    async fn get_price_list(&self, for_day: &DateTime<Utc>) -> Result<Arc<PriceList>, AppError> {
+        let price_list = self.price_list_provider.get_price_list(for_day).await?;
+        self.validation.validate(for_day, &price_list).map(Arc::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+
+    use crate::{
+        model::{AppError, Money, PriceCategory, PriceList, PriceListItem},
+        price_list_providers::{SingleDayPriceList, TimeOfUsePriceListProvider},
+        settings::PriceListValidationConfig,
+    };
+
+    use super::ValidatedPriceList;
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(year, month, day, 0, 0, 0).map(|dt| dt.with_timezone(&Utc)).unwrap()
+    }
+
+    fn create_price_list(day: &DateTime<Utc>, prices: &[i64]) -> PriceList {
+        let items = prices
+            .iter()
+            .enumerate()
+            .map(|(hour, price)| {
+                PriceListItem::new(
+                    *day + TimeDelta::hours(hour as i64),
+                    TimeDelta::hours(1),
+                    Money::from_legacy_price(*price),
+                    PriceCategory::Medium,
+                )
+            })
+            .collect();
+        PriceList::new(*day, "DayAheadMarket", *day, items)
+    }
+
+    fn rejection_message(for_day: &DateTime<Utc>, price_list: PriceList) -> String {
+        match PriceListValidationConfig::default().validate(for_day, &price_list) {
+            Err(AppError::SystemError { message, code: _ }) => message,
+            _ => panic!("Price list should be rejected"),
+        }
+    }
+
+    #[test]
+    fn item_count_should_match_day_length() {
+        let day = date(2024, 10, 27);
+        assert_eq!(
+            rejection_message(&day, create_price_list(&day, &[45000; 24])),
+            "Price list for 27-10-2024 is invalid: it has 24 items but the day has 25 items of 60 minutes"
+        );
+        assert!(PriceListValidationConfig::default().validate(&day, &create_price_list(&day, &[45000; 25])).is_ok());
+
+        let day = date(2024, 8, 26);
+        assert_eq!(
+            rejection_message(&day, create_price_list(&day, &[45000; 48])),
+            "Price list for 26-08-2024 is invalid: it has 48 items but the day has 24 items of 60 minutes"
+        );
+    }
+
+    #[test]
+    fn items_should_be_continuous() {
+        let day = date(2024, 8, 26);
+        let price_list = create_price_list(&day, &[45000; 25]);
+        let mut items = price_list.items().to_vec();
+        items.remove(5);
+        assert_eq!(
+            rejection_message(&day, price_list.with_items(items)),
+            "Price list for 26-08-2024 is invalid: item should start at 26-08-2024 05:00 but it starts at 26-08-2024 06:00"
+        );
+    }
+
+    #[test]
+    fn implausible_price_should_reject_the_day() {
+        let day = date(2024, 8, 26);
+        let mut prices = [45000; 24];
+        // rogue price 99999 PLN/MWh published instead of the price cap
+        prices[7] = Money::from_price_per_mwh(99999.into()).legacy_price();
+        assert_eq!(
+            rejection_message(&day, create_price_list(&day, &prices)),
+            "Price list for 26-08-2024 is invalid: price 99.999 PLN/kWh at 26-08-2024 07:00 is out of plausible range -5 - 50 PLN/kWh"
+        );
+    }
+
+    #[test]
+    fn suspicious_price_should_be_accepted_with_warning() {
+        let day = date(2024, 8, 26);
+        let mut prices = [45000; 24];
+        prices[13] = -120000;
+        let price_list =
+            PriceListValidationConfig::default().validate(&day, &create_price_list(&day, &prices)).unwrap();
+
+        assert_eq!(price_list.items()[13].warnings(), ["Price -1.2 PLN/kWh is out of usual range -0.5 - 2 PLN/kWh"]);
+        assert!(price_list.items()[12].warnings().is_empty());
+        assert_eq!(
+            price_list.warnings(),
+            ["Price -1.2 PLN/kWh is out of usual range -0.5 - 2 PLN/kWh at 26-08-2024 13:00"]
+        );
+    }
+
+    #[tokio::test]
+    async fn validated_price_list_should_pass_valid_day() {
+        let price_list =
+            ValidatedPriceList::new(TimeOfUsePriceListProvider::w12(), PriceListValidationConfig::default());
+        assert_eq!(price_list.get_price_list(&date(2024, 3, 31)).await.unwrap().items().len(), 23);
+    }
+}
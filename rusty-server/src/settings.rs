@@ -1,9 +1,15 @@
 use std::env;
 
-use config::{Config, Environment, File};
+use chrono::{NaiveDate, Weekday};
+use config::{builder::DefaultState, Config, ConfigBuilder, Environment, File};
+use rust_decimal::Decimal;
 use serde::Deserialize;
 
-use crate::{model::AppError, price_list_providers::TariffTypes};
+use crate::{
+    model::{AppError, Money, PriceCategory},
+    power_consumers::{HomeAssistantDomain, SwitchBackendTypes},
+    price_list_providers::TariffTypes,
+};
 use dotenvy::dotenv;
 
 #[derive(Debug, Deserialize)]
@@ -13,11 +19,393 @@ pub struct HttpCallConfig {
     pub token: String,
 }
 
+/// WebSocket connection to Home Assistant which pushes state changes of the power consumer entities,
+/// after connection failure it is reconnected with delay doubled from `min_reconnect_delay_ms` to `max_reconnect_delay_ms`
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct HomeAssistantWebSocketConfig {
+    pub enabled: bool,
+    pub min_reconnect_delay_ms: u64,
+    pub max_reconnect_delay_ms: u64,
+}
+
+impl Default for HomeAssistantWebSocketConfig {
+    fn default() -> Self {
+        Self { enabled: true, min_reconnect_delay_ms: 1000, max_reconnect_delay_ms: 60000 }
+    }
+}
+
+/// Import of power consumers from Home Assistant, entities of `domains` and entities matching glob `entity_patterns`,
+/// e.g. `switch.*_plug`, are proposed as power consumers named by their friendly names.
+/// Imported power consumers are stored in the `file` and registered on startup next to `power_consumers`,
+/// new entities are imported on startup when `import_on_startup` is set
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct HomeAssistantImportConfig {
+    #[serde(default)]
+    pub domains: Vec<HomeAssistantDomain>,
+    #[serde(default)]
+    pub entity_patterns: Vec<String>,
+    pub file: String,
+    pub import_on_startup: bool,
+}
+
+impl Default for HomeAssistantImportConfig {
+    fn default() -> Self {
+        Self {
+            domains: Vec::new(),
+            entity_patterns: Vec::new(),
+            file: "imported-power-consumers.json".to_owned(),
+            import_on_startup: false,
+        }
+    }
+}
+
+/// Timeouts of the http calls made by price list providers
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct HttpClientConfig {
+    pub connect_timeout_ms: u64,
+    pub read_timeout_ms: u64,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self { connect_timeout_ms: 5000, read_timeout_ms: 20000 }
+    }
+}
+
+/// Background prefetch of tomorrow's price list, it starts at local publication time
+/// and it is repeated every `retry_interval_secs` until price list is fetched
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct PriceListPrefetchConfig {
+    pub enabled: bool,
+    pub publication_hour: u32,
+    pub publication_minute: u32,
+    pub retry_interval_secs: u64,
+}
+
+impl Default for PriceListPrefetchConfig {
+    fn default() -> Self {
+        Self { enabled: true, publication_hour: 14, publication_minute: 0, retry_interval_secs: 300 }
+    }
+}
+
+/// Every day fetched from day ahead market or ENTSO-E is stored as JSON file in the `directory`,
+/// archived days are served without remote calls, backfill walks back `backfill_days` days by default,
+/// relative `directory` is resolved against the working directory at startup
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct PriceListArchiveConfig {
+    pub enabled: bool,
+    pub directory: String,
+    pub backfill_days: u32,
+}
+
+impl Default for PriceListArchiveConfig {
+    fn default() -> Self {
+        Self { enabled: true, directory: "price-list-archive".to_owned(), backfill_days: 62 }
+    }
+}
+
+/// Price list of every tariff and every uploaded override is validated before it is archived or used by plans.
+/// Day which does not cover its local day (23 or 25 hours when time is changed) with continuous items
+/// or has a price outside `min_price` - `max_price` is rejected,
+/// prices outside `suspicious_min_price` - `suspicious_max_price` are accepted with warnings.
+/// Prices are energy prices in currency per kWh before price composition.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct PriceListValidationConfig {
+    pub enabled: bool,
+    pub min_price: Money,
+    pub max_price: Money,
+    pub suspicious_min_price: Money,
+    pub suspicious_max_price: Money,
+}
+
+impl Default for PriceListValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_price: Money::from_legacy_price(-500000),
+            max_price: Money::from_legacy_price(5000000),
+            suspicious_min_price: Money::from_legacy_price(-50000),
+            suspicious_max_price: Money::from_legacy_price(200000),
+        }
+    }
+}
+
+/// ENTSO-E transparency platform access, bidding zone is EIC code of the area
+/// for which day ahead prices are requested, e.g. `10YPL-AREA-----S`,
+/// `eur_exchange_rate` is price of 1 EUR in PLN used to convert prices published in EUR
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct EntsoeConfig {
+    pub base_url: String,
+    pub bidding_zone: String,
+    pub token: String,
+    pub eur_exchange_rate: Option<Decimal>,
+}
+
+/// Single time band of time of use tariff, it covers local hours `from_hour` - `to_hour`,
+/// when `from_hour` is greater than `to_hour` band spans midnight, when they are equal band covers the whole day.
+/// Empty weekdays or months mean that band applies to all of them,
+/// optional valid_from and valid_to limit band to the date range.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct TariffBandConfig {
+    pub from_hour: u32,
+    pub to_hour: u32,
+    pub price: Money,
+    #[serde(default)]
+    pub category: PriceCategory,
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    #[serde(default)]
+    pub months: Vec<u32>,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_to: Option<NaiveDate>,
+}
+
+/// Time of use tariff, for each hour the first matching band defines price,
+/// so more specific bands (e.g. weekends) should be listed first
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct TimeOfUseTariffConfig {
+    pub name: String,
+    pub bands: Vec<TariffBandConfig>,
+}
+
+/// Price list item below `min` price is in the Min category, above `max` in the Max category
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct PriceCategoryThresholds {
+    pub min: Money,
+    pub max: Money,
+}
+
+/// default thresholds in 1/100000 of currency per kWh
+const PRICE_CATEGORY_MIN_THRESHOLD: i64 = 20000;
+const PRICE_CATEGORY_MAX_THRESHOLD: i64 = 80000;
+
+impl Default for PriceCategoryThresholds {
+    fn default() -> Self {
+        Self {
+            min: Money::from_legacy_price(PRICE_CATEGORY_MIN_THRESHOLD),
+            max: Money::from_legacy_price(PRICE_CATEGORY_MAX_THRESHOLD),
+        }
+    }
+}
+
+impl PriceCategoryThresholds {
+    pub fn evaluate(&self, price: Money) -> PriceCategory {
+        if price < self.min {
+            PriceCategory::Min
+        } else if price > self.max {
+            PriceCategory::Max
+        } else {
+            PriceCategory::Medium
+        }
+    }
+}
+
+/// Strategy used to assign price category to each price list item of the day:
+/// absolute price thresholds, daily percentiles of prices or deviation from the daily mean price
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type")]
+#[allow(unused)]
+pub enum PriceCategoryStrategy {
+    Absolute(PriceCategoryThresholds),
+    Percentiles { min_percentile: u32, max_percentile: u32 },
+    MeanDeviation { below_mean_percent: u32, above_mean_percent: u32 },
+}
+
+impl Default for PriceCategoryStrategy {
+    fn default() -> Self {
+        Self::Absolute(PriceCategoryThresholds::default())
+    }
+}
+
+/// Per kWh fee which does not depend on time, e.g. capacity fee
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct FeeConfig {
+    pub name: String,
+    pub price: Money,
+}
+
+/// End user price composition, energy price from the price list provider is increased by
+/// distribution charge from the distribution tariff bands, fees and excise, VAT is calculated from their sum.
+/// Composed prices are categorized again by price category strategy, default thresholds are used when it is missing,
+/// time of use tariffs keep categories of their bands.
+/// Energy price is not limited unless `minimal_energy_price` is set, so negative market prices are passed through.
+/// Non-negative energy price below `minimal_energy_price_threshold` is replaced by `minimal_energy_price`,
+/// threshold defaults to the minimal price itself. Negative energy prices are never floored.
+/// Distribution charge is added to every tariff, including time of use tariffs, only when `distribution_tariff` is set.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[allow(unused)]
+pub struct PriceCompositionConfig {
+    pub minimal_energy_price: Option<Money>,
+    pub minimal_energy_price_threshold: Option<Money>,
+    pub distribution_tariff: Option<TimeOfUseTariffConfig>,
+    #[serde(default)]
+    pub fees: Vec<FeeConfig>,
+    #[serde(default)]
+    pub excise: Money,
+    #[serde(default)]
+    pub vat_rate_percent: u32,
+}
+
+/// MQTT broker connection used by the MQTT switch backend, switching is confirmed
+/// when the device reports requested state within `confirmation_timeout_ms`
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    #[serde(default = "default_mqtt_confirmation_timeout_ms")]
+    pub confirmation_timeout_ms: u64,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "rusty-server".to_owned()
+}
+
+fn default_mqtt_keep_alive_secs() -> u64 {
+    30
+}
+
+fn default_mqtt_confirmation_timeout_ms() -> u64 {
+    5000
+}
+
+/// Home Assistant MQTT discovery of the power consumer plans and the current price, it uses broker from `mqtt_config`.
+/// Entities are announced under `discovery_prefix`, their states and button commands are under `base_topic`,
+/// states are published every `publish_interval_secs` and right after each command
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct MqttDiscoveryConfig {
+    pub enabled: bool,
+    pub discovery_prefix: String,
+    pub base_topic: String,
+    pub publish_interval_secs: u64,
+}
+
+impl Default for MqttDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            discovery_prefix: "homeassistant".to_owned(),
+            base_topic: "rusty-server".to_owned(),
+            publish_interval_secs: 60,
+        }
+    }
+}
+
+/// Topics and payloads of the device switched by MQTT, defaults match Tasmota `POWER` topics.
+/// Command payloads are published to `command_topic`, device state is read from `state_topic`,
+/// when `state_value_key` is set state payload is JSON object and state is its field, e.g. `state` for Zigbee2MQTT.
+/// QoS is used by both command publishing and state subscription.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct MqttSwitchConfig {
+    pub command_topic: String,
+    pub state_topic: String,
+    #[serde(default = "default_mqtt_on_payload")]
+    pub payload_on: String,
+    #[serde(default = "default_mqtt_off_payload")]
+    pub payload_off: String,
+    #[serde(default = "default_mqtt_on_payload")]
+    pub state_on: String,
+    #[serde(default = "default_mqtt_off_payload")]
+    pub state_off: String,
+    pub state_value_key: Option<String>,
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn default_mqtt_on_payload() -> String {
+    "ON".to_owned()
+}
+
+fn default_mqtt_off_payload() -> String {
+    "OFF".to_owned()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+/// Home Assistant entity of the power consumer, domain is taken from the entity id by default.
+/// Services replace default services of the domain, e.g. `turn_on` and `turn_off` of switch, `press` of button.
+/// Climate entity is switched by `set_temperature` to `on_temperature` and `off_temperature` setpoints,
+/// e.g. setpoint is raised during cheap hours and lowered during expensive ones.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[allow(unused)]
+pub struct HomeAssistantEntityConfig {
+    pub domain: Option<HomeAssistantDomain>,
+    pub turn_on_service: Option<String>,
+    pub turn_off_service: Option<String>,
+    pub on_temperature: Option<f64>,
+    pub off_temperature: Option<f64>,
+}
+
+/// Local HTTP API of Shelly or Tasmota device, e.g. `http://192.168.1.20`,
+/// channel is the output of multi channel device counted from 0.
+/// When `power_metering` is missing it is learned from the status of the device
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct LocalDeviceConfig {
+    pub base_url: String,
+    #[serde(default)]
+    pub channel: u32,
+    pub power_metering: Option<bool>,
+}
+
+/// Energy meter of the power consumer, it is read at every executed switch action to measure consumption of the plan.
+/// Home Assistant energy sensor reports total energy (kWh or Wh), power sensor reports power (W or kW)
+/// and Backend uses power metering of the switch backend, e.g. Shelly or Tasmota
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type")]
+#[allow(unused)]
+pub enum EnergyMeterConfig {
+    HomeAssistantEnergy { entity_id: String },
+    HomeAssistantPower { entity_id: String },
+    Backend,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(unused)]
 pub struct PowerConsumerConfig {
     pub device_id: String,
     pub name: String,
+    /// rated power of the device in kW, cost of consumption plans is estimated when it is set
+    pub power_kw: Option<Decimal>,
+    /// integration which switches the device, Home Assistant by default
+    #[serde(default)]
+    pub backend: SwitchBackendTypes,
+    /// domain and services of the entity switched by Home Assistant backend
+    pub home_assistant: Option<HomeAssistantEntityConfig>,
+    /// topics of the device switched by Mqtt backend
+    pub mqtt: Option<MqttSwitchConfig>,
+    /// address of the device switched by Shelly or Tasmota backend
+    pub local_device: Option<LocalDeviceConfig>,
+    /// meter which measures actual consumption of the plans
+    pub meter: Option<EnergyMeterConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,7 +413,30 @@ pub struct PowerConsumerConfig {
 pub struct Settings {
     pub application_port: u16,
     pub tariff_type: TariffTypes,
+    #[serde(default)]
+    pub price_list_fallback_chain: Vec<TariffTypes>,
+    #[serde(default)]
+    pub price_list_http_client: HttpClientConfig,
+    #[serde(default)]
+    pub price_list_prefetch: PriceListPrefetchConfig,
+    #[serde(default)]
+    pub price_list_archive: PriceListArchiveConfig,
+    #[serde(default)]
+    pub price_list_validation: PriceListValidationConfig,
     pub home_assistant_config: HttpCallConfig,
+    #[serde(default)]
+    pub home_assistant_websocket: HomeAssistantWebSocketConfig,
+    #[serde(default)]
+    pub home_assistant_import: HomeAssistantImportConfig,
+    pub mqtt_config: Option<MqttConfig>,
+    #[serde(default)]
+    pub mqtt_discovery: MqttDiscoveryConfig,
+    #[serde(default)]
+    pub local_device_http_client: HttpClientConfig,
+    pub entsoe_config: Option<EntsoeConfig>,
+    pub time_of_use_tariff: Option<TimeOfUseTariffConfig>,
+    pub price_composition: Option<PriceCompositionConfig>,
+    pub price_category_strategy: Option<PriceCategoryStrategy>,
     pub power_consumers: Vec<PowerConsumerConfig>,
 }
 
@@ -36,13 +447,54 @@ impl Settings {
         let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
         let _ = dotenvy::from_filename_override(format!(".env.{}", run_mode));
 
-        let config = Config::builder()
-            .add_source(File::with_name("settings"))
-            .add_source(Environment::with_prefix("app").try_parsing(true).separator("."))
-            .build()
-            .map_err(|e| AppError::user_error(&format!("{}", e)))?;
+        Self::from_config(
+            Config::builder()
+                .add_source(File::with_name("settings"))
+                .add_source(Environment::with_prefix("app").try_parsing(true).separator(".")),
+        )
+    }
+
+    /// settings.yaml shipped with the server, without environment overrides
+    #[cfg(test)]
+    pub fn shipped() -> Result<Self, AppError> {
+        Self::from_config(Config::builder().add_source(File::with_name("settings")))
+    }
+
+    fn from_config(builder: ConfigBuilder<DefaultState>) -> Result<Self, AppError> {
+        let config = builder.build().map_err(|e| AppError::user_error(&format!("{}", e)))?;
 
         let settings = config.try_deserialize::<Settings>().map_err(|e| AppError::user_error(&format!("{}", e)))?;
+        settings.validate()?;
         Ok(settings)
     }
+
+    /// settings which depend on each other are checked before any background task is started
+    fn validate(&self) -> Result<(), AppError> {
+        if self.mqtt_discovery.enabled && self.mqtt_config.is_none() {
+            return Err(AppError::user_error("MQTT discovery requires mqtt_config"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, Value};
+
+    use super::Settings;
+
+    #[test]
+    fn mqtt_discovery_without_mqtt_config_should_be_rejected() {
+        let builder = Config::builder()
+            .add_source(File::with_name("settings"))
+            .set_override("mqtt_discovery.enabled", true)
+            .unwrap();
+        assert!(Settings::from_config(builder.clone()).is_ok());
+
+        let without_mqtt_config = builder.set_override("mqtt_config", Value::from(None::<String>)).unwrap();
+        match Settings::from_config(without_mqtt_config) {
+            Err(error) => assert_eq!(error.to_string(), "[UserError] MQTT discovery requires mqtt_config"),
+            Ok(_) => panic!("MQTT discovery without mqtt_config should be rejected"),
+        }
+    }
 }
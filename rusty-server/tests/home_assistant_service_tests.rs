@@ -1,4 +1,7 @@
-use rusty_server::{power_consumers::HomeAssistantService, settings::Settings};
+use rusty_server::{
+    power_consumers::{HomeAssistantService, SwitchBackend},
+    settings::Settings,
+};
 
 async fn switch_device_test(switch_to: bool) {
     let settings = Settings::new().unwrap();
@@ -0,0 +1,82 @@
+use std::{collections::HashMap, env, time::Duration};
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use rusty_server::{
+    power_consumers::{MqttSwitchBackend, SwitchBackend},
+    settings::{MqttConfig, MqttSwitchConfig},
+};
+
+/// broker address is taken from `MQTT_HOST` and `MQTT_PORT`, local mosquitto on the default port is used otherwise,
+/// e.g. `docker run -p 1883:1883 eclipse-mosquitto mosquitto -c /mosquitto-no-auth.conf`
+fn mqtt_config(client_id: &str) -> MqttConfig {
+    MqttConfig {
+        host: env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_owned()),
+        port: env::var("MQTT_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(1883),
+        client_id: client_id.to_owned(),
+        username: None,
+        password: None,
+        keep_alive_secs: 30,
+        confirmation_timeout_ms: 3000,
+    }
+}
+
+fn switch_config(device: &str) -> MqttSwitchConfig {
+    serde_json::from_value(serde_json::json!({
+        "command_topic": format!("cmnd/{}/POWER", device),
+        "state_topic": format!("stat/{}/POWER", device),
+    }))
+    .unwrap()
+}
+
+/// simulates Tasmota relay which reports every command on its state topic
+async fn run_tasmota_relay(device: &str, respond: bool) {
+    let config = mqtt_config(&format!("{}-relay", device));
+    let (client, mut event_loop) = AsyncClient::new(MqttOptions::new(config.client_id, config.host, config.port), 10);
+    client.subscribe(format!("cmnd/{}/POWER", device), QoS::AtLeastOnce).await.unwrap();
+    let device = device.to_owned();
+    tokio::spawn(async move {
+        while let Ok(event) = event_loop.poll().await {
+            if let Event::Incoming(Packet::Publish(publish)) = event {
+                if respond {
+                    client
+                        .publish(format!("stat/{}/POWER", device), QoS::AtLeastOnce, false, publish.payload)
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+}
+
+#[tokio::test]
+#[ignore = "requires MQTT broker"]
+async fn switching_should_be_confirmed_by_device() {
+    run_tasmota_relay("rusty-boiler", true).await;
+    let backend = MqttSwitchBackend::new(
+        &mqtt_config("rusty-server-test"),
+        HashMap::from([("boiler".to_owned(), switch_config("rusty-boiler"))]),
+    )
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    backend.switch_device("boiler", true).await.unwrap();
+    assert!(backend.read_state("boiler").await.unwrap());
+    backend.switch_device("boiler", false).await.unwrap();
+    assert!(!backend.read_state("boiler").await.unwrap());
+}
+
+#[tokio::test]
+#[ignore = "requires MQTT broker"]
+async fn switching_should_fail_when_device_does_not_respond() {
+    run_tasmota_relay("rusty-heater", false).await;
+    let backend = MqttSwitchBackend::new(
+        &mqtt_config("rusty-server-silent-test"),
+        HashMap::from([("heater".to_owned(), switch_config("rusty-heater"))]),
+    )
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    assert!(backend.switch_device("heater", true).await.is_err());
+    assert!(backend.read_state("heater").await.is_err());
+}
@@ -27,11 +27,12 @@ export const PriceListTable = () => {
         const responseJSON = await response.json();
         setLoading(false);
         if (response.ok) {
-          const newPriceListItemDuration = 24*hour / responseJSON.length
+          const priceListItems: PricelistItem[] = responseJSON.items;
+          const newPriceListItemDuration = 24*hour / priceListItems.length
           if (newPriceListItemDuration !== priceListItemDuration) {
             setPriceListItemDuration(newPriceListItemDuration);
           }
-          return responseJSON;
+          return priceListItems;
         } else {
           throw new Error(responseJSON.message)
         }